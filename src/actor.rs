@@ -0,0 +1,124 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use thiserror::Error;
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    oneshot,
+};
+use tracing::error;
+
+type Command<T> = Box<dyn FnOnce(&mut T) + Send>;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActorError {
+    #[error("Server is overloaded, try again later")]
+    Overloaded,
+    #[error("Server state is unavailable")]
+    Stopped,
+}
+
+impl IntoResponse for ActorError {
+    fn into_response(self) -> Response {
+        (StatusCode::SERVICE_UNAVAILABLE, self.to_string()).into_response()
+    }
+}
+
+/// Handle to a value owned by a single task. Commands sent through it run one at a time, in the
+/// order they were sent, so the value needs no lock.
+pub struct Actor<T> {
+    commands: mpsc::Sender<Command<T>>,
+}
+
+impl<T: Send + 'static> Actor<T> {
+    /// Move `value` into a new task and return a handle to it. At most `capacity` commands wait
+    /// for it, any more are refused as [`ActorError::Overloaded`]. Must be called within a Tokio
+    /// runtime.
+    pub fn spawn(mut value: T, capacity: usize) -> Self {
+        let (commands, mut receiver) = mpsc::channel::<Command<T>>(capacity);
+        tokio::spawn(async move {
+            while let Some(command) = receiver.recv().await {
+                // The command may have left the value half updated, it must not be served again
+                if catch_unwind(AssertUnwindSafe(|| command(&mut value))).is_err() {
+                    error!("State command panicked, stopping the state task");
+                    break;
+                }
+            }
+        });
+        Self { commands }
+    }
+
+    /// Run `f` on the value without waiting for it. Dropped, with an error logged, when the queue
+    /// is full or the task is gone.
+    pub fn cast(&self, f: impl FnOnce(&mut T) + Send + 'static) {
+        if let Err(e) = self.try_cast(f) {
+            error!(error = %e, "Dropping state command");
+        }
+    }
+
+    /// Run `f` on the value and wait for its result.
+    pub async fn call<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut T) -> R + Send + 'static,
+    ) -> Result<R, ActorError> {
+        let (reply, result) = oneshot::channel();
+        self.try_cast(move |value| {
+            let _ = reply.send(f(value));
+        })?;
+        result.await.map_err(|_| ActorError::Stopped)
+    }
+
+    /// [`Actor::call`] for commands that can fail themselves.
+    pub async fn try_call<R: Send + 'static, E: From<ActorError> + Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut T) -> Result<R, E> + Send + 'static,
+    ) -> Result<R, E> {
+        self.call(f).await?
+    }
+
+    /// Like [`Actor::call`], but waits for room in a full queue instead of failing. Meant for the
+    /// cleanup that must not be lost to load, such as releasing a closed connection.
+    pub async fn call_queued<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut T) -> R + Send + 'static,
+    ) -> Result<R, ActorError> {
+        let (reply, result) = oneshot::channel();
+        let command: Command<T> = Box::new(move |value| {
+            let _ = reply.send(f(value));
+        });
+        self.commands
+            .send(command)
+            .await
+            .map_err(|_| ActorError::Stopped)?;
+        result.await.map_err(|_| ActorError::Stopped)
+    }
+
+    /// Resolves once the task stopped, after a command panicked.
+    pub async fn stopped(&self) {
+        self.commands.closed().await
+    }
+
+    fn try_cast(&self, f: impl FnOnce(&mut T) + Send + 'static) -> Result<(), ActorError> {
+        self.commands.try_send(Box::new(f)).map_err(|e| match e {
+            TrySendError::Full(_) => ActorError::Overloaded,
+            TrySendError::Closed(_) => ActorError::Stopped,
+        })
+    }
+}
+
+impl<T> Clone for Actor<T> {
+    fn clone(&self) -> Self {
+        Self {
+            commands: self.commands.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Actor<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Actor").finish_non_exhaustive()
+    }
+}
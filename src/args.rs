@@ -1,13 +1,268 @@
-use clap::Parser;
-use std::net::SocketAddr;
+use clap::{ArgAction, Parser};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 
-#[derive(Parser, Debug)]
+/// Defaults shared by the command line and [`Args::default`].
+pub mod defaults {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    pub const HOST: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 3536);
+    pub const ALLOW_TOKEN_IN_PATH: bool = false;
+    pub const RECONNECT_GRACE_SECS: u64 = 10;
+    pub const AUTH_TIMEOUT_SECS: u64 = 5;
+    pub const INVITE_TTL_SECS: u64 = 600;
+    pub const CHAT_MAX_BYTES: usize = 500;
+    pub const DATA_MAX_BYTES: usize = 4096;
+    pub const CHAT_HISTORY_LEN: usize = 50;
+    pub const CHAT_RATE_BURST: u32 = 5;
+    pub const CHAT_RATE_PER_SEC: f64 = 1.0;
+    pub const SIGNAL_MAX_BYTES: usize = 16384;
+    pub const SIGNAL_RATE_BURST: u32 = 50;
+    pub const SIGNAL_RATE_PER_SEC: f64 = 20.0;
+    pub const SIGNAL_MAX_WARNINGS: u32 = 3;
+    pub const MAX_VIOLATIONS: u32 = 50;
+    pub const VIOLATION_TTL_SECS: u64 = 3600;
+    pub const PING_INTERVAL_SECS: u64 = 15;
+    pub const IDLE_TIMEOUT_SECS: u64 = 45;
+    pub const TURN_CREDENTIAL_TTL_SECS: u64 = 3600;
+    pub const TURN_REALM: &str = "matchbox";
+    pub const TURN_MAX_ALLOCATIONS: usize = 8;
+    pub const TURN_MAX_TOTAL_ALLOCATIONS: usize = 1024;
+    pub const TURN_BANDWIDTH_BYTES_PER_SEC: u32 = 262_144;
+}
+
+/// Longest invite lifetime accepted on the command line, 30 days
+pub const MAX_INVITE_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+
+#[derive(Parser, Debug, Clone)]
 #[clap(
     name = "made_in_heaven",
     rename_all = "kebab-case",
     rename_all_env = "screaming-snake"
 )]
 pub struct Args {
-    #[clap(default_value = "0.0.0.0:3536", env)]
+    #[clap(default_value_t = defaults::HOST, env)]
     pub host: SocketAddr,
+
+    /// Seconds a player who lost their signaling connection keeps their lobby seat and peer id.
+    /// 0 disables the grace period.
+    #[clap(long, default_value_t = defaults::RECONNECT_GRACE_SECS, env)]
+    pub reconnect_grace_secs: u64,
+
+    /// Accept the JWT as the signaling URL path (`ws://host/<token>`), for older clients. Off by
+    /// default: tokens in URLs end up in proxy and access logs, prefer the
+    /// `Sec-WebSocket-Protocol` or first-message modes.
+    #[clap(long, default_value_t = defaults::ALLOW_TOKEN_IN_PATH, action = ArgAction::Set, env)]
+    pub allow_token_in_path: bool,
+
+    /// Seconds a signaling connection opened without a token has to send its `Authenticate`
+    /// message before it is closed.
+    #[clap(long, default_value_t = defaults::AUTH_TIMEOUT_SECS, env)]
+    pub auth_timeout_secs: u64,
+
+    /// Seconds a lobby invite stays valid, at most [`MAX_INVITE_TTL_SECS`].
+    #[clap(
+        long,
+        default_value_t = defaults::INVITE_TTL_SECS,
+        value_parser = clap::value_parser!(u64).range(1..=MAX_INVITE_TTL_SECS),
+        env
+    )]
+    pub invite_ttl_secs: u64,
+
+    /// Largest lobby chat line, in bytes.
+    #[clap(long, default_value_t = defaults::CHAT_MAX_BYTES, env)]
+    pub chat_max_bytes: usize,
+
+    /// Largest relayed lobby data message, in bytes of JSON.
+    #[clap(long, default_value_t = defaults::DATA_MAX_BYTES, env)]
+    pub data_max_bytes: usize,
+
+    /// Chat lines kept per lobby and replayed to members when they connect.
+    #[clap(long, default_value_t = defaults::CHAT_HISTORY_LEN, env)]
+    pub chat_history_len: usize,
+
+    /// Chat and data messages a connection may send in a burst.
+    #[clap(long, default_value_t = defaults::CHAT_RATE_BURST, env)]
+    pub chat_rate_burst: u32,
+
+    /// Chat and data messages per second a connection is allowed on average.
+    #[clap(long, default_value_t = defaults::CHAT_RATE_PER_SEC, env)]
+    pub chat_rate_per_sec: f64,
+
+    /// Largest message a signaling connection may send, in bytes. Larger messages are refused
+    /// before they are parsed, like signals over the rate limit.
+    #[clap(long, default_value_t = defaults::SIGNAL_MAX_BYTES, env)]
+    pub signal_max_bytes: usize,
+
+    /// Signals a connection may send in a burst.
+    #[clap(long, default_value_t = defaults::SIGNAL_RATE_BURST, env)]
+    pub signal_rate_burst: u32,
+
+    /// Signals per second a connection is allowed on average.
+    #[clap(long, default_value_t = defaults::SIGNAL_RATE_PER_SEC, env)]
+    pub signal_rate_per_sec: f64,
+
+    /// Messages refused for exceeding the size or rate limit, or for being malformed, before the
+    /// connection is closed. Each refusal is answered with an error event as a warning.
+    #[clap(long, default_value_t = defaults::SIGNAL_MAX_WARNINGS, env)]
+    pub signal_max_warnings: u32,
+
+    /// Protocol violations, such as refused or misaddressed signals, after which a player is
+    /// refused signaling connections. 0 never refuses anyone.
+    #[clap(long, default_value_t = defaults::MAX_VIOLATIONS, env)]
+    pub max_violations: u32,
+
+    /// Seconds without a new violation after which a player's violations are forgotten.
+    #[clap(long, default_value_t = defaults::VIOLATION_TTL_SECS, env)]
+    pub violation_ttl_secs: u64,
+
+    /// Seconds between the WebSocket pings the server sends on each signaling connection.
+    #[clap(
+        long,
+        default_value_t = defaults::PING_INTERVAL_SECS,
+        value_parser = clap::value_parser!(u64).range(1..),
+        env
+    )]
+    pub ping_interval_secs: u64,
+
+    /// Seconds a signaling connection may stay silent, pongs included, before it is treated as
+    /// dropped. At least `ping_interval_secs`, or connections would be evicted between pings.
+    #[clap(long, default_value_t = defaults::IDLE_TIMEOUT_SECS, env)]
+    pub idle_timeout_secs: u64,
+
+    /// UDP port to answer STUN Binding requests on, on the signaling server's address. The
+    /// embedded STUN server is advertised to clients in front of `stun_urls`. Disabled when unset.
+    #[clap(long, env)]
+    pub stun_port: Option<u16>,
+
+    /// Comma separated STUN server URLs handed to clients, e.g. `stun:stun.example.com:3478`.
+    #[clap(long, value_delimiter = ',', env)]
+    pub stun_urls: Vec<String>,
+
+    /// Comma separated TURN server URLs handed to clients along with their credentials.
+    #[clap(long, value_delimiter = ',', env)]
+    pub turn_urls: Vec<String>,
+
+    /// Secret shared with the TURN server (coturn's `static-auth-secret`). TURN servers are only
+    /// advertised when it is set.
+    #[clap(long, env)]
+    pub turn_secret: Option<String>,
+
+    /// Seconds the issued TURN credentials stay valid.
+    #[clap(long, default_value_t = defaults::TURN_CREDENTIAL_TTL_SECS, env)]
+    pub turn_credential_ttl_secs: u64,
+
+    /// UDP port of the embedded TURN relay, on the signaling server's address. It accepts the
+    /// credentials issued with `turn_secret`, which must be set, and is advertised to clients in
+    /// front of `turn_urls`. Disabled when unset.
+    #[clap(long, env)]
+    pub turn_port: Option<u16>,
+
+    /// Realm of the embedded TURN relay.
+    #[clap(long, default_value = defaults::TURN_REALM, env)]
+    pub turn_realm: String,
+
+    /// Address the embedded TURN relay advertises for relayed transports, for a server behind
+    /// NAT. Defaults to the signaling server's address, and is required when that address is
+    /// unspecified, such as `0.0.0.0`.
+    #[clap(long, env)]
+    pub turn_external_ip: Option<IpAddr>,
+
+    /// Let the embedded TURN relay talk to loopback peers. Only meant for testing on one machine,
+    /// internal peer addresses are refused otherwise.
+    #[clap(long, env)]
+    pub turn_allow_loopback_peers: bool,
+
+    /// TURN allocations a player may hold at once on the embedded relay.
+    #[clap(long, default_value_t = defaults::TURN_MAX_ALLOCATIONS, env)]
+    pub turn_max_allocations: usize,
+
+    /// TURN allocations the embedded relay holds at once, for all players together. Each one
+    /// holds a UDP socket.
+    #[clap(long, default_value_t = defaults::TURN_MAX_TOTAL_ALLOCATIONS, env)]
+    pub turn_max_total_allocations: usize,
+
+    /// Bytes per second the embedded relay forwards for a player, both directions and all their
+    /// allocations together.
+    #[clap(long, default_value_t = defaults::TURN_BANDWIDTH_BYTES_PER_SEC, env)]
+    pub turn_bandwidth_bytes_per_sec: u32,
+
+    /// Comma separated addresses of the reverse proxies whose `X-Forwarded-For` header is trusted
+    /// to name the client.
+    #[clap(long, value_delimiter = ',', env)]
+    pub trusted_proxies: Vec<IpAddr>,
+
+    /// Comma separated words masked out of chat lines.
+    #[clap(long, value_delimiter = ',', env)]
+    pub chat_blocklist: Vec<String>,
+}
+
+impl Args {
+    pub fn reconnect_grace(&self) -> Duration {
+        Duration::from_secs(self.reconnect_grace_secs)
+    }
+
+    pub fn auth_timeout(&self) -> Duration {
+        Duration::from_secs(self.auth_timeout_secs)
+    }
+
+    pub fn invite_ttl(&self) -> Duration {
+        Duration::from_secs(self.invite_ttl_secs)
+    }
+
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ping_interval_secs)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+
+    pub fn violation_ttl(&self) -> Duration {
+        Duration::from_secs(self.violation_ttl_secs)
+    }
+
+    pub fn turn_credential_ttl(&self) -> Duration {
+        Duration::from_secs(self.turn_credential_ttl_secs)
+    }
+}
+
+/// The command line defaults. Unlike parsing, it never reads the environment.
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            host: defaults::HOST,
+            reconnect_grace_secs: defaults::RECONNECT_GRACE_SECS,
+            allow_token_in_path: defaults::ALLOW_TOKEN_IN_PATH,
+            auth_timeout_secs: defaults::AUTH_TIMEOUT_SECS,
+            invite_ttl_secs: defaults::INVITE_TTL_SECS,
+            chat_max_bytes: defaults::CHAT_MAX_BYTES,
+            data_max_bytes: defaults::DATA_MAX_BYTES,
+            chat_history_len: defaults::CHAT_HISTORY_LEN,
+            chat_rate_burst: defaults::CHAT_RATE_BURST,
+            chat_rate_per_sec: defaults::CHAT_RATE_PER_SEC,
+            signal_max_bytes: defaults::SIGNAL_MAX_BYTES,
+            signal_rate_burst: defaults::SIGNAL_RATE_BURST,
+            signal_rate_per_sec: defaults::SIGNAL_RATE_PER_SEC,
+            signal_max_warnings: defaults::SIGNAL_MAX_WARNINGS,
+            max_violations: defaults::MAX_VIOLATIONS,
+            violation_ttl_secs: defaults::VIOLATION_TTL_SECS,
+            ping_interval_secs: defaults::PING_INTERVAL_SECS,
+            idle_timeout_secs: defaults::IDLE_TIMEOUT_SECS,
+            stun_port: None,
+            stun_urls: Vec::new(),
+            turn_urls: Vec::new(),
+            turn_secret: None,
+            turn_credential_ttl_secs: defaults::TURN_CREDENTIAL_TTL_SECS,
+            turn_port: None,
+            turn_realm: defaults::TURN_REALM.to_string(),
+            turn_external_ip: None,
+            turn_allow_loopback_peers: false,
+            turn_max_allocations: defaults::TURN_MAX_ALLOCATIONS,
+            turn_max_total_allocations: defaults::TURN_MAX_TOTAL_ALLOCATIONS,
+            turn_bandwidth_bytes_per_sec: defaults::TURN_BANDWIDTH_BYTES_PER_SEC,
+            trusted_proxies: Vec::new(),
+            chat_blocklist: Vec::new(),
+        }
+    }
 }
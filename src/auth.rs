@@ -1,7 +1,12 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
 use axum::{
     async_trait,
-    extract::{FromRef, FromRequestParts},
-    http::{header, request::Parts, StatusCode},
+    extract::{FromRef, FromRequestParts, Request},
+    http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
     response::{IntoResponse, Response},
     Json,
 };
@@ -11,14 +16,26 @@ use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation}
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct AuthSecret(pub String);
 
+impl std::fmt::Debug for AuthSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AuthSecret(..)")
+    }
+}
+
 pub const CHALLENGE_EXPIRATION: Duration = Duration::from_secs(60);
 
+/// Subprotocol the server selects when a client authenticates through `Sec-WebSocket-Protocol`.
+pub const WS_SUBPROTOCOL: &str = "matchbox";
+/// Subprotocol a client offers, in place of [`WS_SUBPROTOCOL`], to be sent control events.
+pub const WS_CONTROL_SUBPROTOCOL: &str = "matchbox.control";
+/// Prefix of the subprotocol entry carrying the JWT: `Sec-WebSocket-Protocol: matchbox, bearer.<jwt>`.
+pub const WS_BEARER_PROTOCOL_PREFIX: &str = "bearer.";
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Claims {
     pub sub: String, // public key
@@ -28,21 +45,21 @@ pub struct Claims {
 
 #[derive(Debug, Clone, Default)]
 pub struct ChallengeManager {
-    challenges: Arc<Mutex<HashMap<String, Instant>>>,
+    challenges: HashMap<String, Instant>,
 }
 
 impl ChallengeManager {
     /// Remove expired challenges from the map
-    pub fn cleanup_expired(&self) {
-        let mut challenges = self.challenges.lock().unwrap();
+    pub fn cleanup_expired(&mut self) {
         let now = Instant::now();
-        challenges.retain(|_, &mut timestamp| now.duration_since(timestamp) < CHALLENGE_EXPIRATION);
+        self.challenges
+            .retain(|_, &mut timestamp| now.duration_since(timestamp) < CHALLENGE_EXPIRATION);
     }
     pub fn new() -> Self {
         Default::default()
     }
 
-    pub fn generate_challenge(&self) -> String {
+    pub fn generate_challenge(&mut self) -> String {
         use rand::distributions::Alphanumeric;
         use rand::{thread_rng, Rng};
 
@@ -51,16 +68,14 @@ impl ChallengeManager {
             .take(32)
             .map(char::from)
             .collect();
-        let mut challenges = self.challenges.lock().unwrap();
-        challenges.insert(challenge.clone(), Instant::now());
+        self.challenges.insert(challenge.clone(), Instant::now());
         challenge
     }
 
-    pub fn verify_challenge(&self, challenge: &str) -> bool {
-        let mut challenges = self.challenges.lock().unwrap();
-        if let Some(timestamp) = challenges.get(challenge) {
+    pub fn verify_challenge(&mut self, challenge: &str) -> bool {
+        if let Some(timestamp) = self.challenges.get(challenge) {
             if timestamp.elapsed() < CHALLENGE_EXPIRATION {
-                challenges.remove(challenge);
+                self.challenges.remove(challenge);
                 return true;
             }
         }
@@ -68,6 +83,15 @@ impl ChallengeManager {
     }
 }
 
+/// Whether `value` is a base64 encoded Ed25519 public key, the form player ids take
+pub fn is_public_key(value: &str) -> bool {
+    general_purpose::STANDARD
+        .decode(value)
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .is_some_and(|bytes| VerifyingKey::from_bytes(&bytes).is_ok())
+}
+
 pub fn verify_signature(
     public_key_b64: &str,
     message: &str,
@@ -92,6 +116,24 @@ pub fn verify_signature(
         .is_ok())
 }
 
+/// Hash a lobby password into a PHC string with a random salt.
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash)
+        .map(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
 pub fn issue_jwt(
     public_key_b64: String,
     username: String,
@@ -115,6 +157,84 @@ pub fn issue_jwt(
     )
 }
 
+pub fn decode_token(
+    token: &str,
+    secret: &AuthSecret,
+) -> Result<Claims, jsonwebtoken::errors::Error> {
+    decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(secret.0.as_ref()),
+        &Validation::default(),
+    )
+    .map(|data| data.claims)
+}
+
+/// Where a signaling connection carried its JWT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WsToken<'a> {
+    /// `Sec-WebSocket-Protocol: matchbox, bearer.<jwt>`, or `matchbox.control` in place of
+    /// `matchbox`
+    Subprotocol(&'a str),
+    /// `ws://host/<jwt>`, only honoured when `--allow-token-in-path` is set
+    Path(&'a str),
+    /// No token on the upgrade, the client must send an `Authenticate` message first
+    Missing,
+}
+
+pub fn ws_token<'a>(path: Option<&'a str>, headers: &'a HeaderMap) -> WsToken<'a> {
+    let subprotocol_token = offered_subprotocols(headers)
+        .find_map(|protocol| protocol.strip_prefix(WS_BEARER_PROTOCOL_PREFIX));
+    match (subprotocol_token, path) {
+        (Some(token), _) => WsToken::Subprotocol(token),
+        (None, Some(token)) if !token.is_empty() => WsToken::Path(token),
+        _ => WsToken::Missing,
+    }
+}
+
+/// Whether a signaling client opted in to control events, by offering the `matchbox.control`
+/// subprotocol or with `?control=true`. The plain `matchbox` subprotocol only carries the token.
+pub fn wants_control_events(headers: &HeaderMap, query_params: &HashMap<String, String>) -> bool {
+    offered_subprotocols(headers).any(|p| p == WS_CONTROL_SUBPROTOCOL)
+        || query_params.get("control").is_some_and(|v| v == "true")
+}
+
+/// Whether a signaling client can take back its seat and peer id after reconnecting, see
+/// [`crate::session::WaitingPlayer::resume`]. Control clients always can, others ask with
+/// `?resume=true`.
+pub fn wants_resume(headers: &HeaderMap, query_params: &HashMap<String, String>) -> bool {
+    wants_control_events(headers, query_params)
+        || query_params.get("resume").is_some_and(|v| v == "true")
+}
+
+fn offered_subprotocols(headers: &HeaderMap) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+}
+
+/// Middleware around the signaling routes: clients must only be answered with a subprotocol they
+/// offered, so select `matchbox.control`, or else `matchbox`, on successful upgrades that asked
+/// for it. The bearer entry is never echoed back.
+pub async fn select_subprotocol(request: Request, next: Next) -> Response {
+    let offered: Vec<&str> = offered_subprotocols(request.headers()).collect();
+    let selected = [WS_CONTROL_SUBPROTOCOL, WS_SUBPROTOCOL]
+        .into_iter()
+        .find(|protocol| offered.contains(protocol));
+    let mut response = next.run(request).await;
+    if let Some(protocol) = selected {
+        if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+            response.headers_mut().insert(
+                header::SEC_WEBSOCKET_PROTOCOL,
+                HeaderValue::from_static(protocol),
+            );
+        }
+    }
+    response
+}
+
 #[async_trait]
 impl<S> FromRequestParts<S> for Claims
 where
@@ -135,14 +255,7 @@ where
             .strip_prefix("Bearer ")
             .ok_or(AuthError::InvalidToken)?;
 
-        let token_data = decode::<Claims>(
-            bearer_token,
-            &DecodingKey::from_secret(secret.0.as_ref()),
-            &Validation::default(),
-        )
-        .map_err(|_| AuthError::InvalidToken)?;
-
-        Ok(token_data.claims)
+        decode_token(bearer_token, &secret).map_err(|_| AuthError::InvalidToken)
     }
 }
 
@@ -0,0 +1,117 @@
+use crate::events::RosterEntry;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// A chat line relayed by the server to a lobby
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ChatMessage {
+    #[serde(flatten)]
+    pub sender: RosterEntry,
+    pub text: String,
+    pub sent_at: DateTime<Utc>,
+}
+
+/// Hook run on every chat line before it is relayed. Return the text to relay, possibly
+/// rewritten, or `None` to drop the message.
+pub trait ChatFilter: Send + Sync {
+    fn filter(&self, text: &str) -> Option<String>;
+}
+
+/// Masks every occurrence of the configured words, case-insensitively, with `*`.
+#[derive(Debug, Clone, Default)]
+pub struct WordListFilter {
+    words: Vec<String>,
+}
+
+impl WordListFilter {
+    pub fn new(words: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            words: words
+                .into_iter()
+                .map(|word| word.trim().chars().map(lowercase).collect::<String>())
+                .filter(|word| !word.is_empty())
+                .collect(),
+        }
+    }
+}
+
+impl ChatFilter for WordListFilter {
+    fn filter(&self, text: &str) -> Option<String> {
+        let mut chars: Vec<char> = text.chars().collect();
+        // Lowercased one character at a time, so that positions line up with `chars`
+        let lower: Vec<char> = chars.iter().copied().map(lowercase).collect();
+        for word in &self.words {
+            let word: Vec<char> = word.chars().collect();
+            let mut start = 0;
+            while start + word.len() <= lower.len() {
+                if lower[start..start + word.len()] == word[..] {
+                    chars[start..start + word.len()].fill('*');
+                    start += word.len();
+                } else {
+                    start += 1;
+                }
+            }
+        }
+        Some(chars.into_iter().collect())
+    }
+}
+
+/// First character of the lowercase form of `c`. A few characters lowercase to several, such as
+/// `İ` to `i` and a combining dot.
+fn lowercase(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+/// Optional [`ChatFilter`] shared by every connection
+#[derive(Clone, Default)]
+pub struct ChatFilterHook(pub Option<Arc<dyn ChatFilter>>);
+
+impl std::fmt::Debug for ChatFilterHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ChatFilterHook")
+            .field(&self.0.is_some())
+            .finish()
+    }
+}
+
+impl ChatFilterHook {
+    pub fn apply(&self, text: &str) -> Option<String> {
+        match &self.0 {
+            Some(filter) => filter.filter(text),
+            None => Some(text.to_string()),
+        }
+    }
+}
+
+/// The most recent chat lines of each lobby, replayed to members when they connect
+#[derive(Debug, Default)]
+pub struct ChatHistory {
+    lobbies: HashMap<Uuid, VecDeque<ChatMessage>>,
+}
+
+impl ChatHistory {
+    pub fn push(&mut self, lobby_id: Uuid, message: ChatMessage, max_len: usize) {
+        if max_len == 0 {
+            return;
+        }
+        let history = self.lobbies.entry(lobby_id).or_default();
+        if history.len() >= max_len {
+            history.pop_front();
+        }
+        history.push_back(message);
+    }
+
+    pub fn get(&self, lobby_id: &Uuid) -> Vec<ChatMessage> {
+        self.lobbies
+            .get(lobby_id)
+            .map(|history| history.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn remove(&mut self, lobby_id: &Uuid) {
+        self.lobbies.remove(lobby_id);
+    }
+}
@@ -0,0 +1,150 @@
+use crate::chat::ChatMessage;
+use crate::lobby::{Lobby, MemberState, PlayerId};
+use axum::extract::ws::Message;
+use matchbox_protocol::{JsonPeerRequest, PeerId};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Version of the control event envelope. Bumped on breaking changes to [`ServerEvent`].
+pub const CONTROL_EVENT_VERSION: u32 = 1;
+
+/// Server-originated control events sent on the signaling socket next to matchbox's
+/// `JsonPeerEvent`s.
+///
+/// They are wrapped in a single `Control` key:
+/// `{"Control":{"version":1,"type":"OwnerChanged","data":{...}}}`. Standard matchbox clients parse
+/// every text frame as a `PeerEvent`, so only connections that opted in get them, by offering the
+/// `matchbox.control` subprotocol or with `?control=true`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", content = "data")]
+pub enum ServerEvent {
+    /// A lobby member lost its connection, its seat is kept during the reconnection grace period
+    PeerDisconnected(PeerId),
+    /// A lobby member came back within the grace period and kept its peer id
+    PeerReconnected(PeerId),
+    /// Sent to a reconnecting client: it resumed its previous identity in the lobby
+    Resumed { peer_id: PeerId, lobby_id: Uuid },
+    /// The game started. `teams` lists each team's players when the lobby has teams.
+    LobbyStarted {
+        lobby_id: Uuid,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        teams: Option<Vec<Vec<PlayerId>>>,
+    },
+    /// The owner rebalanced the teams
+    TeamsChanged {
+        lobby_id: Uuid,
+        teams: Vec<Vec<PlayerId>>,
+    },
+    /// Sent to a player removed from the lobby, right before its socket is closed
+    Kicked { lobby_id: Uuid, reason: String },
+    /// The lobby was closed, every member's socket is closed next
+    LobbyClosed { lobby_id: Uuid },
+    /// Ownership of the lobby passed to another member
+    OwnerChanged { lobby_id: Uuid, owner: PlayerId },
+    /// The host of a star lobby left, the members now connect to this player
+    HostChanged { lobby_id: Uuid, host: PlayerId },
+    /// A spectator connected. Spectators are not announced with `NewPeer`, they open the
+    /// connections to the players themselves.
+    SpectatorJoined(RosterEntry),
+    /// A member changed its ready flag, team or custom state, or the owner changed its rating
+    MemberUpdated {
+        lobby_id: Uuid,
+        pubkey: PlayerId,
+        state: MemberState,
+    },
+    /// Identity of a peer that was just announced with `NewPeer`
+    PeerJoined(RosterEntry),
+    /// Sent to a connecting peer: who is behind each peer of the lobby, itself included
+    Roster {
+        lobby_id: Uuid,
+        members: Vec<RosterEntry>,
+    },
+    /// A chat line from a lobby member, the sender included
+    Chat(ChatMessage),
+    /// Recent chat lines of the lobby, sent to a connecting peer
+    ChatHistory {
+        lobby_id: Uuid,
+        messages: Vec<ChatMessage>,
+    },
+    /// Opaque game data relayed from another lobby member
+    Data {
+        sender: PeerId,
+        data: serde_json::Value,
+    },
+    /// The server is going down, clients should reconnect later
+    ServerShutdown,
+    /// A request on the signaling socket was refused
+    Error { code: ErrorCode, message: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RosterEntry {
+    pub peer_id: PeerId,
+    pub pubkey: PlayerId,
+    pub username: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub spectator: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The message could not be parsed
+    InvalidRequest,
+    /// The message exceeds the configured size limit
+    MessageTooLarge,
+    /// The connection sends messages faster than allowed
+    RateLimited,
+    /// The chat filter refused the message
+    MessageRejected,
+    /// The receiver of a signal is not a peer of the sender's lobby
+    PeerNotInLobby,
+}
+
+#[derive(Serialize)]
+struct ControlEnvelope {
+    version: u32,
+    #[serde(flatten)]
+    event: ServerEvent,
+}
+
+#[derive(Serialize)]
+enum ControlMessage {
+    Control(ControlEnvelope),
+}
+
+/// Messages a client may send on the signaling socket besides matchbox's `PeerRequest`s.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ClientMessage {
+    /// First message of a connection opened without a token: `{"Authenticate":{"token":"<jwt>"}}`
+    Authenticate { token: String },
+    /// Chat line relayed to the whole lobby: `{"Chat":{"text":"gg"}}`
+    Chat { text: String },
+    /// Game data relayed to the other lobby members: `{"Data":{"data":<any json>}}`
+    Data { data: serde_json::Value },
+}
+
+/// A text message of the signaling socket, one of ours or one of matchbox's, parsed in one go.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum IncomingMessage {
+    Client(ClientMessage),
+    Peer(JsonPeerRequest),
+}
+
+impl ServerEvent {
+    pub fn lobby_started(lobby: &Lobby) -> Self {
+        ServerEvent::LobbyStarted {
+            lobby_id: lobby.id,
+            teams: lobby.teams.as_ref().map(|teams| teams.rosters.clone()),
+        }
+    }
+
+    pub fn to_message(&self) -> Message {
+        let message = ControlMessage::Control(ControlEnvelope {
+            version: CONTROL_EVENT_VERSION,
+            event: self.clone(),
+        });
+        Message::Text(serde_json::to_string(&message).expect("server event serializes"))
+    }
+}
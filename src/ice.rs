@@ -0,0 +1,103 @@
+use crate::args::Args;
+use axum::http::{header, HeaderMap};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use std::net::IpAddr;
+
+/// An entry of `RTCConfiguration.iceServers`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+/// ICE servers for a player and when their TURN credentials stop working
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IceServers {
+    pub ice_servers: Vec<IceServer>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl IceServers {
+    /// The STUN servers, plus the TURN servers with credentials for `player_id` when a TURN secret
+    /// is configured. The embedded servers come first when enabled, reached at `host`.
+    pub fn for_player(config: &Args, player_id: &str, host: &str, now: DateTime<Utc>) -> Self {
+        let mut ice_servers = Vec::new();
+        let embedded_stun = config.stun_port.map(|port| format!("stun:{host}:{port}"));
+        let stun_urls: Vec<String> = embedded_stun
+            .into_iter()
+            .chain(config.stun_urls.iter().cloned())
+            .collect();
+        if !stun_urls.is_empty() {
+            ice_servers.push(IceServer {
+                urls: stun_urls,
+                username: None,
+                credential: None,
+            });
+        }
+        let mut expires_at = None;
+        let embedded_turn = config
+            .turn_port
+            .map(|port| format!("turn:{host}:{port}?transport=udp"));
+        let turn_urls: Vec<String> = embedded_turn
+            .into_iter()
+            .chain(config.turn_urls.iter().cloned())
+            .collect();
+        if let Some(secret) = config.turn_secret.as_deref() {
+            if !turn_urls.is_empty() {
+                let expiry = now + config.turn_credential_ttl();
+                let username = turn_username(player_id, expiry);
+                ice_servers.push(IceServer {
+                    urls: turn_urls,
+                    credential: Some(turn_credential(secret, &username)),
+                    username: Some(username),
+                });
+                expires_at = Some(expiry);
+            }
+        }
+        Self {
+            ice_servers,
+            expires_at,
+        }
+    }
+}
+
+/// Host name clients reach the server at, taken from the `Host` header of their request, for
+/// advertising the embedded servers. Falls back to the address the server listens on.
+pub fn advertised_host(headers: &HeaderMap, fallback: IpAddr) -> String {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|host| match host.strip_prefix('[') {
+            // IPv6 literal, keep the brackets
+            Some(rest) => rest.find(']').map(|end| &host[..end + 2]),
+            None => host.split(':').next(),
+        })
+        .filter(|host| !host.is_empty());
+    match (host, fallback) {
+        (Some(host), _) => host.to_string(),
+        (None, IpAddr::V6(ip)) => format!("[{ip}]"),
+        (None, IpAddr::V4(ip)) => ip.to_string(),
+    }
+}
+
+/// TURN username in the coturn REST API scheme: the expiry as a Unix timestamp, then the player.
+pub fn turn_username(player_id: &str, expiry: DateTime<Utc>) -> String {
+    format!("{}:{}", expiry.timestamp(), player_id)
+}
+
+/// TURN password for `username` in the coturn REST API scheme: base64 of its HMAC-SHA1 under the
+/// secret shared with the TURN server.
+pub fn turn_credential(secret: &str, username: &str) -> String {
+    let mut mac =
+        Hmac::<Sha1>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(username.as_bytes());
+    general_purpose::STANDARD.encode(mac.finalize().into_bytes())
+}
@@ -1,24 +1,50 @@
+pub mod actor;
 pub mod args;
 pub mod auth;
+pub mod chat;
+pub mod events;
 pub mod helpers;
+pub mod ice;
 pub mod lobby;
+pub mod lobby_query;
+pub mod rate_limit;
+pub mod session;
 pub mod state;
+pub mod stun;
 pub mod topology;
+pub mod turn;
 
-use crate::{auth::AuthSecret, state::ServerState, topology::MatchmakingDemoTopology};
-use axum::http::HeaderMap;
+use crate::{
+    actor::ActorError,
+    args::Args,
+    auth::AuthSecret,
+    chat::{ChatFilterHook, WordListFilter},
+    events::ServerEvent,
+    ice::{advertised_host, IceServers},
+    lobby::{
+        LobbyError, LobbyMetadata, LobbySettings, MemberStateUpdate, Teams, Topology,
+        MAX_PASSWORD_LEN,
+    },
+    lobby_query::{LobbyQuery, NEXT_CURSOR_HEADER},
+    session::{client_ip, ConnectionId, WaitingPlayer},
+    state::{Core, LeftLobby, ServerState, KICKED_CLOSE_CODE, MOVED_CLOSE_CODE},
+    topology::MatchmakingDemoTopology,
+};
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
 use axum::{
-    extract::{FromRef, Path, State},
+    extract::{ConnectInfo, FromRef, MatchedPath, Path, Query, Request, State},
     http::StatusCode,
-    response::{IntoResponse, Json},
-    routing::{get, post},
+    middleware::{self, Next},
+    response::{IntoResponse, Json, Response},
+    routing::{delete, get, patch, post, put},
     Router,
 };
-use jsonwebtoken::{decode, DecodingKey, Validation};
 use matchbox_signaling::SignalingServerBuilder;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use tower_http::cors::CorsLayer;
 use tracing::info;
 use tracing_subscriber::prelude::*;
@@ -51,98 +77,321 @@ impl FromRef<AppState> for AuthSecret {
 }
 
 pub async fn run(addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+    run_with_args(Args {
+        host: addr,
+        ..Default::default()
+    })
+    .await
+}
+
+pub async fn run_with_args(args: Args) -> Result<(), Box<dyn std::error::Error>> {
     dotenvy::dotenv().ok();
     let secret = std::env::var("JWT_SECRET")
         .unwrap_or_else(|_| "test-secret-key-for-development-only".to_string());
-    let state = ServerState::default();
+    if args.ping_interval_secs == 0 {
+        return Err("the ping interval must be at least one second".into());
+    }
+    if args.idle_timeout_secs < args.ping_interval_secs {
+        return Err("the idle timeout must not be shorter than the ping interval".into());
+    }
+    let addr = args.host;
+    let chat_filter = if args.chat_blocklist.is_empty() {
+        ChatFilterHook::default()
+    } else {
+        ChatFilterHook(Some(Arc::new(WordListFilter::new(
+            args.chat_blocklist.clone(),
+        ))))
+    };
+    let state = ServerState::new(args, AuthSecret(secret), chat_filter);
     let app_state = AppState {
         state: state.clone(),
-        secret: AuthSecret(secret.clone()),
+        secret: state.secret.clone(),
     };
     let app_router = app(app_state);
 
-    let challenge_manager = state.challenge_manager.clone();
+    let core = state.core.clone();
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
         loop {
             interval.tick().await;
-            challenge_manager.cleanup_expired();
+            core.cast(|core| {
+                core.challenges.cleanup_expired();
+                core.lobbies.cleanup_expired_invites();
+                core.cleanup_password_attempts();
+                core.violations.cleanup_expired();
+                core.sessions.cleanup_connecting();
+            });
         }
     });
 
+    if let Some(port) = state.config.stun_port {
+        let stun_addr = SocketAddr::new(addr.ip(), port);
+        let socket = tokio::net::UdpSocket::bind(stun_addr).await?;
+        info!("STUN server listening on {}", stun_addr);
+        tokio::spawn(stun::serve(socket));
+    }
+    if let Some(port) = state.config.turn_port {
+        let Some(secret) = state.config.turn_secret.clone() else {
+            return Err("the embedded TURN relay needs a TURN secret".into());
+        };
+        let turn_addr = SocketAddr::new(addr.ip(), port);
+        if turn_addr.ip().is_unspecified() && state.config.turn_external_ip.is_none() {
+            // Relayed addresses would be advertised as 0.0.0.0, which no peer can reach
+            return Err(format!(
+                "the embedded TURN relay on {turn_addr} needs a TURN external IP to advertise"
+            )
+            .into());
+        }
+        let socket = tokio::net::UdpSocket::bind(turn_addr).await?;
+        info!("TURN relay listening on {}", turn_addr);
+        tokio::spawn(turn::TurnServer::run(socket, state.config.clone(), secret));
+    }
+
     let server = SignalingServerBuilder::new(addr, MatchmakingDemoTopology, state.clone())
-        .on_connection_request({
-            let state = state.clone();
-            let secret = AuthSecret(secret);
-            move |connection| {
-                tracing::info!(origin = ?connection.origin, path = ?connection.path, "WebSocket connection attempt");
-                // Extract token from path (matchbox stores path without leading /)
-                let token = connection
-                    .path
-                    .as_ref()
-                    .map(|p| p.as_str())
-                    .ok_or_else(|| {
-                        tracing::warn!(origin = ?connection.origin, path = ?connection.path, "Missing token in path");
-                        (StatusCode::UNAUTHORIZED, "Missing token in path").into_response()
-                    })?;
-
-                let claims = decode::<auth::Claims>(
-                    token,
-                    &DecodingKey::from_secret(secret.0.as_ref()),
-                    &Validation::default(),
-                )
-                .map_err(|e| {
-                    tracing::warn!(origin = ?connection.origin, error = ?e, "Invalid token");
-                    (StatusCode::UNAUTHORIZED, "Invalid token").into_response()
-                })?
-                .claims;
-
-                tracing::info!(origin = ?connection.origin, pubkey = %&claims.sub[..8], "WebSocket connection request: player connected");
-
-                let mut waiting_players = state.waiting_players.write().unwrap();
-                waiting_players.insert(connection.origin, claims.sub);
-
-                // Log current waiting_players size for debugging
-                tracing::debug!(waiting_players_count = waiting_players.len(), "Current waiting_players map size");
-
-                Ok(true)
-            }
-        })
         .on_id_assignment({
             let state = state.clone();
+            // Queued behind the upgrade's authorization and ahead of its state machine
             move |(origin, peer_id)| {
-                let mut waiting_players = state.waiting_players.write().unwrap();
-                if let Some(player_id) = waiting_players.remove(&origin) {
-                    let mut players_to_peers = state.players_to_peers.write().unwrap();
-                    players_to_peers.insert(player_id.clone(), peer_id);
-                    tracing::info!(origin = ?origin, pubkey = %&player_id[..8], peer_id = ?peer_id, "Assigned peer_id to player");
-                } else {
-                    tracing::error!(origin = ?origin, "No player_id found in waiting_players during id assignment");
-                }
+                // The origin was replaced by the connection id when the upgrade was authorized
+                let Some(connection) = ConnectionId::from_origin(origin) else {
+                    tracing::error!(origin = ?origin, "Upgrade was not authorized");
+                    return;
+                };
+                state.core.cast(move |core| {
+                    let Some(waiting) = core.sessions.assign_peer_id(connection, peer_id) else {
+                        tracing::error!(connection = %connection, "No waiting connection found during id assignment");
+                        return;
+                    };
+                    let client = waiting.client;
+                    match waiting.player_id {
+                        Some(player_id) => {
+                            tracing::info!(connection = %connection, client = %client, pubkey = %&player_id[..8], peer_id = ?peer_id, "Assigned peer_id to player");
+                        }
+                        None => {
+                            tracing::debug!(connection = %connection, client = %client, peer_id = ?peer_id, "Peer must authenticate with its first message");
+                        }
+                    }
+                })
             }
         })
         .cors()
         .trace()
         .mutate_router(|router| router.merge(app_router))
-        .build();
+        .build_with({
+            let state = state.clone();
+            |router| {
+                router
+                    .layer(middleware::from_fn_with_state(state, authorize_upgrade))
+                    .layer(middleware::from_fn(auth::select_subprotocol))
+            }
+        });
 
     info!("listening on {}", addr);
-    server.serve().await?;
+    tokio::select! {
+        result = server.serve() => result?,
+        _ = state.core.stopped() => return Err("the server state task stopped".into()),
+        _ = shutdown_signal() => {
+            info!("Shutting down");
+            state.core.cast(|core| core.notify_shutdown());
+            // Give the sender tasks a moment to flush the notices
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        }
+    }
     Ok(())
 }
 
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Middleware authorizing signaling upgrades before matchbox accepts them, see
+/// [`authorize_connection`]. Accepted upgrades get a fresh [`ConnectionId`] in place of their
+/// `ConnectInfo`, which is how matchbox hands them to the id assignment. Other requests go through
+/// untouched.
+async fn authorize_upgrade(
+    State(state): State<ServerState>,
+    ConnectInfo(origin): ConnectInfo<SocketAddr>,
+    matched_path: Option<MatchedPath>,
+    path: Option<Path<String>>,
+    Query(query_params): Query<HashMap<String, String>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let is_signaling = matched_path.is_some_and(|p| matches!(p.as_str(), "/" | "/:path"));
+    if is_signaling && request.headers().contains_key(axum::http::header::UPGRADE) {
+        let connection = ConnectionId::next();
+        let client = client_ip(
+            origin.ip(),
+            request.headers(),
+            &state.config.trusted_proxies,
+        );
+        let path = path.map(|Path(path)| path);
+        let authorized = authorize_connection(
+            &state,
+            connection,
+            client,
+            path.as_deref(),
+            &query_params,
+            request.headers(),
+        )
+        .await;
+        if let Err(response) = authorized {
+            return response;
+        }
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(connection.to_origin()));
+        let response = next.run(request).await;
+        if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+            // matchbox refused the upgrade itself, no peer id will come for it
+            state
+                .core
+                .cast(move |core| core.sessions.cancel_wait(connection));
+        }
+        return response;
+    }
+    next.run(request).await
+}
+
+/// Authenticate a signaling upgrade. The JWT is taken from the `Sec-WebSocket-Protocol` header, or
+/// from the URL path when that fallback is enabled. Upgrades without a token are accepted and must
+/// authenticate with their first message, see the topology.
+///
+/// The lobby to signal in can be given as `?lobby_id=<uuid>`, it defaults to the player's current
+/// lobby. Authenticated players who are not a member of it are refused with a 403.
+async fn authorize_connection(
+    state: &ServerState,
+    connection: ConnectionId,
+    client: IpAddr,
+    path: Option<&str>,
+    query_params: &HashMap<String, String>,
+    headers: &HeaderMap,
+) -> Result<(), Response> {
+    tracing::info!(connection = %connection, client = %client, "WebSocket connection attempt");
+    let requested_lobby = query_params
+        .get("lobby_id")
+        .map(|id| id.parse::<uuid::Uuid>())
+        .transpose()
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid lobby_id").into_response())?;
+
+    let control_events = auth::wants_control_events(headers, query_params);
+    let resume = auth::wants_resume(headers, query_params);
+
+    let token = match auth::ws_token(path, headers) {
+        auth::WsToken::Subprotocol(token) => token,
+        auth::WsToken::Path(token) if state.config.allow_token_in_path => token,
+        auth::WsToken::Path(_) => {
+            tracing::warn!(connection = %connection, client = %client, "Token in path is disabled");
+            return Err((StatusCode::UNAUTHORIZED, "Token in path is disabled").into_response());
+        }
+        auth::WsToken::Missing => {
+            tracing::info!(connection = %connection, client = %client, "No token on upgrade, waiting for Authenticate message");
+            let waiting = WaitingPlayer {
+                client,
+                player_id: None,
+                username: String::new(),
+                lobby_id: requested_lobby,
+                control_events,
+                resume,
+            };
+            state.core.cast(move |core| {
+                core.sessions.wait(connection, waiting);
+            });
+            return Ok(());
+        }
+    };
+
+    let claims = auth::decode_token(token, &state.secret).map_err(|e| {
+        tracing::warn!(connection = %connection, client = %client, error = ?e, "Invalid token");
+        (StatusCode::UNAUTHORIZED, "Invalid token").into_response()
+    })?;
+
+    state
+        .core
+        .try_call(move |core| {
+            let lobby_id = core
+                .connection_lobby(&claims.sub, requested_lobby)
+                .inspect_err(|e| {
+                    tracing::warn!(connection = %connection, client = %client, pubkey = %&claims.sub[..8], lobby_id = ?requested_lobby, error = %e, "Refusing signaling connection");
+                })?;
+
+            tracing::info!(connection = %connection, client = %client, pubkey = %&claims.sub[..8], lobby_id = %lobby_id, "WebSocket connection request: player connected");
+            let waiting_count = core.sessions.wait(
+                connection,
+                WaitingPlayer {
+                    client,
+                    player_id: Some(claims.sub),
+                    username: claims.username,
+                    lobby_id: Some(lobby_id),
+                    control_events,
+                    resume,
+                },
+            );
+            tracing::debug!(waiting_count, "Connections waiting for a peer id");
+            Ok::<_, LobbyError>(())
+        })
+        .await
+        .map_err(IntoResponse::into_response)
+}
+
 fn app(state: AppState) -> Router {
     Router::new()
         .route("/health", get(health_handler))
         .route("/auth/challenge", post(challenge_handler))
         .route("/auth/login", post(login_handler))
+        .route("/ice-servers", get(ice_servers_handler))
         .route(
             "/lobbies",
             post(create_lobby_handler).get(list_lobbies_handler),
         )
+        .route("/lobbies/:lobby_id", delete(close_lobby_handler))
         .route("/lobbies/:lobby_id/join", post(join_lobby_handler))
+        .route("/lobbies/:lobby_id/start", post(start_lobby_handler))
+        .route(
+            "/lobbies/:lobby_id/teams/balance",
+            post(balance_teams_handler),
+        )
+        .route(
+            "/lobbies/:lobby_id/members/me",
+            patch(update_member_state_handler),
+        )
+        .route("/lobbies/:lobby_id/ratings", put(set_member_rating_handler))
+        .route(
+            "/lobbies/:lobby_id/whitelist",
+            post(add_to_whitelist_handler).delete(remove_from_whitelist_handler),
+        )
+        .route("/lobbies/:lobby_id/privacy", put(set_lobby_privacy_handler))
+        .route(
+            "/lobbies/:lobby_id/metadata",
+            put(update_lobby_metadata_handler),
+        )
+        .route("/lobbies/:lobby_id/invites", post(create_invite_handler))
+        .route("/invites", get(list_invites_handler))
+        .route("/invites/:invite_id/accept", post(accept_invite_handler))
+        .route("/invites/:invite_id/decline", post(decline_invite_handler))
         // TODO: Restrict CORS for production environments
-        .layer(CorsLayer::very_permissive())
+        .layer(
+            CorsLayer::very_permissive()
+                .expose_headers([HeaderName::from_static(NEXT_CURSOR_HEADER)]),
+        )
         .with_state(state)
 }
 
@@ -155,9 +404,15 @@ struct ChallengeResponse {
     challenge: String,
 }
 
-async fn challenge_handler(State(state): State<AppState>) -> Json<ChallengeResponse> {
-    let challenge = state.state.challenge_manager.generate_challenge();
-    Json(ChallengeResponse { challenge })
+async fn challenge_handler(
+    State(state): State<AppState>,
+) -> Result<Json<ChallengeResponse>, ActorError> {
+    let challenge = state
+        .state
+        .core
+        .call(|core| core.challenges.generate_challenge())
+        .await?;
+    Ok(Json(ChallengeResponse { challenge }))
 }
 
 #[derive(Deserialize)]
@@ -177,13 +432,16 @@ async fn login_handler(
         "Login attempt"
     );
 
-    if !state
+    let challenge = payload.challenge.clone();
+    let challenge_valid = state
         .state
-        .challenge_manager
-        .verify_challenge(&payload.challenge)
-    {
+        .core
+        .call(move |core| core.challenges.verify_challenge(&challenge))
+        .await
+        .map_err(IntoResponse::into_response)?;
+    if !challenge_valid {
         tracing::warn!(pubkey = %payload.public_key_b64, "Challenge verification failed");
-        return Err((StatusCode::UNAUTHORIZED, "Invalid challenge"));
+        return Err((StatusCode::UNAUTHORIZED, "Invalid challenge").into_response());
     }
 
     let signature_valid = match auth::verify_signature(
@@ -197,13 +455,13 @@ async fn login_handler(
         }
         Err(e) => {
             tracing::warn!(pubkey = %payload.public_key_b64, error = ?e, "Signature verification error");
-            return Err((StatusCode::UNAUTHORIZED, "Invalid signature"));
+            return Err((StatusCode::UNAUTHORIZED, "Invalid signature").into_response());
         }
     };
 
     if !signature_valid {
         tracing::warn!(pubkey = %payload.public_key_b64, "Signature validation failed");
-        return Err((StatusCode::UNAUTHORIZED, "Invalid signature"));
+        return Err((StatusCode::UNAUTHORIZED, "Invalid signature").into_response());
     }
 
     match auth::issue_jwt(
@@ -217,16 +475,47 @@ async fn login_handler(
         }
         Err(_) => {
             tracing::error!(pubkey = %payload.public_key_b64, "Failed to issue JWT");
-            Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to issue token"))
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to issue token").into_response())
         }
     }
 }
 
+/// STUN and TURN servers for the player, with TURN credentials bound to their public key.
+async fn ice_servers_handler(
+    State(state): State<AppState>,
+    claims: auth::Claims,
+    headers: HeaderMap,
+) -> Json<IceServers> {
+    let config = &state.state.config;
+    let host = advertised_host(&headers, config.host.ip());
+    let ice_servers = IceServers::for_player(config, &claims.sub, &host, chrono::Utc::now());
+    if let Some(expires_at) = ice_servers.expires_at {
+        tracing::info!(pubkey = %&claims.sub[..8], expires_at = %expires_at, "Issued TURN credentials");
+    }
+    Json(ice_servers)
+}
+
 #[derive(Deserialize)]
 pub struct CreateLobbyRequest {
     is_private: bool,
     #[serde(default)]
     whitelist: Option<Vec<String>>,
+    #[serde(default)]
+    max_players: Option<usize>,
+    #[serde(default)]
+    min_players: Option<usize>,
+    #[serde(default)]
+    auto_start: bool,
+    #[serde(default)]
+    team_count: Option<usize>,
+    #[serde(default)]
+    team_size: Option<usize>,
+    #[serde(default)]
+    topology: Topology,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(flatten)]
+    metadata: LobbyMetadata,
 }
 
 async fn create_lobby_handler(
@@ -234,17 +523,87 @@ async fn create_lobby_handler(
     claims: auth::Claims,
     Json(payload): Json<CreateLobbyRequest>,
 ) -> impl IntoResponse {
-    let mut lobby_manager = state.state.lobby_manager.write().unwrap();
-    // Create lobby and ensure the owner is present atomically
-    let lobby = lobby_manager.create_lobby_with_owner(payload.is_private, claims.sub.clone(), payload.whitelist);
-    let mut players_in_lobbies = state.state.players_in_lobbies.write().unwrap();
-    players_in_lobbies.insert(claims.sub.clone(), lobby.id);
+    if let Err(e) = payload.metadata.validate() {
+        tracing::warn!(pubkey = %&claims.sub[..8], error = %e, "Rejected lobby creation");
+        return e.into_response();
+    }
+    let mut max_players = payload.max_players;
+    let teams = match Teams::new(payload.team_count, payload.team_size, &mut max_players) {
+        Ok(teams) => teams,
+        Err(e) => return e.into_response(),
+    };
+    // Checked after the teams, which may have derived max_players
+    if let Some(min_players) = payload.min_players {
+        if min_players == 0 || max_players.is_some_and(|max| min_players > max) {
+            return LobbyError::InvalidPlayerLimits.into_response();
+        }
+    }
+    let password_hash = match payload.password {
+        Some(password) if password.is_empty() || password.len() > MAX_PASSWORD_LEN => {
+            return LobbyError::InvalidPassword.into_response();
+        }
+        Some(password) => {
+            let hashed = state
+                .state
+                .run_argon2(move || auth::hash_password(&password))
+                .await;
+            match hashed {
+                Ok(Ok(hash)) => Some(hash),
+                result => {
+                    tracing::error!(?result, "Failed to hash lobby password");
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+            }
+        }
+        None => None,
+    };
+    let settings = LobbySettings {
+        is_private: payload.is_private,
+        whitelist: payload.whitelist,
+        max_players,
+        min_players: payload.min_players,
+        auto_start: payload.auto_start,
+        teams,
+        topology: payload.topology,
+        password_hash,
+        metadata: payload.metadata,
+    };
+    let player_id = claims.sub.clone();
+    let lobby = state
+        .state
+        .core
+        .call(move |core| {
+            // Create lobby and ensure the owner is present atomically
+            let lobby = core
+                .lobbies
+                .create_lobby_with_owner(player_id.clone(), settings);
+            let previous_lobby = core.assign_player_lobby(&player_id, lobby.id);
+            leave_previous_lobby(core, &player_id, previous_lobby);
+            lobby
+        })
+        .await;
+    let lobby = match lobby {
+        Ok(lobby) => lobby,
+        Err(e) => return e.into_response(),
+    };
     tracing::info!(lobby_id = %lobby.id, pubkey = %&claims.sub[..8], "Lobby created and player added");
-    Json(lobby)
+    Json(lobby).into_response()
+}
+
+/// A player can only be in one lobby at a time. Once they have been moved, close the socket they
+/// still hold for the previous lobby so its peers get `PeerLeft`.
+fn leave_previous_lobby(core: &mut Core, player_id: &str, previous_lobby: Option<LeftLobby>) {
+    if let Some(previous_lobby) = previous_lobby {
+        let lobby_id = previous_lobby.lobby_id;
+        tracing::info!(lobby_id = %lobby_id, pubkey = %&player_id[..8], "Player left previous lobby");
+        core.disconnect_player(player_id, MOVED_CLOSE_CODE, "Joined another lobby");
+        core.announce_handover(lobby_id, previous_lobby.handover);
+    }
 }
 
 async fn list_lobbies_handler(
     State(state): State<AppState>,
+    Query(query): Query<LobbyQuery>,
     headers: HeaderMap,
 ) -> impl IntoResponse {
     // Try to extract bearer token from Authorization header and decode claims
@@ -252,49 +611,498 @@ async fn list_lobbies_handler(
         .get("authorization")
         .and_then(|hv| hv.to_str().ok())
         .and_then(|auth| auth.strip_prefix("Bearer "))
-        .and_then(|token| {
-            decode::<auth::Claims>(
-                token,
-                &DecodingKey::from_secret(state.secret.0.as_ref()),
-                &Validation::default(),
-            )
-            .ok()
-            .map(|data| data.claims.sub)
-        });
+        .and_then(|token| auth::decode_token(token, &state.secret).ok())
+        .map(|claims| claims.sub);
+
+    // Filtered and paged in place, only the requested page is cloned out of the state
+    let page = state
+        .state
+        .core
+        .try_call(move |core| {
+            let lobbies = core
+                .lobbies
+                .lobbies_for_player(player_pubkey.as_deref())
+                .filter(|lobby| query.matches(lobby));
+            query.paginate(lobbies)
+        })
+        .await;
+    let page = match page {
+        Ok(page) => page,
+        Err(e) => return e.into_response(),
+    };
+
+    let mut response_headers = HeaderMap::new();
+    if let Some(cursor) = page.next_cursor {
+        response_headers.insert(
+            NEXT_CURSOR_HEADER,
+            HeaderValue::from_str(&cursor).expect("cursor is url-safe base64"),
+        );
+    }
+    (response_headers, Json(page.lobbies)).into_response()
+}
 
-    let lobby_manager = state.state.lobby_manager.read().unwrap();
-    let lobbies = lobby_manager.get_lobbies_for_player(player_pubkey);
-    Json(lobbies)
+#[derive(Deserialize, Default)]
+pub struct JoinLobbyRequest {
+    #[serde(default)]
+    password: Option<String>,
+    /// Join as a spectator instead of taking a seat
+    #[serde(default)]
+    spectate: bool,
 }
 
 async fn join_lobby_handler(
     State(state): State<AppState>,
     Path(lobby_id): Path<uuid::Uuid>,
+    ConnectInfo(origin): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     claims: auth::Claims,
+    payload: Option<Json<JoinLobbyRequest>>,
 ) -> impl IntoResponse {
-    let mut lobby_manager = state.state.lobby_manager.write().unwrap();
-    let result = lobby_manager.add_player_to_lobby(&lobby_id, claims.sub.clone());
-
-    if result.is_err() {
-        // Check if it's a whitelist rejection
-        let lobby = lobby_manager.get_lobby(&lobby_id);
-        if let Some(lobby) = lobby {
-            if let Some(whitelist) = &lobby.whitelist {
-                if !whitelist.contains(&claims.sub) {
-                    tracing::warn!(lobby_id = %lobby_id, pubkey = %&claims.sub[..8], "Player not in whitelist");
-                    return (StatusCode::FORBIDDEN, "Not in whitelist").into_response();
-                }
+    let Json(mut payload) = payload.unwrap_or_default();
+    let client = client_ip(origin.ip(), &headers, &state.state.config.trusted_proxies);
+    // Check the password outside of the state core, argon2 verification is slow
+    let player_id = claims.sub.clone();
+    let password_hash = state
+        .state
+        .core
+        .try_call(move |core| {
+            let password_hash = match core.lobbies.lobby(&lobby_id) {
+                Some(lobby) if !lobby.is_member(&player_id) => lobby.password_hash.clone(),
+                Some(_) => None,
+                None => return Err(LobbyError::NotFound),
+            };
+            if password_hash.is_some() && !core.try_password_attempt(lobby_id, client) {
+                return Err(LobbyError::TooManyAttempts);
             }
+            Ok(password_hash)
+        })
+        .await;
+    let password_hash = match password_hash {
+        Ok(password_hash) => password_hash,
+        Err(e) => {
+            tracing::warn!(lobby_id = %lobby_id, pubkey = %&claims.sub[..8], error = %e, "Player failed to join lobby");
+            return e.into_response();
+        }
+    };
+    if let Some(password_hash) = password_hash {
+        let password = payload.password.take();
+        let valid = state
+            .state
+            .run_argon2(move || {
+                password.is_some_and(|password| auth::verify_password(&password, &password_hash))
+            })
+            .await
+            .unwrap_or(false);
+        if !valid {
+            tracing::warn!(lobby_id = %lobby_id, pubkey = %&claims.sub[..8], "Wrong lobby password");
+            return LobbyError::WrongPassword.into_response();
         }
-        tracing::warn!(lobby_id = %lobby_id, pubkey = %&claims.sub[..8], "Player failed to join lobby: not found");
-        return (StatusCode::NOT_FOUND, "Lobby not found").into_response();
     }
 
-    // Insert the joining player's public key into players_in_lobbies
-    let mut players_in_lobbies = state.state.players_in_lobbies.write().unwrap();
-    players_in_lobbies.insert(claims.sub.clone(), lobby_id);
+    let player_id = claims.sub.clone();
+    let spectate = payload.spectate;
+    let joined = state
+        .state
+        .core
+        .try_call(move |core| {
+            if spectate {
+                core.lobbies
+                    .add_spectator_to_lobby(&lobby_id, player_id.clone())?;
+            } else {
+                core.lobbies
+                    .add_player_to_lobby(&lobby_id, player_id.clone())?;
+            }
+            // Point the joining player's public key at the new lobby, leaving any previous one
+            let previous_lobby = core.assign_player_lobby(&player_id, lobby_id);
+            leave_previous_lobby(core, &player_id, previous_lobby);
+            Ok::<_, LobbyError>(())
+        })
+        .await;
+    if let Err(e) = joined {
+        tracing::warn!(lobby_id = %lobby_id, pubkey = %&claims.sub[..8], error = %e, "Player failed to join lobby");
+        return e.into_response();
+    }
     tracing::debug!(full_pubkey = %claims.sub, "Full public key for join");
-    tracing::debug!(players_in_lobbies = ?*players_in_lobbies, "Current players_in_lobbies map");
-    tracing::info!(lobby_id = %lobby_id, pubkey = %&claims.sub[..8], "Player joined lobby");
+    tracing::info!(lobby_id = %lobby_id, pubkey = %&claims.sub[..8], spectate = payload.spectate, "Player joined lobby");
     StatusCode::OK.into_response()
 }
+
+async fn start_lobby_handler(
+    State(state): State<AppState>,
+    Path(lobby_id): Path<uuid::Uuid>,
+    claims: auth::Claims,
+) -> impl IntoResponse {
+    let player_id = claims.sub.clone();
+    let result = state
+        .state
+        .core
+        .try_call(move |core| core.lobbies.start_lobby(&lobby_id, &player_id))
+        .await;
+    match result {
+        Ok(lobby) => {
+            tracing::info!(lobby_id = %lobby_id, pubkey = %&claims.sub[..8], "Lobby started");
+            let event = ServerEvent::lobby_started(&lobby);
+            state.state.broadcast_event(lobby_id, None, event);
+            Json(lobby).into_response()
+        }
+        Err(e) => {
+            tracing::warn!(lobby_id = %lobby_id, pubkey = %&claims.sub[..8], error = %e, "Failed to start lobby");
+            e.into_response()
+        }
+    }
+}
+
+async fn update_member_state_handler(
+    State(state): State<AppState>,
+    Path(lobby_id): Path<uuid::Uuid>,
+    claims: auth::Claims,
+    Json(payload): Json<MemberStateUpdate>,
+) -> impl IntoResponse {
+    let player_id = claims.sub.clone();
+    let result = state
+        .state
+        .core
+        .try_call(move |core| {
+            core.lobbies
+                .update_member_state(&lobby_id, &player_id, payload)
+        })
+        .await;
+    let (lobby, started) = match result {
+        Ok(result) => result,
+        Err(e) => {
+            tracing::warn!(lobby_id = %lobby_id, pubkey = %&claims.sub[..8], error = %e, "Failed to update member state");
+            return e.into_response();
+        }
+    };
+
+    let member = lobby.members[&claims.sub].clone();
+    let event = ServerEvent::MemberUpdated {
+        lobby_id,
+        pubkey: claims.sub.clone(),
+        state: member,
+    };
+    state.state.broadcast_event(lobby_id, None, event);
+    if started {
+        state
+            .state
+            .core
+            .cast(move |core| core.announce_auto_start(&lobby_id));
+    }
+    Json(lobby).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct MemberRatingRequest {
+    pubkey: String,
+    rating: Option<i32>,
+}
+
+async fn set_member_rating_handler(
+    State(state): State<AppState>,
+    Path(lobby_id): Path<uuid::Uuid>,
+    claims: auth::Claims,
+    Json(payload): Json<MemberRatingRequest>,
+) -> impl IntoResponse {
+    let player_id = claims.sub.clone();
+    let pubkey = payload.pubkey.clone();
+    let result = state
+        .state
+        .core
+        .try_call(move |core| {
+            core.lobbies
+                .set_member_rating(&lobby_id, &player_id, &payload.pubkey, payload.rating)
+        })
+        .await;
+    let lobby = match result {
+        Ok(lobby) => lobby,
+        Err(e) => {
+            tracing::warn!(lobby_id = %lobby_id, pubkey = %&claims.sub[..8], error = %e, "Failed to set member rating");
+            return e.into_response();
+        }
+    };
+
+    let event = ServerEvent::MemberUpdated {
+        lobby_id,
+        state: lobby.members[&pubkey].clone(),
+        pubkey,
+    };
+    state.state.broadcast_event(lobby_id, None, event);
+    Json(lobby).into_response()
+}
+
+async fn balance_teams_handler(
+    State(state): State<AppState>,
+    Path(lobby_id): Path<uuid::Uuid>,
+    claims: auth::Claims,
+) -> impl IntoResponse {
+    let player_id = claims.sub.clone();
+    let result = state
+        .state
+        .core
+        .try_call(move |core| core.lobbies.balance_teams(&lobby_id, &player_id))
+        .await;
+    match result {
+        Ok(lobby) => {
+            tracing::info!(lobby_id = %lobby_id, "Teams balanced");
+            let teams = lobby
+                .teams
+                .as_ref()
+                .map(|teams| teams.rosters.clone())
+                .unwrap_or_default();
+            let event = ServerEvent::TeamsChanged { lobby_id, teams };
+            state.state.broadcast_event(lobby_id, None, event);
+            Json(lobby).into_response()
+        }
+        Err(e) => {
+            tracing::warn!(lobby_id = %lobby_id, pubkey = %&claims.sub[..8], error = %e, "Failed to balance teams");
+            e.into_response()
+        }
+    }
+}
+
+async fn close_lobby_handler(
+    State(state): State<AppState>,
+    Path(lobby_id): Path<uuid::Uuid>,
+    claims: auth::Claims,
+) -> impl IntoResponse {
+    let player_id = claims.sub.clone();
+    let result = state
+        .state
+        .core
+        .try_call(move |core| core.lobbies.close_lobby(&lobby_id, &player_id))
+        .await;
+    let lobby = match result {
+        Ok(lobby) => lobby,
+        Err(e) => {
+            tracing::warn!(lobby_id = %lobby_id, pubkey = %&claims.sub[..8], error = %e, "Failed to close lobby");
+            return e.into_response();
+        }
+    };
+
+    state.state.core.cast(move |core| core.lobby_closed(&lobby));
+    tracing::info!(lobby_id = %lobby_id, pubkey = %&claims.sub[..8], "Lobby closed");
+    StatusCode::NO_CONTENT.into_response()
+}
+
+#[derive(Deserialize)]
+pub struct WhitelistRequest {
+    pubkeys: Vec<String>,
+}
+
+async fn add_to_whitelist_handler(
+    State(state): State<AppState>,
+    Path(lobby_id): Path<uuid::Uuid>,
+    claims: auth::Claims,
+    Json(payload): Json<WhitelistRequest>,
+) -> impl IntoResponse {
+    let player_id = claims.sub.clone();
+    let pubkeys = payload.pubkeys;
+    let result = state
+        .state
+        .core
+        .try_call(move |core| {
+            core.lobbies
+                .add_to_whitelist(&lobby_id, &player_id, pubkeys)
+        })
+        .await;
+    match result {
+        Ok(lobby) => {
+            tracing::info!(lobby_id = %lobby_id, pubkey = %&claims.sub[..8], "Whitelist entries added");
+            Json(lobby).into_response()
+        }
+        Err(e) => {
+            tracing::warn!(lobby_id = %lobby_id, pubkey = %&claims.sub[..8], error = %e, "Failed to add whitelist entries");
+            e.into_response()
+        }
+    }
+}
+
+async fn remove_from_whitelist_handler(
+    State(state): State<AppState>,
+    Path(lobby_id): Path<uuid::Uuid>,
+    claims: auth::Claims,
+    Json(payload): Json<WhitelistRequest>,
+) -> impl IntoResponse {
+    let player_id = claims.sub.clone();
+    let result = state
+        .state
+        .core
+        .try_call(move |core| {
+            let (lobby, kicked, started) =
+                core.lobbies
+                    .remove_from_whitelist(&lobby_id, &player_id, payload.pubkeys)?;
+            for player_id in &kicked {
+                core.sessions.unseat(player_id, lobby_id);
+                let event = ServerEvent::Kicked {
+                    lobby_id,
+                    reason: "Removed from lobby whitelist".to_string(),
+                };
+                core.send_event_to_player(player_id, &event);
+                core.disconnect_player(player_id, KICKED_CLOSE_CODE, "Removed from lobby whitelist");
+                tracing::info!(lobby_id = %lobby_id, pubkey = %&player_id[..8], "Player kicked after whitelist removal");
+            }
+            if started {
+                core.announce_auto_start(&lobby_id);
+            }
+            Ok::<_, LobbyError>(lobby)
+        })
+        .await;
+    let lobby = match result {
+        Ok(lobby) => lobby,
+        Err(e) => {
+            tracing::warn!(lobby_id = %lobby_id, pubkey = %&claims.sub[..8], error = %e, "Failed to remove whitelist entries");
+            return e.into_response();
+        }
+    };
+
+    Json(lobby).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct LobbyPrivacyRequest {
+    is_private: bool,
+}
+
+async fn set_lobby_privacy_handler(
+    State(state): State<AppState>,
+    Path(lobby_id): Path<uuid::Uuid>,
+    claims: auth::Claims,
+    Json(payload): Json<LobbyPrivacyRequest>,
+) -> impl IntoResponse {
+    let player_id = claims.sub.clone();
+    let result = state
+        .state
+        .core
+        .try_call(move |core| {
+            core.lobbies
+                .set_lobby_privacy(&lobby_id, &player_id, payload.is_private)
+        })
+        .await;
+    match result {
+        Ok(lobby) => {
+            tracing::info!(lobby_id = %lobby_id, is_private = payload.is_private, "Lobby privacy changed");
+            Json(lobby).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn update_lobby_metadata_handler(
+    State(state): State<AppState>,
+    Path(lobby_id): Path<uuid::Uuid>,
+    claims: auth::Claims,
+    Json(payload): Json<LobbyMetadata>,
+) -> impl IntoResponse {
+    let player_id = claims.sub.clone();
+    let result = state
+        .state
+        .core
+        .try_call(move |core| {
+            core.lobbies
+                .update_lobby_metadata(&lobby_id, &player_id, payload)
+        })
+        .await;
+    match result {
+        Ok(lobby) => {
+            tracing::info!(lobby_id = %lobby_id, "Lobby metadata updated");
+            Json(lobby).into_response()
+        }
+        Err(e) => {
+            tracing::warn!(lobby_id = %lobby_id, pubkey = %&claims.sub[..8], error = %e, "Failed to update lobby metadata");
+            e.into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateInviteRequest {
+    invitee: String,
+}
+
+async fn create_invite_handler(
+    State(state): State<AppState>,
+    Path(lobby_id): Path<uuid::Uuid>,
+    claims: auth::Claims,
+    Json(payload): Json<CreateInviteRequest>,
+) -> impl IntoResponse {
+    let player_id = claims.sub.clone();
+    let invitee = payload.invitee;
+    let ttl = state.state.config.invite_ttl();
+    let result = state
+        .state
+        .core
+        .try_call(move |core| {
+            core.lobbies
+                .create_invite(&lobby_id, &player_id, invitee, ttl)
+        })
+        .await;
+    match result {
+        Ok(invite) => {
+            tracing::info!(lobby_id = %lobby_id, invite_id = %invite.id, pubkey = %&claims.sub[..8], "Invite created");
+            Json(invite).into_response()
+        }
+        Err(e) => {
+            tracing::warn!(lobby_id = %lobby_id, pubkey = %&claims.sub[..8], error = %e, "Failed to create invite");
+            e.into_response()
+        }
+    }
+}
+
+async fn list_invites_handler(
+    State(state): State<AppState>,
+    claims: auth::Claims,
+) -> impl IntoResponse {
+    let invites = state
+        .state
+        .core
+        .call(move |core| core.lobbies.get_invites_for_player(&claims.sub))
+        .await;
+    invites.map(Json)
+}
+
+async fn accept_invite_handler(
+    State(state): State<AppState>,
+    Path(invite_id): Path<uuid::Uuid>,
+    claims: auth::Claims,
+) -> impl IntoResponse {
+    let player_id = claims.sub.clone();
+    let result = state
+        .state
+        .core
+        .try_call(move |core| {
+            let lobby = core.lobbies.accept_invite(&invite_id, &player_id)?;
+            let previous_lobby = core.assign_player_lobby(&player_id, lobby.id);
+            leave_previous_lobby(core, &player_id, previous_lobby);
+            Ok::<_, LobbyError>(lobby)
+        })
+        .await;
+    let lobby = match result {
+        Ok(lobby) => lobby,
+        Err(e) => {
+            tracing::warn!(invite_id = %invite_id, pubkey = %&claims.sub[..8], error = %e, "Failed to accept invite");
+            return e.into_response();
+        }
+    };
+    tracing::info!(lobby_id = %lobby.id, pubkey = %&claims.sub[..8], "Player accepted invite and joined lobby");
+    Json(lobby).into_response()
+}
+
+async fn decline_invite_handler(
+    State(state): State<AppState>,
+    Path(invite_id): Path<uuid::Uuid>,
+    claims: auth::Claims,
+) -> impl IntoResponse {
+    let player_id = claims.sub.clone();
+    let result = state
+        .state
+        .core
+        .try_call(move |core| core.lobbies.decline_invite(&invite_id, &player_id))
+        .await;
+    match result {
+        Ok(()) => {
+            tracing::info!(invite_id = %invite_id, pubkey = %&claims.sub[..8], "Invite declined");
+            StatusCode::OK.into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
@@ -1,9 +1,35 @@
-use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use crate::actor::ActorError;
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use thiserror::Error;
 use uuid::Uuid;
 
 pub type PlayerId = String;
 
+pub const MAX_PASSWORD_LEN: usize = 128;
+/// Password attempts a client address can make in a row at a lobby, refilled at
+/// [`PASSWORD_ATTEMPTS_PER_SEC`]
+pub const PASSWORD_ATTEMPT_BURST: u32 = 5;
+pub const PASSWORD_ATTEMPTS_PER_SEC: f64 = 1.0 / 30.0;
+/// Lobby password hashes and verifications running at once. Each takes about 19 MiB.
+pub const MAX_CONCURRENT_PASSWORD_HASHES: usize = 4;
+pub const MAX_METADATA_FIELD_LEN: usize = 64;
+pub const MAX_LOBBY_ATTRIBUTES: usize = 16;
+pub const MAX_ATTRIBUTE_KEY_LEN: usize = 32;
+pub const MAX_ATTRIBUTE_VALUE_LEN: usize = 256;
+pub const MAX_TEAM_COUNT: usize = 16;
+pub const MAX_TEAM_SIZE: usize = 64;
+/// Largest custom member state blob, in bytes of JSON.
+pub const MAX_MEMBER_CUSTOM_BYTES: usize = 1024;
+/// Invites a lobby can have pending at once
+pub const MAX_PENDING_INVITES: usize = 64;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Player {
     pub id: PlayerId,
@@ -15,12 +41,503 @@ pub enum LobbyStatus {
     InProgress,
 }
 
+/// How the peers of a lobby are connected
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Topology {
+    /// Every member connects to every other member
+    #[default]
+    Mesh,
+    /// Every member only connects to the host
+    Star,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct Lobby {
     pub id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<PlayerId>,
     pub players: HashSet<PlayerId>,
+    /// Watch the match without taking a seat
+    pub spectators: HashSet<PlayerId>,
     pub status: LobbyStatus,
     pub is_private: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub whitelist: Option<HashSet<PlayerId>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_players: Option<usize>,
+    /// Argon2 hash of the join password. Only exposed as `has_password`.
+    #[serde(rename = "has_password", serialize_with = "serialize_is_some")]
+    pub password_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_players: Option<usize>,
+    /// Start the lobby as soon as every player is ready and `min_players` is met
+    pub auto_start: bool,
+    /// Per-player state, one entry for each of `players`
+    pub members: HashMap<PlayerId, MemberState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub teams: Option<Teams>,
+    pub topology: Topology,
+    /// Player the other members connect to in a star lobby. Defaults to the owner.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<PlayerId>,
+    pub created_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub metadata: LobbyMetadata,
+}
+
+/// Options chosen when creating a lobby
+#[derive(Debug, Clone, Default)]
+pub struct LobbySettings {
+    pub is_private: bool,
+    pub whitelist: Option<Vec<String>>,
+    pub max_players: Option<usize>,
+    pub min_players: Option<usize>,
+    pub auto_start: bool,
+    pub teams: Option<Teams>,
+    pub topology: Topology,
+    pub password_hash: Option<String>,
+    pub metadata: LobbyMetadata,
+}
+
+/// Team layout of a lobby. `rosters` lists the players of each team, derived from the members'
+/// `team` picks.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Teams {
+    pub count: usize,
+    pub size: usize,
+    pub rosters: Vec<Vec<PlayerId>>,
+}
+
+impl Teams {
+    /// Validate the requested layout. Both values must be given together; a lobby with teams
+    /// holds at most `count * size` players.
+    pub fn new(
+        count: Option<usize>,
+        size: Option<usize>,
+        max_players: &mut Option<usize>,
+    ) -> Result<Option<Teams>, LobbyError> {
+        let (count, size) = match (count, size) {
+            (None, None) => return Ok(None),
+            (Some(count), Some(size)) if count > 0 && size > 0 => (count, size),
+            _ => {
+                return Err(LobbyError::InvalidTeams(
+                    "team_count and team_size must both be at least 1".to_string(),
+                ))
+            }
+        };
+        if count > MAX_TEAM_COUNT || size > MAX_TEAM_SIZE {
+            return Err(LobbyError::InvalidTeams(format!(
+                "at most {MAX_TEAM_COUNT} teams of {MAX_TEAM_SIZE} players"
+            )));
+        }
+        let capacity = count.checked_mul(size).ok_or_else(|| {
+            LobbyError::InvalidTeams("team_count * team_size is too large".to_string())
+        })?;
+        match max_players {
+            Some(max) if *max > capacity => {
+                return Err(LobbyError::InvalidTeams(format!(
+                    "max_players exceeds the {capacity} team seats"
+                )))
+            }
+            Some(_) => {}
+            None => *max_players = Some(capacity),
+        }
+        Ok(Some(Teams {
+            count,
+            size,
+            rosters: vec![Vec::new(); count],
+        }))
+    }
+}
+
+/// What a lobby member picked before the game starts
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MemberState {
+    pub ready: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub team: Option<usize>,
+    /// Skill rating used to balance teams. Only the lobby owner sets it, members cannot rate
+    /// themselves.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rating: Option<i32>,
+    /// Game-defined data such as the chosen character
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub custom: Option<serde_json::Value>,
+}
+
+/// Partial update of a [`MemberState`] by the member, absent fields are left unchanged. The
+/// rating is not part of it and is refused like any other unknown field.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemberStateUpdate {
+    #[serde(default)]
+    pub ready: Option<bool>,
+    #[serde(default)]
+    pub team: Option<usize>,
+    #[serde(default)]
+    pub custom: Option<serde_json::Value>,
+}
+
+impl MemberStateUpdate {
+    pub fn validate(&self) -> Result<(), LobbyError> {
+        if self
+            .custom
+            .as_ref()
+            .is_some_and(|custom| custom.to_string().len() > MAX_MEMBER_CUSTOM_BYTES)
+        {
+            return Err(LobbyError::InvalidMemberState(format!(
+                "custom state exceeds {MAX_MEMBER_CUSTOM_BYTES} bytes"
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn apply(self, state: &mut MemberState) {
+        if let Some(ready) = self.ready {
+            state.ready = ready;
+        }
+        if let Some(team) = self.team {
+            state.team = Some(team);
+        }
+        if let Some(custom) = self.custom {
+            state.custom = Some(custom);
+        }
+    }
+}
+
+fn serialize_is_some<S: Serializer>(
+    value: &Option<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_bool(value.is_some())
+}
+
+/// Descriptive information shown in lobby browsers. Set by the creator and editable by the owner.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LobbyMetadata {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub game_id: Option<String>,
+    #[serde(default)]
+    pub game_version: Option<String>,
+    #[serde(default)]
+    pub mode: Option<String>,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+}
+
+impl LobbyMetadata {
+    pub fn validate(&self) -> Result<(), LobbyError> {
+        let fields = [
+            ("name", &self.name),
+            ("game_id", &self.game_id),
+            ("game_version", &self.game_version),
+            ("mode", &self.mode),
+        ];
+        for (field, value) in fields {
+            if value
+                .as_ref()
+                .is_some_and(|v| v.len() > MAX_METADATA_FIELD_LEN)
+            {
+                return Err(LobbyError::InvalidMetadata(format!(
+                    "{field} exceeds {MAX_METADATA_FIELD_LEN} bytes"
+                )));
+            }
+        }
+        if self.attributes.len() > MAX_LOBBY_ATTRIBUTES {
+            return Err(LobbyError::InvalidMetadata(format!(
+                "at most {MAX_LOBBY_ATTRIBUTES} attributes are allowed"
+            )));
+        }
+        for (key, value) in &self.attributes {
+            if key.is_empty() || key.len() > MAX_ATTRIBUTE_KEY_LEN {
+                return Err(LobbyError::InvalidMetadata(format!(
+                    "attribute keys must be 1 to {MAX_ATTRIBUTE_KEY_LEN} bytes"
+                )));
+            }
+            if value.len() > MAX_ATTRIBUTE_VALUE_LEN {
+                return Err(LobbyError::InvalidMetadata(format!(
+                    "attribute {key} exceeds {MAX_ATTRIBUTE_VALUE_LEN} bytes"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Lobby {
+    pub fn is_owner(&self, player_id: &str) -> bool {
+        self.owner.as_deref() == Some(player_id)
+    }
+
+    pub fn is_host(&self, player_id: &str) -> bool {
+        self.host.as_deref() == Some(player_id)
+    }
+
+    /// Remaining seats, or `None` if the lobby has no capacity limit.
+    pub fn free_slots(&self) -> Option<usize> {
+        self.max_players
+            .map(|max| max.saturating_sub(self.players.len()))
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.free_slots() == Some(0)
+    }
+
+    /// Players and spectators
+    pub fn is_member(&self, player_id: &str) -> bool {
+        self.players.contains(player_id) || self.spectators.contains(player_id)
+    }
+
+    /// Everyone connected to the lobby, players first, then spectators
+    pub fn everyone(&self) -> impl Iterator<Item = &PlayerId> {
+        self.players.iter().chain(self.spectators.iter())
+    }
+
+    pub fn add_player(&mut self, player_id: PlayerId) {
+        if self.topology == Topology::Star && self.host.is_none() {
+            self.host = Some(player_id.clone());
+        }
+        self.members.entry(player_id.clone()).or_default();
+        self.players.insert(player_id);
+    }
+
+    pub fn add_spectator(&mut self, player_id: PlayerId) {
+        self.spectators.insert(player_id);
+    }
+
+    /// Remove a player or spectator. Returns whether they were in the lobby.
+    pub fn remove_player(&mut self, player_id: &str) -> bool {
+        self.members.remove(player_id);
+        self.sync_teams();
+        let was_spectator = self.spectators.remove(player_id);
+        self.players.remove(player_id) || was_spectator
+    }
+
+    /// Check a member's team pick against the lobby's layout.
+    pub fn validate_team_pick(&self, player_id: &str, team: usize) -> Result<(), LobbyError> {
+        let Some(teams) = &self.teams else {
+            return Err(LobbyError::InvalidMemberState(
+                "this lobby has no teams".to_string(),
+            ));
+        };
+        if team >= teams.count {
+            return Err(LobbyError::InvalidMemberState(format!(
+                "team must be below {}",
+                teams.count
+            )));
+        }
+        let roster = &teams.rosters[team];
+        if roster.len() >= teams.size && !roster.iter().any(|p| p == player_id) {
+            return Err(LobbyError::TeamFull);
+        }
+        Ok(())
+    }
+
+    /// Split players across teams, strongest first onto the team with the lowest total rating.
+    /// Unrated players count as the average rating. With `keep_picks`, players who already chose
+    /// a team stay there and only the others are placed.
+    pub fn balance_teams(&mut self, keep_picks: bool) {
+        let Some(teams) = &self.teams else {
+            return;
+        };
+        let (count, size) = (teams.count, teams.size);
+        let rated: Vec<i64> = self
+            .members
+            .values()
+            .filter_map(|m| m.rating.map(i64::from))
+            .collect();
+        let average = if rated.is_empty() {
+            0
+        } else {
+            rated.iter().sum::<i64>() / rated.len() as i64
+        };
+        let rating = |member: &MemberState| member.rating.map(i64::from).unwrap_or(average);
+
+        let mut totals = vec![(0i64, 0usize); count];
+        let mut unplaced = Vec::new();
+        for (player_id, member) in &mut self.members {
+            match member.team {
+                Some(team) if keep_picks && team < count => {
+                    totals[team].0 += rating(member);
+                    totals[team].1 += 1;
+                }
+                _ => {
+                    member.team = None;
+                    unplaced.push((rating(member), player_id.clone()));
+                }
+            }
+        }
+        unplaced.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        for (player_rating, player_id) in unplaced {
+            let team = (0..count)
+                .filter(|&team| totals[team].1 < size)
+                .min_by_key(|&team| (totals[team], team));
+            if let Some(team) = team {
+                totals[team].0 += player_rating;
+                totals[team].1 += 1;
+                if let Some(member) = self.members.get_mut(&player_id) {
+                    member.team = Some(team);
+                }
+            }
+        }
+        self.sync_teams();
+    }
+
+    /// Rebuild the team rosters from the members' picks.
+    pub fn sync_teams(&mut self) {
+        let Some(teams) = &mut self.teams else {
+            return;
+        };
+        let mut rosters = vec![Vec::new(); teams.count];
+        for (player_id, member) in &self.members {
+            if let Some(team) = member.team.filter(|&team| team < teams.count) {
+                rosters[team].push(player_id.clone());
+            }
+        }
+        for roster in &mut rosters {
+            roster.sort();
+        }
+        teams.rosters = rosters;
+    }
+
+    /// Every player is ready and there are at least `min_players` of them.
+    pub fn all_ready(&self) -> bool {
+        !self.players.is_empty()
+            && self.players.len() >= self.min_players.unwrap_or(1)
+            && self.members.values().all(|member| member.ready)
+    }
+
+    /// Start a waiting auto-start lobby once every player is ready. Returns whether it started.
+    pub fn try_auto_start(&mut self) -> bool {
+        let start = self.status == LobbyStatus::Waiting && self.auto_start && self.all_ready();
+        if start {
+            self.balance_teams(true);
+            self.status = LobbyStatus::InProgress;
+        }
+        start
+    }
+}
+
+/// A pending invitation from a lobby owner to another player.
+#[derive(Debug, Clone, Serialize)]
+pub struct Invite {
+    pub id: Uuid,
+    pub lobby_id: Uuid,
+    pub inviter: PlayerId,
+    pub invitee: PlayerId,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl Invite {
+    pub fn new(
+        lobby_id: Uuid,
+        inviter: PlayerId,
+        invitee: PlayerId,
+        ttl: Duration,
+    ) -> Result<Self, LobbyError> {
+        let expires_at = chrono::Duration::from_std(ttl)
+            .ok()
+            .and_then(|ttl| Utc::now().checked_add_signed(ttl))
+            .ok_or(LobbyError::InvalidInviteTtl)?;
+        Ok(Self {
+            id: Uuid::new_v4(),
+            lobby_id,
+            inviter,
+            invitee,
+            expires_at,
+        })
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Utc::now() >= self.expires_at
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum LobbyError {
+    #[error("Lobby not found")]
+    NotFound,
+    #[error("Not in whitelist")]
+    NotWhitelisted,
+    #[error("Not a member of this lobby")]
+    NotMember,
+    #[error("Only the lobby owner can do this")]
+    NotOwner,
+    #[error("The lobby owner cannot be removed")]
+    CannotRemoveOwner,
+    #[error("The lobby owner cannot spectate")]
+    OwnerCannotSpectate,
+    #[error("Already in this lobby with another role, leave it first")]
+    RoleSwitch,
+    #[error("Invalid lobby metadata: {0}")]
+    InvalidMetadata(String),
+    #[error("Invalid member state: {0}")]
+    InvalidMemberState(String),
+    #[error("Invalid lobby password")]
+    WrongPassword,
+    #[error("Lobby password must be 1 to {MAX_PASSWORD_LEN} bytes")]
+    InvalidPassword,
+    #[error("Too many password attempts, try again later")]
+    TooManyAttempts,
+    #[error("Lobby is full")]
+    Full,
+    #[error("Lobby already started")]
+    AlreadyStarted,
+    #[error("min_players must be at least 1 and no more than max_players")]
+    InvalidPlayerLimits,
+    #[error("Invalid teams: {0}")]
+    InvalidTeams(String),
+    #[error("Team is full")]
+    TeamFull,
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
+    #[error("Invite not found")]
+    InviteNotFound,
+    #[error("Invite expired")]
+    InviteExpired,
+    #[error("Invitee must be a player public key")]
+    InvalidInvitee,
+    #[error("Too many pending invites, at most {MAX_PENDING_INVITES}")]
+    TooManyInvites,
+    #[error("Invite lifetime is out of range")]
+    InvalidInviteTtl,
+    #[error("Too many protocol violations, try again later")]
+    TooManyViolations,
+    #[error(transparent)]
+    Unavailable(#[from] ActorError),
+}
+
+impl IntoResponse for LobbyError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            LobbyError::NotFound | LobbyError::InviteNotFound => StatusCode::NOT_FOUND,
+            LobbyError::NotWhitelisted
+            | LobbyError::NotMember
+            | LobbyError::NotOwner
+            | LobbyError::WrongPassword
+            | LobbyError::TooManyViolations => StatusCode::FORBIDDEN,
+            LobbyError::CannotRemoveOwner
+            | LobbyError::InvalidMetadata(_)
+            | LobbyError::InvalidMemberState(_)
+            | LobbyError::InvalidTeams(_)
+            | LobbyError::InvalidPlayerLimits
+            | LobbyError::InvalidPassword
+            | LobbyError::InvalidQuery(_)
+            | LobbyError::InvalidInvitee => StatusCode::BAD_REQUEST,
+            LobbyError::Full
+            | LobbyError::AlreadyStarted
+            | LobbyError::TeamFull
+            | LobbyError::OwnerCannotSpectate
+            | LobbyError::RoleSwitch
+            | LobbyError::TooManyInvites => StatusCode::CONFLICT,
+            LobbyError::InviteExpired => StatusCode::GONE,
+            LobbyError::TooManyAttempts => StatusCode::TOO_MANY_REQUESTS,
+            LobbyError::InvalidInviteTtl => StatusCode::INTERNAL_SERVER_ERROR,
+            LobbyError::Unavailable(e) => return e.into_response(),
+        };
+        (status, self.to_string()).into_response()
+    }
 }
@@ -0,0 +1,168 @@
+use crate::lobby::{Lobby, LobbyError};
+use base64::{engine::general_purpose, Engine as _};
+use serde::Deserialize;
+use std::cmp::Ordering;
+use uuid::Uuid;
+
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 100;
+
+/// Response header carrying the cursor of the next page of `GET /lobbies`, if any.
+pub const NEXT_CURSOR_HEADER: &str = "x-next-cursor";
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LobbySort {
+    #[default]
+    CreatedAt,
+    PlayerCount,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Query parameters accepted by `GET /lobbies`.
+///
+/// List-valued filters are comma separated: `attributes=map:nebula,region:eu` and
+/// `friends=<pubkey>,<pubkey>`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LobbyQuery {
+    pub game_id: Option<String>,
+    pub game_version: Option<String>,
+    pub mode: Option<String>,
+    pub attributes: Option<String>,
+    pub min_free_slots: Option<usize>,
+    pub friends: Option<String>,
+    #[serde(default)]
+    pub sort: LobbySort,
+    #[serde(default)]
+    pub order: SortOrder,
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LobbyPage {
+    pub lobbies: Vec<Lobby>,
+    pub next_cursor: Option<String>,
+}
+
+impl LobbyQuery {
+    pub fn matches(&self, lobby: &Lobby) -> bool {
+        let metadata = &lobby.metadata;
+        let field_matches =
+            |filter: &Option<String>, value: &Option<String>| filter.is_none() || filter == value;
+        if !field_matches(&self.game_id, &metadata.game_id)
+            || !field_matches(&self.game_version, &metadata.game_version)
+            || !field_matches(&self.mode, &metadata.mode)
+        {
+            return false;
+        }
+
+        if let Some(attributes) = &self.attributes {
+            let all_match = split_list(attributes).all(|pair| match pair.split_once(':') {
+                Some((key, value)) => {
+                    metadata.attributes.get(key).map(String::as_str) == Some(value)
+                }
+                None => metadata.attributes.contains_key(pair),
+            });
+            if !all_match {
+                return false;
+            }
+        }
+
+        if let Some(min_free_slots) = self.min_free_slots {
+            if lobby.free_slots().is_some_and(|free| free < min_free_slots) {
+                return false;
+            }
+        }
+
+        if let Some(friends) = &self.friends {
+            if !split_list(friends).any(|friend| lobby.players.contains(friend)) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Sort the lobbies, skip everything up to and including the cursor and cut a page. Only the
+    /// lobbies of the page are cloned.
+    pub fn paginate<'a>(
+        &self,
+        lobbies: impl IntoIterator<Item = &'a Lobby>,
+    ) -> Result<LobbyPage, LobbyError> {
+        let cursor = self.cursor.as_deref().map(decode_cursor).transpose()?;
+        let limit = self
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+
+        let mut lobbies: Vec<&Lobby> = lobbies
+            .into_iter()
+            .filter(|lobby| {
+                cursor.is_none_or(|cursor| {
+                    self.compare(self.sort_key(lobby), cursor) == Ordering::Greater
+                })
+            })
+            .collect();
+        lobbies.sort_by(|a, b| self.compare(self.sort_key(a), self.sort_key(b)));
+
+        let has_more = lobbies.len() > limit;
+        lobbies.truncate(limit);
+        let next_cursor = if has_more {
+            lobbies
+                .last()
+                .map(|lobby| encode_cursor(self.sort_key(lobby)))
+        } else {
+            None
+        };
+        Ok(LobbyPage {
+            lobbies: lobbies.into_iter().cloned().collect(),
+            next_cursor,
+        })
+    }
+
+    fn sort_key(&self, lobby: &Lobby) -> (i64, Uuid) {
+        let key = match self.sort {
+            LobbySort::CreatedAt => lobby.created_at.timestamp_micros(),
+            LobbySort::PlayerCount => lobby.players.len() as i64,
+        };
+        (key, lobby.id)
+    }
+
+    fn compare(&self, a: (i64, Uuid), b: (i64, Uuid)) -> Ordering {
+        match self.order {
+            SortOrder::Asc => a.cmp(&b),
+            SortOrder::Desc => b.cmp(&a),
+        }
+    }
+}
+
+fn split_list(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+fn encode_cursor((key, id): (i64, Uuid)) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(format!("{key}:{id}"))
+}
+
+fn decode_cursor(cursor: &str) -> Result<(i64, Uuid), LobbyError> {
+    let invalid = || LobbyError::InvalidQuery("invalid cursor".to_string());
+    let bytes = general_purpose::URL_SAFE_NO_PAD
+        .decode(cursor)
+        .map_err(|_| invalid())?;
+    let text = String::from_utf8(bytes).map_err(|_| invalid())?;
+    let (key, id) = text.split_once(':').ok_or_else(invalid)?;
+    Ok((
+        key.parse().map_err(|_| invalid())?,
+        id.parse().map_err(|_| invalid())?,
+    ))
+}
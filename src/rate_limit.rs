@@ -0,0 +1,103 @@
+use crate::lobby::PlayerId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Token bucket holding up to `capacity` tokens, refilled continuously at `refill_per_sec`.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket
+    pub fn new(capacity: u32, refill_per_sec: f64) -> Self {
+        Self {
+            capacity: f64::from(capacity),
+            refill_per_sec,
+            tokens: f64::from(capacity),
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take a token if one is available.
+    pub fn try_take(&mut self) -> bool {
+        self.try_take_n(1)
+    }
+
+    /// Take `n` tokens if that many are available.
+    pub fn try_take_n(&mut self, n: u32) -> bool {
+        self.refill();
+        let n = f64::from(n);
+        if self.tokens >= n {
+            self.tokens -= n;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether the bucket refilled completely, so dropping it loses nothing.
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+    }
+}
+
+/// Protocol violations per player, such as signaling peers of another lobby. Counts survive
+/// reconnects and are forgotten once a player went `ttl` without a new one. A player who reaches
+/// `limit` is refused new signaling sessions until then; a `limit` of 0 never refuses anyone.
+#[derive(Debug, Clone)]
+pub struct ViolationLog {
+    counts: HashMap<PlayerId, (u32, Instant)>,
+    limit: u32,
+    ttl: Duration,
+}
+
+impl ViolationLog {
+    pub fn new(limit: u32, ttl: Duration) -> Self {
+        Self {
+            counts: HashMap::new(),
+            limit,
+            ttl,
+        }
+    }
+
+    /// Count a violation by a player and return their total so far.
+    pub fn record(&mut self, player_id: &str) -> u32 {
+        let now = Instant::now();
+        let (count, last) = self.counts.entry(player_id.to_string()).or_insert((0, now));
+        if now.duration_since(*last) >= self.ttl {
+            *count = 0;
+        }
+        *count += 1;
+        *last = now;
+        *count
+    }
+
+    /// Violations a player made, none once they expired
+    pub fn count(&self, player_id: &str) -> u32 {
+        match self.counts.get(player_id) {
+            Some((count, last)) if last.elapsed() < self.ttl => *count,
+            _ => 0,
+        }
+    }
+
+    pub fn is_banned(&self, player_id: &str) -> bool {
+        self.limit > 0 && self.count(player_id) >= self.limit
+    }
+
+    pub fn cleanup_expired(&mut self) {
+        let ttl = self.ttl;
+        self.counts.retain(|_, (_, last)| last.elapsed() < ttl);
+    }
+}
@@ -0,0 +1,357 @@
+use crate::lobby::PlayerId;
+use axum::http::HeaderMap;
+use axum::{extract::ws::Message, Error};
+use matchbox_protocol::PeerId;
+use matchbox_signaling::{common_logic, SignalingError};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc::UnboundedSender, Notify};
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub id: PeerId,
+    pub sender: UnboundedSender<Result<Message, Error>>,
+    /// Lobby the connection was opened for
+    pub lobby_id: Uuid,
+    /// Wakes the peer's state machine when the server wants to end the connection
+    pub disconnect: Arc<Notify>,
+    pub connected_at: Instant,
+    /// Milliseconds after `connected_at` the peer last sent anything, pongs included
+    pub last_activity: Arc<AtomicU64>,
+    /// The client opted in to the control event envelope, see [`WaitingPlayer::control_events`]
+    pub control_events: bool,
+}
+
+impl Peer {
+    pub fn touch(&self) {
+        let elapsed = self.connected_at.elapsed().as_millis() as u64;
+        self.last_activity.store(elapsed, Ordering::Relaxed);
+    }
+
+    pub fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last_activity.load(Ordering::Relaxed));
+        self.connected_at.elapsed().saturating_sub(last)
+    }
+}
+
+/// Server-issued id of an accepted signaling upgrade.
+///
+/// matchbox only carries the upgrade's `ConnectInfo` address over to the id assignment, so the id
+/// travels as a synthetic address in the discard-only prefix `100::/64`. Clients cannot pick it,
+/// unlike their address, which is shared by everyone behind the same proxy or NAT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionId(u64);
+
+const CONNECTION_ID_PREFIX: u128 = 0x0100 << 112;
+
+/// How long an accepted upgrade waits for its peer id, and an assigned peer id for its state
+/// machine. The upgrade may have failed in between, and its cancellation may have been lost.
+const CONNECTING_TIMEOUT: Duration = Duration::from_secs(60);
+
+impl ConnectionId {
+    pub fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn to_origin(self) -> SocketAddr {
+        let ip = Ipv6Addr::from(CONNECTION_ID_PREFIX | u128::from(self.0));
+        SocketAddr::new(ip.into(), 0)
+    }
+
+    /// The id carried by an origin built with [`ConnectionId::to_origin`]
+    pub fn from_origin(origin: SocketAddr) -> Option<Self> {
+        let IpAddr::V6(ip) = origin.ip() else {
+            return None;
+        };
+        let bits = u128::from(ip);
+        (bits >> 64 == CONNECTION_ID_PREFIX >> 64).then_some(Self(bits as u64))
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Address of the client behind a connection. `X-Forwarded-For` is only honoured from the
+/// `trusted` proxies: walking it from the right, each entry is believed while the hop that added it
+/// is trusted.
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[IpAddr]) -> IpAddr {
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    let mut client = peer;
+    for entry in forwarded.into_iter().rev() {
+        if !trusted.contains(&client) {
+            break;
+        }
+        match entry.trim().parse() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+/// A signaling upgrade that was accepted and is waiting for its peer id
+#[derive(Debug, Clone)]
+pub struct WaitingPlayer {
+    /// Address of the client, see [`client_ip`]
+    pub client: IpAddr,
+    /// `None` when the connection must authenticate with its first message
+    pub player_id: Option<PlayerId>,
+    /// Username from the token, empty until the connection authenticates
+    pub username: String,
+    /// Lobby requested in the connection URL, or the lobby resolved for an authenticated upgrade
+    pub lobby_id: Option<Uuid>,
+    /// The client offered the `matchbox.control` subprotocol or asked for `?control=true`. Only
+    /// then is it sent control events; standard matchbox clients parse every text frame as a
+    /// `PeerEvent`.
+    pub control_events: bool,
+    /// The client can resume a seat held for it under its old peer id. Standard matchbox clients
+    /// cannot: they keep the fresh id matchbox assigned them and only learn about peers through
+    /// `NewPeer`.
+    pub resume: bool,
+}
+
+/// A player whose signaling socket dropped and whose seat is held for the reconnection grace period
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisconnectedPlayer {
+    pub peer_id: PeerId,
+    pub lobby_id: Uuid,
+}
+
+/// Outcome of binding a player to a new connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reservation {
+    /// Nothing was held for the player
+    None,
+    /// The player came back within the grace period and keeps this peer id
+    Resumed(PeerId),
+    /// A seat was held for the player in another lobby, or for a client that cannot resume. Its
+    /// peer id was released and must be announced as left.
+    Released(DisconnectedPlayer),
+}
+
+/// Which lobby each player sits in and which connection they signal with.
+///
+/// Owned by the state core, so each transition (seating a player, binding a connection, ending a
+/// session) is atomic with the lobby changes that go with it.
+#[derive(Debug, Default)]
+pub struct SessionRegistry {
+    /// Lobby each player is seated in
+    lobbies: HashMap<PlayerId, Uuid>,
+    /// Peer id each player signals with
+    peer_ids: HashMap<PlayerId, PeerId>,
+    /// Accepted upgrades waiting for their peer id, and since when
+    waiting: HashMap<ConnectionId, (WaitingPlayer, Instant)>,
+    /// Upgrade each freshly assigned peer id came from and when, until its state machine picks it
+    /// up
+    connecting: HashMap<PeerId, (WaitingPlayer, Instant)>,
+    /// Seats held for players whose socket dropped
+    held_seats: HashMap<PlayerId, DisconnectedPlayer>,
+    /// Live signaling connections
+    peers: HashMap<PeerId, Peer>,
+}
+
+impl SessionRegistry {
+    /// Remember an accepted upgrade until its peer id is assigned. Returns how many are waiting.
+    pub fn wait(&mut self, connection: ConnectionId, waiting: WaitingPlayer) -> usize {
+        self.waiting.insert(connection, (waiting, Instant::now()));
+        self.waiting.len()
+    }
+
+    /// Forget an upgrade that failed before its peer id was assigned.
+    pub fn cancel_wait(&mut self, connection: ConnectionId) {
+        self.waiting.remove(&connection);
+    }
+
+    /// Hand the peer id matchbox assigned to a waiting upgrade. The player is only bound to it once
+    /// its state machine starts the session, see [`SessionRegistry::start_session`]: until then
+    /// their current connection keeps its peer id.
+    pub fn assign_peer_id(
+        &mut self,
+        connection: ConnectionId,
+        peer_id: PeerId,
+    ) -> Option<WaitingPlayer> {
+        let (waiting, _) = self.waiting.remove(&connection)?;
+        self.connecting
+            .insert(peer_id, (waiting.clone(), Instant::now()));
+        Some(waiting)
+    }
+
+    /// Upgrade a freshly assigned peer id came from
+    pub fn take_connecting(&mut self, peer_id: &PeerId) -> Option<WaitingPlayer> {
+        self.connecting.remove(peer_id).map(|(waiting, _)| waiting)
+    }
+
+    /// Forget upgrades that never got their peer id, and assigned peer ids whose upgrade never
+    /// reached its state machine
+    pub fn cleanup_connecting(&mut self) {
+        self.waiting
+            .retain(|_, (_, accepted_at)| accepted_at.elapsed() < CONNECTING_TIMEOUT);
+        self.connecting
+            .retain(|_, (_, assigned_at)| assigned_at.elapsed() < CONNECTING_TIMEOUT);
+    }
+
+    pub fn peer_id_of(&self, player_id: &str) -> Option<PeerId> {
+        self.peer_ids.get(player_id).cloned()
+    }
+
+    pub fn lobby_of(&self, player_id: &str) -> Option<Uuid> {
+        self.lobbies.get(player_id).cloned()
+    }
+
+    /// Bind a player to the peer id of their new connection for `lobby_id`. A seat held for them
+    /// in that lobby is resumed with its old peer id instead, when the connection can `resume`.
+    pub fn start_session(
+        &mut self,
+        player_id: &str,
+        peer_id: PeerId,
+        lobby_id: Uuid,
+        resume: bool,
+    ) -> Reservation {
+        let (peer_id, reservation) = match self.held_seats.remove(player_id) {
+            Some(held) if resume && held.lobby_id == lobby_id => {
+                (held.peer_id, Reservation::Resumed(held.peer_id))
+            }
+            Some(held) => (peer_id, Reservation::Released(held)),
+            None => (peer_id, Reservation::None),
+        };
+        self.peer_ids.insert(player_id.to_string(), peer_id);
+        reservation
+    }
+
+    /// End the session of a connection that left `lobby_id`. Returns false, and changes nothing,
+    /// when the player has since reconnected with another peer id.
+    pub fn end_session(&mut self, player_id: &str, peer_id: PeerId, lobby_id: Uuid) -> bool {
+        if self.peer_ids.get(player_id) != Some(&peer_id) {
+            return false;
+        }
+        self.peer_ids.remove(player_id);
+        if self.lobbies.get(player_id) == Some(&lobby_id) {
+            self.lobbies.remove(player_id);
+        }
+        true
+    }
+
+    /// Seat a player in `lobby_id`. Returns the other lobby they were seated in before.
+    pub fn seat(&mut self, player_id: &str, lobby_id: Uuid) -> Option<Uuid> {
+        self.lobbies
+            .insert(player_id.to_string(), lobby_id)
+            .filter(|previous| *previous != lobby_id)
+    }
+
+    /// Unseat a player if they are still seated in `lobby_id`.
+    pub fn unseat(&mut self, player_id: &str, lobby_id: Uuid) {
+        if self.lobbies.get(player_id) == Some(&lobby_id) {
+            self.lobbies.remove(player_id);
+        }
+    }
+
+    /// Unseat everyone from a lobby that is going away.
+    pub fn unseat_all(&mut self, lobby_id: Uuid) {
+        self.lobbies
+            .retain(|_, player_lobby| *player_lobby != lobby_id);
+    }
+
+    pub fn hold_seat(&mut self, player_id: &str, held: DisconnectedPlayer) {
+        self.held_seats.insert(player_id.to_string(), held);
+    }
+
+    /// Release a held seat once the grace period is over, unless the player already came back.
+    pub fn expire_seat(&mut self, player_id: &str, peer_id: PeerId) -> Option<DisconnectedPlayer> {
+        match self.held_seats.get(player_id) {
+            Some(held) if held.peer_id == peer_id => self.held_seats.remove(player_id),
+            _ => None,
+        }
+    }
+
+    pub fn add_peer(&mut self, peer: Peer) {
+        self.peers.insert(peer.id, peer);
+    }
+
+    pub fn remove_peer(&mut self, peer_id: &PeerId) -> Option<Peer> {
+        self.peers.remove(peer_id)
+    }
+
+    pub fn get_peer(&self, peer_id: &PeerId) -> Option<Peer> {
+        self.peers.get(peer_id).cloned()
+    }
+
+    /// Live connection of a player
+    pub fn player_peer(&self, player_id: &str) -> Option<Peer> {
+        let peer_id = self.peer_ids.get(player_id)?;
+        self.peers.get(peer_id).cloned()
+    }
+
+    pub fn peers(&self) -> Vec<Peer> {
+        self.peers.values().cloned().collect()
+    }
+
+    pub fn try_send(&self, id: PeerId, message: Message) -> Result<(), SignalingError> {
+        match self.peers.get(&id) {
+            Some(peer) => Ok(common_logic::try_send(&peer.sender, message)?),
+            None => Err(SignalingError::UnknownPeer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forwarded_for(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", value.parse().unwrap());
+        headers
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn forwarded_for_from_untrusted_peer_is_ignored() {
+        let headers = forwarded_for("203.0.113.7");
+        assert_eq!(
+            client_ip(ip("198.51.100.1"), &headers, &[ip("10.0.0.1")]),
+            ip("198.51.100.1")
+        );
+    }
+
+    #[test]
+    fn chain_of_trusted_proxies_is_walked_from_the_right() {
+        // client -> 10.0.0.2 -> 10.0.0.1 -> server
+        let headers = forwarded_for("203.0.113.7, 10.0.0.2");
+        let trusted = [ip("10.0.0.1"), ip("10.0.0.2")];
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &headers, &trusted),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn spoofed_leftmost_entry_is_not_believed() {
+        // The client sent its own header, the trusted proxy appended the real address
+        let headers = forwarded_for("192.0.2.66, 203.0.113.7");
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &headers, &[ip("10.0.0.1")]),
+            ip("203.0.113.7")
+        );
+    }
+}
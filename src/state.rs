@@ -1,52 +1,53 @@
-impl ServerState {
-    /// Remove a player from all server state (peers, players_in_lobbies, players_to_peers, and all lobbies)
-    pub fn remove_player(&self, player_id: &str) {
-        // Remove from peers (by PeerId)
-        if let Some(peer_id) = self.players_to_peers.write().unwrap().remove(player_id) {
-            self.peers.lock().unwrap().remove(&peer_id);
-        }
-        // Remove from players_in_lobbies
-        let lobby_id_opt = self.players_in_lobbies.write().unwrap().remove(player_id);
-        // Remove from all lobbies
-        if let Some(lobby_id) = lobby_id_opt {
-            if let Ok(mut lobby_manager) = self.lobby_manager.try_write() {
-                lobby_manager.remove_player_from_lobby(&lobby_id, &player_id.to_string());
-            }
-        } else {
-            // Remove from any lobby where present
-            if let Ok(mut lobby_manager) = self.lobby_manager.try_write() {
-                for lobby in lobby_manager.lobbies.values_mut() {
-                    lobby.players.remove(player_id);
-                }
-            }
-        }
-    }
-}
-use crate::auth::ChallengeManager;
-use crate::lobby::Lobby;
-use axum::{extract::ws::Message, Error};
-use matchbox_protocol::PeerId;
-use matchbox_signaling::{
-    common_logic::{self, StateObj},
-    SignalingError, SignalingState,
+use crate::actor::Actor;
+use crate::args::Args;
+use crate::auth::{is_public_key, AuthSecret, ChallengeManager};
+use crate::chat::{ChatFilterHook, ChatHistory};
+use crate::events::{RosterEntry, ServerEvent};
+use crate::lobby::{
+    Invite, Lobby, LobbyError, LobbyMetadata, LobbySettings, LobbyStatus, MemberStateUpdate,
+    PlayerId, Topology, MAX_CONCURRENT_PASSWORD_HASHES, MAX_PENDING_INVITES,
+    PASSWORD_ATTEMPTS_PER_SEC, PASSWORD_ATTEMPT_BURST,
 };
-use std::{
-    collections::HashMap,
-    net::SocketAddr,
-    sync::{Arc, RwLock},
-};
-use tokio::sync::mpsc::UnboundedSender;
+use crate::rate_limit::{TokenBucket, ViolationLog};
+use crate::session::{Peer, SessionRegistry};
+use axum::extract::ws::{CloseFrame, Message};
+use matchbox_protocol::{JsonPeerEvent, PeerId};
+use matchbox_signaling::{common_logic, SignalingError, SignalingState};
+use std::{collections::HashMap, net::IpAddr, sync::Arc, time::Duration};
+use tokio::sync::Semaphore;
+use tokio::task::JoinError;
 use uuid::Uuid;
 
-#[derive(Debug, Clone)]
-pub struct Peer {
-    pub id: PeerId,
-    pub sender: UnboundedSender<Result<Message, Error>>,
-}
+/// Close code sent to a peer that was removed from its lobby by the server.
+pub const KICKED_CLOSE_CODE: u16 = 4000;
+/// Close code sent to a peer whose player joined another lobby. The client should reconnect to
+/// signal with its new lobby.
+pub const MOVED_CLOSE_CODE: u16 = 4001;
+/// Close code sent to a connection that opened without a token and failed to authenticate with
+/// its first message.
+pub const AUTH_FAILED_CLOSE_CODE: u16 = 4002;
+/// Close code sent to a connection whose player is not a member of the lobby it connected for.
+pub const NOT_IN_LOBBY_CLOSE_CODE: u16 = 4003;
+/// Close code sent to the members of a lobby its owner closed.
+pub const LOBBY_CLOSED_CLOSE_CODE: u16 = 4004;
+/// Close code sent to a peer that kept exceeding the signal size or rate limits after being
+/// warned.
+pub const SIGNAL_LIMITS_CLOSE_CODE: u16 = 4005;
+/// Close code sent to a connection whose player reached the violation limit, see
+/// [`ViolationLog`].
+pub const TOO_MANY_VIOLATIONS_CLOSE_CODE: u16 = 4006;
+/// Close code sent to a connection the server state could not take on, it is overloaded or gone.
+/// This is the standard "Try Again Later" code.
+pub const UNAVAILABLE_CLOSE_CODE: u16 = 1013;
+
+/// State commands that may wait for the state task. Requests beyond it are refused with a 503
+/// rather than queued without bound.
+pub const STATE_QUEUE_LEN: usize = 4096;
 
 #[derive(Default, Debug, Clone)]
 pub struct LobbyManager {
     lobbies: HashMap<Uuid, Lobby>,
+    invites: HashMap<Uuid, Invite>,
 }
 
 impl LobbyManager {
@@ -55,20 +56,27 @@ impl LobbyManager {
     }
 
     /// Create a lobby and add an initial owner/creator into the players set atomically.
-    pub fn create_lobby_with_owner(
-        &mut self,
-        is_private: bool,
-        owner: String,
-        whitelist: Option<Vec<String>>,
-    ) -> Lobby {
+    pub fn create_lobby_with_owner(&mut self, owner: String, settings: LobbySettings) -> Lobby {
         let mut lobby = Lobby {
             id: Uuid::new_v4(),
+            owner: Some(owner.clone()),
             players: Default::default(),
+            spectators: Default::default(),
             status: crate::lobby::LobbyStatus::Waiting,
-            is_private,
-            whitelist: whitelist.map(|w| w.into_iter().collect()),
+            is_private: settings.is_private,
+            whitelist: settings.whitelist.map(|w| w.into_iter().collect()),
+            max_players: settings.max_players,
+            password_hash: settings.password_hash,
+            min_players: settings.min_players,
+            auto_start: settings.auto_start,
+            members: Default::default(),
+            teams: settings.teams,
+            topology: settings.topology,
+            host: None,
+            created_at: chrono::Utc::now(),
+            metadata: settings.metadata,
         };
-        lobby.players.insert(owner);
+        lobby.add_player(owner);
         self.lobbies.insert(lobby.id, lobby.clone());
         lobby
     }
@@ -80,10 +88,22 @@ impl LobbyManager {
     ) -> Lobby {
         let lobby = Lobby {
             id: Uuid::new_v4(),
+            owner: None,
             players: Default::default(),
+            spectators: Default::default(),
             status: crate::lobby::LobbyStatus::Waiting,
             is_private,
             whitelist: whitelist.map(|w| w.into_iter().collect()),
+            max_players: None,
+            password_hash: None,
+            min_players: None,
+            auto_start: false,
+            members: Default::default(),
+            teams: None,
+            topology: Topology::Mesh,
+            host: None,
+            created_at: chrono::Utc::now(),
+            metadata: LobbyMetadata::default(),
         };
         self.lobbies.insert(lobby.id, lobby.clone());
         lobby
@@ -93,95 +113,793 @@ impl LobbyManager {
         self.lobbies.get(id).cloned()
     }
 
-    pub fn get_lobbies_for_player(&self, player_pubkey: Option<String>) -> Vec<Lobby> {
-        self.lobbies
-            .values()
-            .filter(|lobby| {
-                // If lobby is public, always show
-                if !lobby.is_private && lobby.status == crate::lobby::LobbyStatus::Waiting {
+    pub fn lobby(&self, id: &Uuid) -> Option<&Lobby> {
+        self.lobbies.get(id)
+    }
+
+    /// Lobbies a player may browse, everyone's public lobbies included
+    pub fn lobbies_for_player<'a>(
+        &'a self,
+        player_pubkey: Option<&'a str>,
+    ) -> impl Iterator<Item = &'a Lobby> {
+        self.lobbies.values().filter(move |lobby| {
+            // If lobby is public, always show
+            if !lobby.is_private && lobby.status == crate::lobby::LobbyStatus::Waiting {
+                return true;
+            }
+            // If the player is already in the lobby (e.g., the creator), always show it to them
+            if let Some(pk) = player_pubkey {
+                if lobby.is_member(pk) {
                     return true;
                 }
-                // If the player is already in the lobby (e.g., the creator), always show it to them
-                if let Some(ref pk) = player_pubkey {
-                    if lobby.players.contains(pk) {
-                        return true;
-                    }
-                }
-                // If lobby is private and has a whitelist, only show if player is whitelisted
-                if lobby.is_private {
-                    if let Some(whitelist) = &lobby.whitelist {
-                        if let Some(ref pk) = player_pubkey {
-                            return whitelist.contains(pk);
-                        } else {
-                            return false;
-                        }
+            }
+            // If lobby is private and has a whitelist, only show if player is whitelisted
+            if lobby.is_private {
+                if let Some(whitelist) = &lobby.whitelist {
+                    if let Some(pk) = player_pubkey {
+                        return whitelist.contains(pk);
+                    } else {
+                        return false;
                     }
                 }
-                false
-            })
-            .cloned()
-            .collect()
+            }
+            false
+        })
     }
 
     pub fn add_player_to_lobby(
         &mut self,
         lobby_id: &Uuid,
         player_id: String,
-    ) -> Result<(), SignalingError> {
+    ) -> Result<(), LobbyError> {
         if let Some(lobby) = self.lobbies.get_mut(lobby_id) {
             // Check whitelist if it exists
             if let Some(whitelist) = &lobby.whitelist {
                 if !whitelist.contains(&player_id) {
-                    return Err(SignalingError::UnknownPeer); // Using UnknownPeer to indicate "not allowed"
+                    return Err(LobbyError::NotWhitelisted);
                 }
             }
-            lobby.players.insert(player_id);
+            if lobby.spectators.contains(&player_id) {
+                return Err(LobbyError::RoleSwitch);
+            }
+            if !lobby.players.contains(&player_id) {
+                if lobby.status == LobbyStatus::InProgress {
+                    return Err(LobbyError::AlreadyStarted);
+                }
+                if lobby.is_full() {
+                    return Err(LobbyError::Full);
+                }
+            }
+            lobby.add_player(player_id);
             Ok(())
         } else {
             // Log available lobbies for debugging when a lobby is unexpectedly missing
             let ids: Vec<String> = self.lobbies.keys().map(|u| u.to_string()).collect();
             tracing::debug!(?ids, ?lobby_id, "add_player_to_lobby: lobby not found");
-            Err(SignalingError::UnknownPeer)
+            Err(LobbyError::NotFound)
         }
     }
 
-    pub fn remove_player_from_lobby(&mut self, lobby_id: &Uuid, player_id: &String) {
-        if let Some(lobby) = self.lobbies.get_mut(lobby_id) {
-            lobby.players.remove(player_id);
+    /// Add a spectator to a lobby. Spectators don't take a seat and may join a lobby that is
+    /// already in progress; the whitelist still applies. Players of the lobby cannot switch to
+    /// spectating without leaving it first, nor spectators to playing: their connection was
+    /// announced with the role it joined with.
+    pub fn add_spectator_to_lobby(
+        &mut self,
+        lobby_id: &Uuid,
+        player_id: String,
+    ) -> Result<(), LobbyError> {
+        let lobby = self.lobbies.get_mut(lobby_id).ok_or(LobbyError::NotFound)?;
+        if let Some(whitelist) = &lobby.whitelist {
+            if !whitelist.contains(&player_id) {
+                return Err(LobbyError::NotWhitelisted);
+            }
+        }
+        if lobby.is_owner(&player_id) {
+            return Err(LobbyError::OwnerCannotSpectate);
+        }
+        if lobby.players.contains(&player_id) {
+            return Err(LobbyError::RoleSwitch);
+        }
+        lobby.add_spectator(player_id);
+        Ok(())
+    }
+
+    /// Remove a player from a lobby. When the owner leaves, ownership passes to another member;
+    /// the new owner is returned so it can be announced. A lobby left without players is deleted
+    /// along with its invites, one left with only ready players auto-starts.
+    pub fn remove_player_from_lobby(&mut self, lobby_id: &Uuid, player_id: &str) -> Handover {
+        let mut handover = Handover::default();
+        let Some(lobby) = self.lobbies.get_mut(lobby_id) else {
+            return handover;
+        };
+        lobby.remove_player(player_id);
+        if lobby.players.is_empty() {
+            self.invites
+                .retain(|_, invite| invite.lobby_id != *lobby_id);
+            handover.closed = self.lobbies.remove(lobby_id);
+            return handover;
+        }
+        if lobby.is_owner(player_id) {
+            if let Some(new_owner) = lobby.players.iter().min().cloned() {
+                lobby.owner = Some(new_owner.clone());
+                handover.owner = Some(new_owner);
+            }
+        }
+        if lobby.is_host(player_id) {
+            // The owner takes over as host, if they are still playing
+            lobby.host = lobby
+                .owner
+                .clone()
+                .filter(|owner| lobby.players.contains(owner))
+                .or_else(|| lobby.players.iter().min().cloned());
+            handover.host = lobby.host.clone();
+        }
+        handover.started = lobby.try_auto_start();
+        handover
+    }
+
+    /// Update a member's state. Returns the updated lobby and whether the update made it start.
+    pub fn update_member_state(
+        &mut self,
+        lobby_id: &Uuid,
+        player_id: &str,
+        update: MemberStateUpdate,
+    ) -> Result<(Lobby, bool), LobbyError> {
+        update.validate()?;
+        let lobby = self.lobbies.get_mut(lobby_id).ok_or(LobbyError::NotFound)?;
+        if lobby.status == LobbyStatus::InProgress {
+            return Err(LobbyError::AlreadyStarted);
+        }
+        if !lobby.members.contains_key(player_id) {
+            return Err(LobbyError::NotMember);
+        }
+        if let Some(team) = update.team {
+            lobby.validate_team_pick(player_id, team)?;
+        }
+        update.apply(lobby.members.get_mut(player_id).expect("member present"));
+        lobby.sync_teams();
+        let started = lobby.try_auto_start();
+        Ok((lobby.clone(), started))
+    }
+
+    /// Set or clear the rating of a member of a waiting lobby. Owner only, so that players cannot
+    /// rate themselves into a better team.
+    pub fn set_member_rating(
+        &mut self,
+        lobby_id: &Uuid,
+        requester: &str,
+        player_id: &str,
+        rating: Option<i32>,
+    ) -> Result<Lobby, LobbyError> {
+        let lobby = self.owned_lobby_mut(lobby_id, requester)?;
+        if lobby.status == LobbyStatus::InProgress {
+            return Err(LobbyError::AlreadyStarted);
+        }
+        let member = lobby
+            .members
+            .get_mut(player_id)
+            .ok_or(LobbyError::NotMember)?;
+        member.rating = rating;
+        Ok(lobby.clone())
+    }
+
+    /// Mark a lobby as in progress. Only the owner may start it, and only once.
+    pub fn start_lobby(&mut self, lobby_id: &Uuid, requester: &str) -> Result<Lobby, LobbyError> {
+        let lobby = self.owned_lobby_mut(lobby_id, requester)?;
+        if lobby.status == LobbyStatus::InProgress {
+            return Err(LobbyError::AlreadyStarted);
+        }
+        lobby.balance_teams(true);
+        lobby.status = LobbyStatus::InProgress;
+        Ok(lobby.clone())
+    }
+
+    /// Reassign every player of a waiting lobby to a team, ignoring their picks. Owner only.
+    pub fn balance_teams(&mut self, lobby_id: &Uuid, requester: &str) -> Result<Lobby, LobbyError> {
+        let lobby = self.owned_lobby_mut(lobby_id, requester)?;
+        if lobby.teams.is_none() {
+            return Err(LobbyError::InvalidTeams(
+                "this lobby has no teams".to_string(),
+            ));
+        }
+        if lobby.status == LobbyStatus::InProgress {
+            return Err(LobbyError::AlreadyStarted);
+        }
+        lobby.balance_teams(false);
+        Ok(lobby.clone())
+    }
+
+    /// Delete a lobby and its pending invites. Only the owner may close it. Returns the closed
+    /// lobby so its members can be notified.
+    pub fn close_lobby(&mut self, lobby_id: &Uuid, requester: &str) -> Result<Lobby, LobbyError> {
+        self.owned_lobby_mut(lobby_id, requester)?;
+        self.invites
+            .retain(|_, invite| invite.lobby_id != *lobby_id);
+        Ok(self.lobbies.remove(lobby_id).expect("lobby present"))
+    }
+
+    /// Invite a player to a lobby for `ttl`. Only the lobby owner may invite; a newer invite for
+    /// the same player replaces any pending one. A lobby has at most [`MAX_PENDING_INVITES`]
+    /// invites pending.
+    pub fn create_invite(
+        &mut self,
+        lobby_id: &Uuid,
+        inviter: &str,
+        invitee: String,
+        ttl: Duration,
+    ) -> Result<Invite, LobbyError> {
+        self.owned_lobby_mut(lobby_id, inviter)?;
+        if !is_public_key(&invitee) {
+            return Err(LobbyError::InvalidInvitee);
+        }
+        let invite = Invite::new(*lobby_id, inviter.to_string(), invitee, ttl)?;
+        self.invites.retain(|_, pending| {
+            pending.lobby_id != *lobby_id
+                || (pending.invitee != invite.invitee && !pending.is_expired())
+        });
+        let pending = self
+            .invites
+            .values()
+            .filter(|pending| pending.lobby_id == *lobby_id)
+            .count();
+        if pending >= MAX_PENDING_INVITES {
+            return Err(LobbyError::TooManyInvites);
+        }
+        self.invites.insert(invite.id, invite.clone());
+        Ok(invite)
+    }
+
+    /// Add entries to a lobby's whitelist. A lobby without a whitelist gets one seeded with its
+    /// current players, so nobody already inside is locked out.
+    pub fn add_to_whitelist(
+        &mut self,
+        lobby_id: &Uuid,
+        requester: &str,
+        pubkeys: Vec<String>,
+    ) -> Result<Lobby, LobbyError> {
+        let lobby = self.owned_lobby_mut(lobby_id, requester)?;
+        let players = lobby.players.clone();
+        lobby.whitelist.get_or_insert(players).extend(pubkeys);
+        Ok(lobby.clone())
+    }
+
+    /// Remove entries from a lobby's whitelist and drop any players who are no longer allowed in.
+    /// Returns the updated lobby, the players that were kicked and whether the lobby auto-started
+    /// without them.
+    pub fn remove_from_whitelist(
+        &mut self,
+        lobby_id: &Uuid,
+        requester: &str,
+        pubkeys: Vec<String>,
+    ) -> Result<(Lobby, Vec<PlayerId>, bool), LobbyError> {
+        let lobby = self.owned_lobby_mut(lobby_id, requester)?;
+        if pubkeys.iter().any(|pk| lobby.is_owner(pk)) {
+            return Err(LobbyError::CannotRemoveOwner);
+        }
+        let mut kicked = Vec::new();
+        if lobby.whitelist.is_some() {
+            for pubkey in pubkeys {
+                if let Some(whitelist) = lobby.whitelist.as_mut() {
+                    whitelist.remove(&pubkey);
+                }
+                if lobby.remove_player(&pubkey) {
+                    kicked.push(pubkey);
+                }
+            }
+        }
+        let started = !kicked.is_empty() && lobby.try_auto_start();
+        Ok((lobby.clone(), kicked, started))
+    }
+
+    pub fn set_lobby_privacy(
+        &mut self,
+        lobby_id: &Uuid,
+        requester: &str,
+        is_private: bool,
+    ) -> Result<Lobby, LobbyError> {
+        let lobby = self.owned_lobby_mut(lobby_id, requester)?;
+        lobby.is_private = is_private;
+        Ok(lobby.clone())
+    }
+
+    pub fn update_lobby_metadata(
+        &mut self,
+        lobby_id: &Uuid,
+        requester: &str,
+        metadata: LobbyMetadata,
+    ) -> Result<Lobby, LobbyError> {
+        metadata.validate()?;
+        let lobby = self.owned_lobby_mut(lobby_id, requester)?;
+        lobby.metadata = metadata;
+        Ok(lobby.clone())
+    }
+
+    /// Pending, non-expired invites addressed to a player.
+    pub fn get_invites_for_player(&self, player_id: &str) -> Vec<Invite> {
+        self.invites
+            .values()
+            .filter(|invite| invite.invitee == player_id && !invite.is_expired())
+            .cloned()
+            .collect()
+    }
+
+    /// Consume an invite: whitelist the invitee (if the lobby has a whitelist) and add them to
+    /// the lobby in one step.
+    pub fn accept_invite(
+        &mut self,
+        invite_id: &Uuid,
+        player_id: &str,
+    ) -> Result<Lobby, LobbyError> {
+        let invite = match self.invites.get(invite_id) {
+            Some(invite) if invite.invitee == player_id => invite,
+            _ => return Err(LobbyError::InviteNotFound),
+        };
+        if invite.is_expired() {
+            self.invites.remove(invite_id);
+            return Err(LobbyError::InviteExpired);
+        }
+        let lobby = self
+            .lobbies
+            .get_mut(&invite.lobby_id)
+            .ok_or(LobbyError::NotFound)?;
+        // The invite is only consumed once the join succeeds, so it can be retried.
+        if lobby.spectators.contains(&invite.invitee) {
+            return Err(LobbyError::RoleSwitch);
+        }
+        if !lobby.players.contains(&invite.invitee) {
+            if lobby.status == LobbyStatus::InProgress {
+                return Err(LobbyError::AlreadyStarted);
+            }
+            if lobby.is_full() {
+                return Err(LobbyError::Full);
+            }
+        }
+        let invite = self.invites.remove(invite_id).expect("invite present");
+        let lobby = self
+            .lobbies
+            .get_mut(&invite.lobby_id)
+            .expect("lobby present");
+        if let Some(whitelist) = lobby.whitelist.as_mut() {
+            whitelist.insert(invite.invitee.clone());
+        }
+        lobby.add_player(invite.invitee);
+        Ok(lobby.clone())
+    }
+
+    pub fn decline_invite(&mut self, invite_id: &Uuid, player_id: &str) -> Result<(), LobbyError> {
+        self.take_invite(invite_id, player_id).map(|_| ())
+    }
+
+    /// Remove expired invites and invites pointing at lobbies that no longer exist
+    pub fn cleanup_expired_invites(&mut self) {
+        let lobbies = &self.lobbies;
+        self.invites
+            .retain(|_, invite| !invite.is_expired() && lobbies.contains_key(&invite.lobby_id));
+    }
+
+    fn owned_lobby_mut(
+        &mut self,
+        lobby_id: &Uuid,
+        requester: &str,
+    ) -> Result<&mut Lobby, LobbyError> {
+        let lobby = self.lobbies.get_mut(lobby_id).ok_or(LobbyError::NotFound)?;
+        if !lobby.is_owner(requester) {
+            return Err(LobbyError::NotOwner);
+        }
+        Ok(lobby)
+    }
+
+    fn take_invite(&mut self, invite_id: &Uuid, player_id: &str) -> Result<Invite, LobbyError> {
+        match self.invites.get(invite_id) {
+            Some(invite) if invite.invitee == player_id => {
+                Ok(self.invites.remove(invite_id).expect("invite present"))
+            }
+            _ => Err(LobbyError::InviteNotFound),
         }
     }
 }
 
-#[derive(Default, Debug, Clone)]
+/// Roles a player held in a lobby that passed to another member when they left
+#[derive(Debug, Clone, Default)]
+pub struct Handover {
+    pub owner: Option<PlayerId>,
+    /// New host of a star lobby
+    pub host: Option<PlayerId>,
+    /// The lobby itself, deleted because its last player left
+    pub closed: Option<Lobby>,
+    /// The lobby auto-started, the player was the last one not ready
+    pub started: bool,
+}
+
+/// The lobby a player was moved out of
+#[derive(Debug, Clone)]
+pub struct LeftLobby {
+    pub lobby_id: Uuid,
+    pub handover: Handover,
+}
+
+/// Shared, read-only configuration and the handle to the state core. Cheap to clone.
+#[derive(Debug, Clone)]
 pub struct ServerState {
-    pub lobby_manager: Arc<RwLock<LobbyManager>>,
-    pub peers: StateObj<HashMap<PeerId, Peer>>,
-    pub players_in_lobbies: Arc<RwLock<HashMap<String, Uuid>>>,
-    pub challenge_manager: ChallengeManager,
-    pub players_to_peers: Arc<RwLock<HashMap<String, PeerId>>>,
-    pub waiting_players: Arc<RwLock<HashMap<SocketAddr, String>>>,
+    pub config: Arc<Args>,
+    pub secret: AuthSecret,
+    pub chat_filter: ChatFilterHook,
+    /// Every piece of mutable server state, owned by a single task
+    pub core: Actor<Core>,
+    /// Permits for argon2 work, see [`ServerState::run_argon2`]
+    pub password_hashing: Arc<Semaphore>,
 }
 
 impl SignalingState for ServerState {}
 
 impl ServerState {
-    pub fn add_peer(&mut self, peer: Peer) {
-        self.peers.lock().unwrap().insert(peer.id, peer);
+    /// Spawn the state core. Must be called within a Tokio runtime.
+    pub fn new(config: Args, secret: AuthSecret, chat_filter: ChatFilterHook) -> Self {
+        let core = Actor::spawn(Core::new(&config), STATE_QUEUE_LEN);
+        Self {
+            config: Arc::new(config),
+            secret,
+            chat_filter,
+            core,
+            password_hashing: Arc::new(Semaphore::new(MAX_CONCURRENT_PASSWORD_HASHES)),
+        }
+    }
+
+    /// Run an argon2 hash or verification on the blocking pool, keeping it off the runtime's
+    /// worker threads. At most [`MAX_CONCURRENT_PASSWORD_HASHES`] run at once, the others wait.
+    pub async fn run_argon2<T: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<T, JoinError> {
+        let _permit = self
+            .password_hashing
+            .acquire()
+            .await
+            .expect("the semaphore is never closed");
+        tokio::task::spawn_blocking(job).await
+    }
+
+    /// Send a control event to every connected member of a lobby except `except_player`.
+    pub fn broadcast_event(&self, lobby_id: Uuid, except_player: Option<&str>, event: ServerEvent) {
+        let except_player = except_player.map(str::to_string);
+        self.core
+            .cast(move |core| core.broadcast_event(&lobby_id, except_player.as_deref(), &event));
     }
 
-    pub fn remove_peer(&mut self, peer_id: &PeerId) -> Option<Peer> {
-        self.peers.lock().unwrap().remove(peer_id)
+    pub fn send_event(&self, peer_id: PeerId, event: ServerEvent) {
+        self.core.cast(move |core| core.send_event(peer_id, &event));
+    }
+}
+
+/// Mutable server state. It lives in a single task, see [`ServerState::core`], so none of it is
+/// behind a lock.
+#[derive(Debug)]
+pub struct Core {
+    pub lobbies: LobbyManager,
+    pub sessions: SessionRegistry,
+    pub challenges: ChallengeManager,
+    /// Username from the token of each player's session, forgotten once the session ends
+    pub usernames: HashMap<PlayerId, String>,
+    pub chat_history: ChatHistory,
+    pub violations: ViolationLog,
+    /// Lobby password attempts left per lobby and client address. Not per player: public keys
+    /// cost nothing to make.
+    pub password_attempts: HashMap<(Uuid, IpAddr), TokenBucket>,
+}
+
+/// An empty state with the default violation limits
+impl Default for Core {
+    fn default() -> Self {
+        Self::new(&Args::default())
+    }
+}
+
+impl Core {
+    pub fn new(config: &Args) -> Self {
+        Self {
+            lobbies: LobbyManager::default(),
+            sessions: SessionRegistry::default(),
+            challenges: ChallengeManager::default(),
+            usernames: HashMap::new(),
+            chat_history: ChatHistory::default(),
+            violations: ViolationLog::new(config.max_violations, config.violation_ttl()),
+            password_attempts: HashMap::new(),
+        }
     }
 
     pub fn get_peer(&self, peer_id: &PeerId) -> Option<Peer> {
-        self.peers.lock().unwrap().get(peer_id).cloned()
+        self.sessions.get_peer(peer_id)
+    }
+
+    /// Lobby a connected peer belongs to
+    pub fn peer_lobby(&self, peer_id: &PeerId) -> Option<Uuid> {
+        self.sessions.get_peer(peer_id).map(|peer| peer.lobby_id)
+    }
+
+    /// Count a protocol violation by a player and return their total so far.
+    pub fn record_violation(&mut self, player_id: &str) -> u32 {
+        self.violations.record(player_id)
+    }
+
+    /// Spend one of the attempts a client has left at a lobby's password, if any.
+    pub fn try_password_attempt(&mut self, lobby_id: Uuid, client: IpAddr) -> bool {
+        self.password_attempts
+            .entry((lobby_id, client))
+            .or_insert_with(|| TokenBucket::new(PASSWORD_ATTEMPT_BURST, PASSWORD_ATTEMPTS_PER_SEC))
+            .try_take()
+    }
+
+    /// Forget the clients whose password attempts refilled completely
+    pub fn cleanup_password_attempts(&mut self) {
+        self.password_attempts
+            .retain(|_, attempts| !attempts.is_full());
     }
 
     pub fn try_send(&self, id: PeerId, message: Message) -> Result<(), SignalingError> {
-        let clients = self.peers.lock().unwrap();
-        match clients.get(&id) {
-            Some(peer) => Ok(common_logic::try_send(&peer.sender, message)?),
-            None => Err(SignalingError::UnknownPeer),
+        self.sessions.try_send(id, message)
+    }
+
+    /// Send a message to a peer, logging failures.
+    pub fn send(&self, peer_id: PeerId, message: Message) {
+        if let Err(e) = self.try_send(peer_id, message) {
+            tracing::error!("error sending to {peer_id:?}: {e:?}");
+        }
+    }
+
+    /// Record that a player now belongs to `lobby_id`, removing them from the lobby they were in
+    /// before so they never hold two seats. Returns the lobby that was left, if any.
+    pub fn assign_player_lobby(&mut self, player_id: &str, lobby_id: Uuid) -> Option<LeftLobby> {
+        let previous = self.sessions.seat(player_id, lobby_id)?;
+        Some(LeftLobby {
+            lobby_id: previous,
+            handover: self.lobbies.remove_player_from_lobby(&previous, player_id),
+        })
+    }
+
+    pub fn record_username(&mut self, player_id: &str, username: &str) {
+        self.usernames
+            .insert(player_id.to_string(), username.to_string());
+    }
+
+    pub fn roster_entry(&self, player_id: &str, peer_id: PeerId) -> RosterEntry {
+        let username = self.usernames.get(player_id).cloned().unwrap_or_default();
+        RosterEntry {
+            peer_id,
+            pubkey: player_id.to_string(),
+            username,
+            spectator: false,
+        }
+    }
+
+    /// Identities of the lobby members that have a peer id, players then spectators, each sorted
+    /// by public key.
+    pub fn lobby_roster(&self, lobby_id: &Uuid) -> Vec<RosterEntry> {
+        let Some(lobby) = self.lobbies.get_lobby(lobby_id) else {
+            return Vec::new();
+        };
+        let mut players: Vec<_> = lobby.players.into_iter().collect();
+        let mut spectators: Vec<_> = lobby.spectators.into_iter().collect();
+        players.sort();
+        spectators.sort();
+        let players = players.into_iter().map(|player_id| (player_id, false));
+        let spectators = spectators.into_iter().map(|player_id| (player_id, true));
+        players
+            .chain(spectators)
+            .filter_map(|(player_id, spectator)| {
+                let peer_id = self.sessions.peer_id_of(&player_id)?;
+                Some(RosterEntry {
+                    spectator,
+                    ..self.roster_entry(&player_id, peer_id)
+                })
+            })
+            .collect()
+    }
+
+    /// Send a message to a player's signaling socket, if connected.
+    pub fn send_to_player(&self, player_id: &str, message: Message) {
+        if let Some(peer) = self.sessions.player_peer(player_id) {
+            if let Err(e) = common_logic::try_send(&peer.sender, message) {
+                tracing::error!("error sending to {:?}: {e:?}", peer.id);
+            }
+        }
+    }
+
+    /// Send a message to every connected member of a lobby except `except_player`.
+    pub fn broadcast_to_lobby(
+        &self,
+        lobby_id: &Uuid,
+        except_player: Option<&str>,
+        message: Message,
+    ) {
+        let Some(lobby) = self.lobbies.lobby(lobby_id) else {
+            return;
+        };
+        for player_id in lobby.everyone() {
+            if Some(player_id.as_str()) != except_player {
+                self.send_to_player(player_id, message.clone());
+            }
+        }
+    }
+
+    /// Send a control event to a peer. Only peers that opted in to control events get them.
+    pub fn send_event(&self, peer_id: PeerId, event: &ServerEvent) {
+        if self
+            .get_peer(&peer_id)
+            .is_some_and(|peer| peer.control_events)
+        {
+            self.send(peer_id, event.to_message());
+        }
+    }
+
+    /// Send a control event to a player's signaling socket, if connected and opted in.
+    pub fn send_event_to_player(&self, player_id: &str, event: &ServerEvent) {
+        self.send_control(player_id, event.to_message());
+    }
+
+    /// Send a control event to every member of a lobby except `except_player`, see
+    /// [`Core::send_event`].
+    pub fn broadcast_event(
+        &self,
+        lobby_id: &Uuid,
+        except_player: Option<&str>,
+        event: &ServerEvent,
+    ) {
+        let Some(lobby) = self.lobbies.lobby(lobby_id) else {
+            return;
+        };
+        let message = event.to_message();
+        for player_id in lobby.everyone() {
+            if Some(player_id.as_str()) != except_player {
+                self.send_control(player_id, message.clone());
+            }
+        }
+    }
+
+    fn send_control(&self, player_id: &str, message: Message) {
+        if let Some(peer) = self.sessions.player_peer(player_id) {
+            if !peer.control_events {
+                return;
+            }
+            if let Err(e) = common_logic::try_send(&peer.sender, message) {
+                tracing::error!("error sending to {:?}: {e:?}", peer.id);
+            }
+        }
+    }
+
+    /// Drop what is kept alongside a deleted lobby and disconnect whoever was still in it.
+    pub fn lobby_closed(&mut self, lobby: &Lobby) {
+        self.sessions.unseat_all(lobby.id);
+        self.chat_history.remove(&lobby.id);
+        let event = ServerEvent::LobbyClosed { lobby_id: lobby.id };
+        for player_id in lobby.everyone() {
+            self.send_event_to_player(player_id, &event);
+            self.disconnect_player(player_id, LOBBY_CLOSED_CLOSE_CODE, "Lobby closed");
+        }
+    }
+
+    /// Announce the roles a departing player handed over. A new star host is sent `NewPeer` for
+    /// every connected member, since the host opens the connections. A lobby the player left
+    /// empty is closed instead.
+    pub fn announce_handover(&mut self, lobby_id: Uuid, handover: Handover) {
+        if let Some(lobby) = handover.closed {
+            tracing::info!(lobby_id = %lobby_id, "Lobby closed, its last player left");
+            self.lobby_closed(&lobby);
+            return;
         }
+        if let Some(owner) = handover.owner {
+            tracing::info!(lobby_id = %lobby_id, owner = %&owner[..8], "Lobby ownership passed on");
+            let event = ServerEvent::OwnerChanged { lobby_id, owner };
+            self.broadcast_event(&lobby_id, None, &event);
+        }
+        if let Some(host) = handover.host {
+            tracing::info!(lobby_id = %lobby_id, host = %&host[..8], "Lobby host migrated");
+            let members = self.lobby_roster(&lobby_id);
+            let event = ServerEvent::HostChanged {
+                lobby_id,
+                host: host.clone(),
+            };
+            self.broadcast_event(&lobby_id, None, &event);
+            let host_peer = members
+                .iter()
+                .find(|member| member.pubkey == host)
+                .map(|member| member.peer_id)
+                .filter(|peer_id| self.get_peer(peer_id).is_some());
+            // The host opens the connections, as soon as it is connected
+            if let Some(host_peer) = host_peer {
+                for member in &members {
+                    if member.peer_id != host_peer && self.get_peer(&member.peer_id).is_some() {
+                        let event = JsonPeerEvent::NewPeer(member.peer_id).to_string();
+                        self.send(host_peer, Message::Text(event));
+                    }
+                }
+            }
+        }
+        if handover.started {
+            self.announce_auto_start(&lobby_id);
+        }
+    }
+
+    /// Tell the members of a lobby that it auto-started.
+    pub fn announce_auto_start(&self, lobby_id: &Uuid) {
+        let Some(lobby) = self.lobbies.lobby(lobby_id) else {
+            return;
+        };
+        tracing::info!(lobby_id = %lobby_id, "Every player is ready, lobby auto-started");
+        self.broadcast_event(lobby_id, None, &ServerEvent::lobby_started(lobby));
+    }
+
+    /// Tell every connected peer that the server is going away and close their sockets.
+    pub fn notify_shutdown(&self) {
+        let event = ServerEvent::ServerShutdown.to_message();
+        let frame = CloseFrame {
+            code: axum::extract::ws::close_code::AWAY,
+            reason: "Server shutting down".into(),
+        };
+        for peer in self.sessions.peers() {
+            if peer.control_events {
+                let _ = common_logic::try_send(&peer.sender, event.clone());
+            }
+            let _ = common_logic::try_send(&peer.sender, Message::Close(Some(frame.clone())));
+        }
+    }
+
+    /// Resolve the lobby a signaling connection is for: the lobby requested in the connection URL,
+    /// or else the player's current lobby. The player must be a member of it, and must not have
+    /// reached the violation limit.
+    pub fn connection_lobby(
+        &self,
+        player_id: &str,
+        requested: Option<Uuid>,
+    ) -> Result<Uuid, LobbyError> {
+        if self.violations.is_banned(player_id) {
+            return Err(LobbyError::TooManyViolations);
+        }
+        let lobby_id = match requested {
+            Some(lobby_id) => lobby_id,
+            None => self
+                .sessions
+                .lobby_of(player_id)
+                .ok_or(LobbyError::NotMember)?,
+        };
+        let lobby = self.lobbies.lobby(&lobby_id).ok_or(LobbyError::NotFound)?;
+        if !lobby.is_member(player_id) {
+            return Err(LobbyError::NotMember);
+        }
+        Ok(lobby_id)
+    }
+
+    /// Close the signaling socket of a player, if connected. The topology then runs its regular
+    /// disconnect cleanup and announces `PeerLeft` to the rest of the lobby.
+    pub fn disconnect_player(&self, player_id: &str, code: u16, reason: &'static str) {
+        if let Some(peer) = self.sessions.player_peer(player_id) {
+            let frame = CloseFrame {
+                code,
+                reason: reason.into(),
+            };
+            if let Err(e) = common_logic::try_send(&peer.sender, Message::Close(Some(frame))) {
+                tracing::warn!(peer_id = ?peer.id, error = ?e, "Failed to notify disconnected peer");
+            }
+            // Don't wait for the client to answer the close handshake
+            peer.disconnect.notify_one();
+        }
+    }
+
+    /// End the session of a peer that left `lobby_id` and announce `PeerLeft` to the remaining
+    /// members. If the player has since moved to another lobby or reconnected with another peer,
+    /// their seat is left alone.
+    pub fn leave_lobby(&mut self, player_id: &str, peer_id: PeerId, lobby_id: Uuid) {
+        if self.sessions.end_session(player_id, peer_id, lobby_id) {
+            self.usernames.remove(player_id);
+            self.release_seat(player_id, lobby_id);
+        }
+        let event = Message::Text(JsonPeerEvent::PeerLeft(peer_id).to_string());
+        self.broadcast_to_lobby(&lobby_id, Some(player_id), event);
+    }
+
+    /// Give up a player's place in a lobby and announce who took over their roles.
+    fn release_seat(&mut self, player_id: &str, lobby_id: Uuid) {
+        let handover = self.lobbies.remove_player_from_lobby(&lobby_id, player_id);
+        self.announce_handover(lobby_id, handover);
     }
 }
@@ -0,0 +1,363 @@
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use thiserror::Error;
+use tokio::net::UdpSocket;
+use tracing::{debug, warn};
+
+pub const MAGIC_COOKIE: u32 = 0x2112_A442;
+pub const HEADER_LEN: usize = 20;
+
+pub const METHOD_BINDING: u16 = 0x001;
+
+pub const ATTR_USERNAME: u16 = 0x0006;
+pub const ATTR_MESSAGE_INTEGRITY: u16 = 0x0008;
+pub const ATTR_ERROR_CODE: u16 = 0x0009;
+pub const ATTR_UNKNOWN_ATTRIBUTES: u16 = 0x000A;
+pub const ATTR_REALM: u16 = 0x0014;
+pub const ATTR_NONCE: u16 = 0x0015;
+pub const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+pub const ATTR_SOFTWARE: u16 = 0x8022;
+
+const SOFTWARE: &str = concat!("matchbox_server ", env!("CARGO_PKG_VERSION"));
+const MESSAGE_INTEGRITY_LEN: usize = 20;
+
+/// Class of a STUN message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Request,
+    Indication,
+    Success,
+    Error,
+}
+
+impl Class {
+    fn bits(self) -> u16 {
+        match self {
+            Class::Request => 0b00,
+            Class::Indication => 0b01,
+            Class::Success => 0b10,
+            Class::Error => 0b11,
+        }
+    }
+}
+
+/// STUN message type: the method's bits interleaved with the class' two bits
+pub fn message_type(method: u16, class: Class) -> u16 {
+    let class = class.bits();
+    (method & 0x000F)
+        | ((method & 0x0070) << 1)
+        | ((method & 0x0F80) << 2)
+        | ((class & 0b01) << 4)
+        | ((class & 0b10) << 7)
+}
+
+fn split_message_type(message_type: u16) -> (u16, Class) {
+    let method =
+        (message_type & 0x000F) | ((message_type >> 1) & 0x0070) | ((message_type >> 2) & 0x0F80);
+    let class = match ((message_type >> 4) & 0b01) | ((message_type >> 7) & 0b10) {
+        0b00 => Class::Request,
+        0b01 => Class::Indication,
+        0b10 => Class::Success,
+        _ => Class::Error,
+    };
+    (method, class)
+}
+
+/// A decoded STUN message, borrowing its attribute values from the packet
+#[derive(Debug)]
+pub struct Message<'a> {
+    pub method: u16,
+    pub class: Class,
+    pub transaction_id: [u8; 12],
+    /// Type, offset in the packet and value of each attribute, in order
+    attributes: Vec<(u16, usize, &'a [u8])>,
+    packet: &'a [u8],
+}
+
+impl<'a> Message<'a> {
+    /// Decode a STUN message. Anything that is not well formed STUN is `None`.
+    pub fn parse(packet: &'a [u8]) -> Option<Self> {
+        if packet.len() < HEADER_LEN || packet[0] & 0xC0 != 0 {
+            return None;
+        }
+        let length = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+        let cookie = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+        if cookie != MAGIC_COOKIE || length % 4 != 0 || packet.len() != HEADER_LEN + length {
+            return None;
+        }
+        let (method, class) = split_message_type(u16::from_be_bytes([packet[0], packet[1]]));
+        let mut attributes = Vec::new();
+        let mut offset = HEADER_LEN;
+        while offset < packet.len() {
+            let rest = &packet[offset..];
+            if rest.len() < 4 {
+                return None;
+            }
+            let kind = u16::from_be_bytes([rest[0], rest[1]]);
+            let value_len = usize::from(u16::from_be_bytes([rest[2], rest[3]]));
+            let padded = 4 + value_len.div_ceil(4) * 4;
+            if rest.len() < padded {
+                return None;
+            }
+            attributes.push((kind, offset, &rest[4..4 + value_len]));
+            offset += padded;
+        }
+        Some(Self {
+            method,
+            class,
+            transaction_id: packet[8..HEADER_LEN].try_into().ok()?,
+            attributes,
+            packet,
+        })
+    }
+
+    /// Value of the first attribute of the given type
+    pub fn attribute(&self, kind: u16) -> Option<&'a [u8]> {
+        self.attributes
+            .iter()
+            .find(|(k, _, _)| *k == kind)
+            .map(|(_, _, value)| *value)
+    }
+
+    /// Values of every attribute of the given type
+    pub fn attributes(&self, kind: u16) -> impl Iterator<Item = &'a [u8]> + '_ {
+        self.attributes
+            .iter()
+            .filter(move |(k, _, _)| *k == kind)
+            .map(|(_, _, value)| *value)
+    }
+
+    /// Comprehension-required attributes (below 0x8000) not in `known`
+    pub fn unknown_attributes(&self, known: &[u16]) -> Vec<u16> {
+        self.attributes
+            .iter()
+            .map(|(kind, _, _)| *kind)
+            .filter(|kind| *kind < 0x8000 && !known.contains(kind))
+            .collect()
+    }
+
+    pub fn text(&self, kind: u16) -> Option<&'a str> {
+        std::str::from_utf8(self.attribute(kind)?).ok()
+    }
+
+    /// An `XOR-*-ADDRESS` attribute
+    pub fn xor_address(&self, kind: u16) -> Option<SocketAddr> {
+        decode_xor_address(self.attribute(kind)?, &self.transaction_id)
+    }
+
+    /// Whether the message carries a `MESSAGE-INTEGRITY` attribute
+    pub fn has_integrity(&self) -> bool {
+        self.attribute(ATTR_MESSAGE_INTEGRITY).is_some()
+    }
+
+    /// Check `MESSAGE-INTEGRITY`: the HMAC-SHA1 under `key` of the message up to the attribute,
+    /// with the header length counting up to its end.
+    pub fn check_integrity(&self, key: &[u8]) -> bool {
+        let Some((_, offset, value)) = self
+            .attributes
+            .iter()
+            .find(|(kind, _, _)| *kind == ATTR_MESSAGE_INTEGRITY)
+        else {
+            return false;
+        };
+        let length = (offset + 4 + MESSAGE_INTEGRITY_LEN - HEADER_LEN) as u16;
+        let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes keys of any size");
+        mac.update(&self.packet[..2]);
+        mac.update(&length.to_be_bytes());
+        mac.update(&self.packet[4..*offset]);
+        mac.verify_slice(value).is_ok()
+    }
+}
+
+/// A STUN message or attribute longer than its 16-bit length field can describe
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("STUN message too long")]
+pub struct MessageTooLong;
+
+/// A STUN message being encoded
+#[derive(Debug)]
+pub struct MessageBuilder {
+    message_type: u16,
+    transaction_id: [u8; 12],
+    attributes: Vec<u8>,
+    /// An attribute value was too long to encode, finishing the message fails
+    too_long: bool,
+}
+
+impl MessageBuilder {
+    pub fn new(method: u16, class: Class, transaction_id: [u8; 12]) -> Self {
+        Self {
+            message_type: message_type(method, class),
+            transaction_id,
+            attributes: Vec::new(),
+            too_long: false,
+        }
+    }
+
+    /// An error response with the given code and reason phrase
+    pub fn error(method: u16, transaction_id: [u8; 12], code: u16, reason: &str) -> Self {
+        let mut message = Self::new(method, Class::Error, transaction_id);
+        let mut value = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+        value.extend_from_slice(reason.as_bytes());
+        message.attribute(ATTR_ERROR_CODE, &value);
+        message
+    }
+
+    /// Append an attribute. A value too long for an attribute makes finishing the message fail
+    /// with [`MessageTooLong`].
+    pub fn attribute(&mut self, kind: u16, value: &[u8]) -> &mut Self {
+        let Ok(len) = u16::try_from(value.len()) else {
+            self.too_long = true;
+            return self;
+        };
+        self.attributes.extend_from_slice(&kind.to_be_bytes());
+        self.attributes.extend_from_slice(&len.to_be_bytes());
+        self.attributes.extend_from_slice(value);
+        let padding = (4 - value.len() % 4) % 4;
+        self.attributes.resize(self.attributes.len() + padding, 0);
+        self
+    }
+
+    pub fn u32_attribute(&mut self, kind: u16, value: u32) -> &mut Self {
+        self.attribute(kind, &value.to_be_bytes())
+    }
+
+    /// An `XOR-*-ADDRESS` attribute
+    pub fn xor_address(&mut self, kind: u16, address: SocketAddr) -> &mut Self {
+        let value = encode_xor_address(address, &self.transaction_id);
+        self.attribute(kind, &value)
+    }
+
+    pub fn software(&mut self) -> &mut Self {
+        self.attribute(ATTR_SOFTWARE, SOFTWARE.as_bytes())
+    }
+
+    fn header(&self, length: usize) -> Result<Vec<u8>, MessageTooLong> {
+        if self.too_long {
+            return Err(MessageTooLong);
+        }
+        let length = u16::try_from(length).map_err(|_| MessageTooLong)?;
+        let mut header = Vec::with_capacity(HEADER_LEN + usize::from(length));
+        header.extend_from_slice(&self.message_type.to_be_bytes());
+        header.extend_from_slice(&length.to_be_bytes());
+        header.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        header.extend_from_slice(&self.transaction_id);
+        Ok(header)
+    }
+
+    pub fn finish(&self) -> Result<Vec<u8>, MessageTooLong> {
+        let mut message = self.header(self.attributes.len())?;
+        message.extend_from_slice(&self.attributes);
+        Ok(message)
+    }
+
+    /// Finish the message with a `MESSAGE-INTEGRITY` attribute under `key`.
+    pub fn finish_with_integrity(&self, key: &[u8]) -> Result<Vec<u8>, MessageTooLong> {
+        let mut message = self.header(self.attributes.len() + 4 + MESSAGE_INTEGRITY_LEN)?;
+        message.extend_from_slice(&self.attributes);
+        let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes keys of any size");
+        mac.update(&message);
+        message.extend_from_slice(&ATTR_MESSAGE_INTEGRITY.to_be_bytes());
+        message.extend_from_slice(&(MESSAGE_INTEGRITY_LEN as u16).to_be_bytes());
+        message.extend_from_slice(&mac.finalize().into_bytes());
+        Ok(message)
+    }
+}
+
+/// Key XORed with an address: the magic cookie, then the transaction id for IPv6
+fn xor_key(transaction_id: &[u8; 12]) -> impl Iterator<Item = u8> + '_ {
+    MAGIC_COOKIE
+        .to_be_bytes()
+        .into_iter()
+        .chain(transaction_id.iter().copied())
+}
+
+pub fn encode_xor_address(address: SocketAddr, transaction_id: &[u8; 12]) -> Vec<u8> {
+    let port = address.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let (family, octets) = match address.ip() {
+        IpAddr::V4(ip) => (0x01, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (0x02, ip.octets().to_vec()),
+    };
+    let mut value = vec![0, family];
+    value.extend_from_slice(&port.to_be_bytes());
+    value.extend(
+        octets
+            .iter()
+            .zip(xor_key(transaction_id))
+            .map(|(a, b)| a ^ b),
+    );
+    value
+}
+
+pub fn decode_xor_address(value: &[u8], transaction_id: &[u8; 12]) -> Option<SocketAddr> {
+    if value.len() < 4 {
+        return None;
+    }
+    let port = u16::from_be_bytes([value[2], value[3]]) ^ (MAGIC_COOKIE >> 16) as u16;
+    let octets: Vec<u8> = value[4..]
+        .iter()
+        .zip(xor_key(transaction_id))
+        .map(|(a, b)| a ^ b)
+        .collect();
+    let ip = match (value[1], octets.len()) {
+        (0x01, 4) => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(octets).ok()?)),
+        (0x02, 16) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(octets).ok()?)),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Receive the next datagram on `socket`, skipping failed receives. On some platforms an ICMP
+/// error caused by an earlier send, such as port unreachable, surfaces as a receive error; it
+/// says nothing about this socket, which is served on. Cancel safe.
+pub async fn recv_datagram(socket: &UdpSocket, buf: &mut [u8], name: &str) -> (usize, SocketAddr) {
+    loop {
+        match socket.recv_from(buf).await {
+            Ok(received) => return received,
+            Err(e) => debug!(socket = name, error = ?e, "UDP receive failed"),
+        }
+    }
+}
+
+/// Answer STUN Binding requests (RFC 5389) on `socket` with the address they came from, so a
+/// self-hosted deployment needs no separate STUN server.
+pub async fn serve(socket: UdpSocket) {
+    let mut buf = [0u8; 1500];
+    loop {
+        let (len, source) = recv_datagram(&socket, &mut buf, "STUN").await;
+        let Some(response) = Message::parse(&buf[..len]).and_then(|m| respond(&m, source)) else {
+            continue;
+        };
+        if let Err(e) = socket.send_to(&response, source).await {
+            warn!(source = %source, error = ?e, "Failed to send STUN response");
+        }
+    }
+}
+
+/// The response to a Binding request received from `source`. Other messages are ignored.
+pub fn respond(message: &Message, source: SocketAddr) -> Option<Vec<u8>> {
+    if message.method != METHOD_BINDING || message.class != Class::Request {
+        return None;
+    }
+    // Binding requests carry no attribute we must understand, so any comprehension-required one
+    // is refused as unknown
+    let unknown = message.unknown_attributes(&[]);
+    if !unknown.is_empty() {
+        let unknown: Vec<u8> = unknown.iter().flat_map(|kind| kind.to_be_bytes()).collect();
+        let response = MessageBuilder::error(
+            METHOD_BINDING,
+            message.transaction_id,
+            420,
+            "Unknown Attribute",
+        )
+        .attribute(ATTR_UNKNOWN_ATTRIBUTES, &unknown)
+        .finish();
+        return response.ok();
+    }
+    let response = MessageBuilder::new(METHOD_BINDING, Class::Success, message.transaction_id)
+        .xor_address(ATTR_XOR_MAPPED_ADDRESS, source)
+        .software()
+        .finish();
+    response.ok()
+}
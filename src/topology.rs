@@ -1,104 +1,230 @@
-use crate::state::{Peer, ServerState};
+use crate::auth::decode_token;
+use crate::chat::ChatMessage;
+use crate::events::{ClientMessage, ErrorCode, IncomingMessage, RosterEntry, ServerEvent};
+use crate::lobby::{LobbyError, Topology};
+use crate::rate_limit::TokenBucket;
+use crate::session::{DisconnectedPlayer, Peer, Reservation, WaitingPlayer};
+use crate::state::{
+    Core, ServerState, AUTH_FAILED_CLOSE_CODE, NOT_IN_LOBBY_CLOSE_CODE, SIGNAL_LIMITS_CLOSE_CODE,
+    TOO_MANY_VIOLATIONS_CLOSE_CODE, UNAVAILABLE_CLOSE_CODE,
+};
 use async_trait::async_trait;
-use axum::extract::ws::Message;
-use futures::StreamExt;
-use matchbox_protocol::{JsonPeerEvent, PeerRequest};
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
+use chrono::Utc;
+use futures::{stream::SplitStream, StreamExt};
+use matchbox_protocol::{JsonPeerEvent, PeerId, PeerRequest};
 use matchbox_signaling::{
-    common_logic::parse_request, ClientRequestError, NoCallbacks, SignalingTopology, WsStateMeta,
+    common_logic::{parse_request, try_send},
+    ClientRequestError, NoCallbacks, SignalingTopology, WsStateMeta,
 };
-use tracing::{error, info, warn};
+use std::sync::{atomic::AtomicU64, Arc};
+use std::time::Instant;
+use tokio::sync::{mpsc::UnboundedSender, Notify};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
 
 #[derive(Debug, Default)]
 pub struct MatchmakingDemoTopology;
 
+/// How a signaling connection ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionEnd {
+    /// The client sent a close frame, it is leaving on purpose
+    Closed,
+    /// The socket errored or vanished without a close frame, the client may come back
+    Dropped,
+    /// The server ended the connection (kick, lobby move)
+    Disconnected,
+}
+
 #[async_trait]
 impl SignalingTopology<NoCallbacks, ServerState> for MatchmakingDemoTopology {
     async fn state_machine(upgrade: WsStateMeta<NoCallbacks, ServerState>) {
         let WsStateMeta {
-            peer_id,
+            peer_id: assigned_peer_id,
             sender,
             mut receiver,
-            mut state,
+            state,
             ..
         } = upgrade;
 
-        let player_id = {
-            let players_to_peers = state.players_to_peers.read().unwrap();
-            tracing::debug!(peer_id = ?peer_id, players_to_peers = ?*players_to_peers, "Looking up player_id for peer_id");
-            players_to_peers
-                .iter()
-                .find(|(_, p)| **p == peer_id)
-                .map(|(player_id, _)| player_id.clone())
+        let connecting = state
+            .core
+            .call(move |core| core.sessions.take_connecting(&assigned_peer_id))
+            .await;
+        let connecting = match connecting {
+            Ok(connecting) => connecting,
+            Err(e) => {
+                warn!(peer_id = ?assigned_peer_id, error = %e, "Cannot start session");
+                close(&sender, UNAVAILABLE_CLOSE_CODE, "Server is unavailable");
+                return;
+            }
         };
-
-        let player_id = match player_id {
+        let Some(mut connecting) = connecting else {
+            error!(peer_id = ?assigned_peer_id, "No upgrade found for peer");
+            close(&sender, AUTH_FAILED_CLOSE_CODE, "Authentication failed");
+            return;
+        };
+        let player_id = match connecting.player_id.clone() {
             Some(id) => {
-                tracing::info!(peer_id = ?peer_id, player_id = %&id[..8], "Found player_id for peer");
+                tracing::info!(peer_id = ?assigned_peer_id, player_id = %&id[..8], "Found player_id for peer");
                 id
             }
-            None => {
-                error!(peer_id = ?peer_id, "No player id found for peer, somehow");
-                return;
-            }
+            None => match authenticate_first_message(&state, assigned_peer_id, &mut receiver).await
+            {
+                Some((id, username)) => {
+                    connecting.username = username;
+                    id
+                }
+                None => {
+                    close(&sender, AUTH_FAILED_CLOSE_CODE, "Authentication failed");
+                    return;
+                }
+            },
         };
 
-        let lobby_id = {
-            let players_in_lobbies = state.players_in_lobbies.read().unwrap();
-            tracing::debug!(player_id = %&player_id[..8], players_in_lobbies = ?*players_in_lobbies, "Looking up lobby_id for player");
-            players_in_lobbies.get(&player_id).cloned()
+        let disconnect = Arc::new(Notify::new());
+        let session = {
+            let player_id = player_id.clone();
+            let sender = sender.clone();
+            let disconnect = disconnect.clone();
+            state
+                .core
+                .try_call(move |core| {
+                    start_session(
+                        core,
+                        &player_id,
+                        assigned_peer_id,
+                        connecting,
+                        sender,
+                        disconnect,
+                    )
+                })
+                .await
         };
-
-        let lobby_id = match lobby_id {
-            Some(id) => {
-                tracing::info!(player_id = %&player_id[..8], lobby_id = %id, "Found lobby for player");
-                id
+        let peer = match session {
+            Ok(peer) => peer,
+            Err(LobbyError::TooManyViolations) => {
+                warn!(player_id = %&player_id[..8], "Refusing session of a player over the violation limit");
+                close(
+                    &sender,
+                    TOO_MANY_VIOLATIONS_CLOSE_CODE,
+                    "Too many protocol violations",
+                );
+                return;
             }
-            None => {
-                error!(player_id = %&player_id[..8], "No lobby id found for peer, somehow");
+            Err(LobbyError::Unavailable(e)) => {
+                warn!(player_id = %&player_id[..8], error = %e, "Cannot start session");
+                close(&sender, UNAVAILABLE_CLOSE_CODE, "Server is unavailable");
+                return;
+            }
+            Err(e) => {
+                warn!(player_id = %&player_id[..8], error = %e, "Player is not in the requested lobby");
+                close(
+                    &sender,
+                    NOT_IN_LOBBY_CLOSE_CODE,
+                    "Not a member of this lobby",
+                );
                 return;
             }
         };
+        let peer_id = peer.id;
+        let lobby_id = peer.lobby_id;
 
-        let peer = Peer {
-            id: peer_id,
-            sender: sender.clone(),
-        };
-        state.add_peer(peer);
-
-        let players = {
-            let lobby_manager = state.lobby_manager.read().unwrap();
-            lobby_manager.get_lobby(&lobby_id).map(|l| l.players)
+        let mut relay_limit =
+            TokenBucket::new(state.config.chat_rate_burst, state.config.chat_rate_per_sec);
+        let mut signal_limits = SignalLimits {
+            state: &state,
+            player_id: &player_id,
+            peer_id,
+            sender: &sender,
+            rate: TokenBucket::new(
+                state.config.signal_rate_burst,
+                state.config.signal_rate_per_sec,
+            ),
+            warnings: 0,
         };
-
-        if let Some(players) = players {
-            let event = Message::Text(JsonPeerEvent::NewPeer(peer_id).to_string());
-            for player_id_str in players {
-                if player_id_str != player_id {
-                    let players_to_peers = state.players_to_peers.read().unwrap();
-                    if let Some(peer_id) = players_to_peers.get(&player_id_str) {
-                        if let Err(e) = state.try_send(*peer_id, event.clone()) {
-                            error!("error sending to {peer_id:?}: {e:?}");
-                        }
+        let ping_interval = state.config.ping_interval();
+        let mut ping =
+            tokio::time::interval_at(tokio::time::Instant::now() + ping_interval, ping_interval);
+        let connection_end = loop {
+            let request = tokio::select! {
+                request = receiver.next() => match request {
+                    Some(request) => request,
+                    None => break ConnectionEnd::Dropped,
+                },
+                _ = disconnect.notified() => {
+                    info!("Disconnecting {peer_id:?} on server request");
+                    break ConnectionEnd::Disconnected;
+                }
+                _ = ping.tick() => {
+                    // A half-open connection never errors, it only goes quiet
+                    if peer.idle_for() > state.config.idle_timeout() {
+                        warn!(peer_id = ?peer_id, "Evicting idle peer");
+                        break ConnectionEnd::Dropped;
                     }
+                    if let Err(e) = try_send(&sender, Message::Ping(Vec::new())) {
+                        warn!(peer_id = ?peer_id, error = ?e, "Failed to ping peer");
+                    }
+                    continue;
+                }
+            };
+            peer.touch();
+            // Oversized messages are refused before any parsing
+            let size = match &request {
+                Ok(Message::Text(text)) => text.len(),
+                Ok(Message::Binary(data)) => data.len(),
+                _ => 0,
+            };
+            if size > state.config.signal_max_bytes {
+                if signal_limits
+                    .refuse(ErrorCode::MessageTooLarge, "Message is too large")
+                    .await
+                {
+                    break ConnectionEnd::Disconnected;
                 }
+                continue;
             }
-        }
-
-        while let Some(request) = receiver.next().await {
-            let request = match parse_request(request) {
-                Ok(request) => request,
+            // Our own messages share the socket with matchbox's requests
+            let parsed = match request {
+                Ok(Message::Text(text)) => {
+                    serde_json::from_str::<IncomingMessage>(&text).map_err(ClientRequestError::Json)
+                }
+                Ok(Message::Ping(_) | Message::Pong(_)) => continue,
+                request => parse_request(request).map(IncomingMessage::Peer),
+            };
+            let request = match parsed {
+                Ok(IncomingMessage::Client(message)) => {
+                    let relay = Relay {
+                        state: &state,
+                        lobby_id,
+                        player_id: &player_id,
+                        peer_id,
+                    };
+                    relay.handle(message, &mut relay_limit);
+                    continue;
+                }
+                Ok(IncomingMessage::Peer(request)) => request,
                 Err(e) => {
                     match e {
                         ClientRequestError::Axum(_) => {
                             warn!("Unrecoverable error with {peer_id:?}: {e:?}");
-                            break;
+                            break ConnectionEnd::Dropped;
                         }
                         ClientRequestError::Close => {
                             info!("Connection closed by {peer_id:?}");
-                            break;
+                            break ConnectionEnd::Closed;
                         }
                         ClientRequestError::Json(_) | ClientRequestError::UnsupportedType(_) => {
-                            error!("Error with request: {:?}", e);
+                            // Malformed messages cost as much as signals, and are refused like
+                            // oversized ones
+                            signal_limits.rate.try_take();
+                            if signal_limits
+                                .refuse(ErrorCode::InvalidRequest, &e.to_string())
+                                .await
+                            {
+                                break ConnectionEnd::Disconnected;
+                            }
                             continue;
                         }
                     };
@@ -107,53 +233,386 @@ impl SignalingTopology<NoCallbacks, ServerState> for MatchmakingDemoTopology {
 
             match request {
                 PeerRequest::Signal { receiver, data } => {
-                    let event = Message::Text(
-                        JsonPeerEvent::Signal {
-                            sender: peer_id,
-                            data,
+                    if !signal_limits.rate.try_take() {
+                        if signal_limits
+                            .refuse(ErrorCode::RateLimited, "Too many signals")
+                            .await
+                        {
+                            break ConnectionEnd::Disconnected;
                         }
-                        .to_string(),
-                    );
-                    if let Err(e) = state.try_send(receiver, event) {
-                        error!("error sending to {receiver:?}: {e:?}");
+                        continue;
                     }
+                    let player_id = player_id.clone();
+                    state.core.cast(move |core| {
+                        forward_signal(core, &player_id, peer_id, lobby_id, receiver, data)
+                    });
                 }
                 PeerRequest::KeepAlive => {}
             }
-        }
+        };
 
         info!("Removing peer: {:?}", peer_id);
-        state.remove_peer(&peer_id);
-        {
-            let mut players_in_lobbies = state.players_in_lobbies.write().unwrap();
-            players_in_lobbies.remove(&player_id);
+        let grace = state.config.reconnect_grace();
+        let may_resume = connection_end == ConnectionEnd::Dropped && !grace.is_zero();
+        let held = {
+            let player_id = player_id.clone();
+            // Waits out an overload, the peer and its seat must not leak
+            state
+                .core
+                .call_queued(move |core| {
+                    core.sessions.remove_peer(&peer_id);
+                    let is_current = core.sessions.peer_id_of(&player_id) == Some(peer_id);
+                    if may_resume && is_current {
+                        hold_seat(core, &player_id, peer_id, lobby_id);
+                        true
+                    } else {
+                        core.leave_lobby(&player_id, peer_id, lobby_id);
+                        false
+                    }
+                })
+                .await
+                .unwrap_or(false)
+        };
+        if held {
+            tokio::spawn(async move {
+                tokio::time::sleep(grace).await;
+                let expired = state.core.call_queued(move |core| {
+                    if let Some(held) = core.sessions.expire_seat(&player_id, peer_id) {
+                        info!(player_id = %&player_id[..8], peer_id = ?peer_id, "Reconnection grace period expired");
+                        core.leave_lobby(&player_id, held.peer_id, held.lobby_id);
+                    }
+                });
+                if let Err(e) = expired.await {
+                    error!(peer_id = ?peer_id, error = %e, "Failed to release held seat");
+                }
+            });
         }
-        {
-            let mut players_to_peers = state.players_to_peers.write().unwrap();
-            players_to_peers.remove(&player_id);
+    }
+}
+
+/// Bind a connection to its player and lobby, register it and tell the lobby about it. A player
+/// coming back within the grace period takes over their previous peer id, so the rest of the lobby
+/// keeps addressing them the same way.
+fn start_session(
+    core: &mut Core,
+    player_id: &str,
+    assigned_peer_id: PeerId,
+    upgrade: WaitingPlayer,
+    sender: UnboundedSender<Result<Message, axum::Error>>,
+    disconnect: Arc<Notify>,
+) -> Result<Peer, LobbyError> {
+    // Membership is checked again: the player may have left the lobby since the upgrade
+    let lobby_id = core.connection_lobby(player_id, upgrade.lobby_id)?;
+    tracing::info!(player_id = %&player_id[..8], lobby_id = %lobby_id, "Found lobby for player");
+
+    let reservation =
+        core.sessions
+            .start_session(player_id, assigned_peer_id, lobby_id, upgrade.resume);
+    let (peer_id, resumed) = match reservation {
+        Reservation::Resumed(peer_id) => {
+            info!(player_id = %&player_id[..8], peer_id = ?peer_id, "Player resumed their seat");
+            (peer_id, true)
         }
-        {
-            let mut lobby_manager = state.lobby_manager.write().unwrap();
-            lobby_manager.remove_player_from_lobby(&lobby_id, &player_id);
+        Reservation::Released(held) => {
+            // They moved to another lobby while disconnected, or cannot take back their old peer
+            // id: announce it as left. The lobby seat itself stays theirs if they came back to it.
+            core.leave_lobby(player_id, held.peer_id, held.lobby_id);
+            (assigned_peer_id, false)
         }
+        Reservation::None => (assigned_peer_id, false),
+    };
+    core.record_username(player_id, &upgrade.username);
 
-        let players = {
-            let lobby_manager = state.lobby_manager.read().unwrap();
-            lobby_manager.get_lobby(&lobby_id).map(|l| l.players)
+    let peer = Peer {
+        id: peer_id,
+        sender,
+        lobby_id,
+        disconnect,
+        connected_at: Instant::now(),
+        last_activity: Arc::new(AtomicU64::new(0)),
+        control_events: upgrade.control_events,
+    };
+    core.sessions.add_peer(peer.clone());
+    announce_arrival(core, player_id, peer_id, lobby_id, resumed);
+    Ok(peer)
+}
+
+/// Send a new connection its lobby's state and announce it to the other members.
+fn announce_arrival(core: &Core, player_id: &str, peer_id: PeerId, lobby_id: Uuid, resumed: bool) {
+    if resumed {
+        send_event(core, peer_id, ServerEvent::Resumed { peer_id, lobby_id });
+    }
+    let members = core.lobby_roster(&lobby_id);
+    let spectator = members
+        .iter()
+        .any(|member| member.peer_id == peer_id && member.spectator);
+    // Other members with a live connection
+    let connected: Vec<RosterEntry> = members
+        .iter()
+        .filter(|member| member.peer_id != peer_id)
+        .filter(|member| core.get_peer(&member.peer_id).is_some())
+        .cloned()
+        .collect();
+    let (topology, host) = core
+        .lobbies
+        .lobby(&lobby_id)
+        .map(|lobby| (lobby.topology, lobby.host.clone()))
+        .unwrap_or_default();
+    send_event(core, peer_id, ServerEvent::Roster { lobby_id, members });
+    let messages = core.chat_history.get(&lobby_id);
+    send_event(
+        core,
+        peer_id,
+        ServerEvent::ChatHistory { lobby_id, messages },
+    );
+    let entry = RosterEntry {
+        spectator,
+        ..core.roster_entry(player_id, peer_id)
+    };
+    if resumed {
+        let event = ServerEvent::PeerReconnected(peer_id);
+        core.broadcast_event(&lobby_id, Some(player_id), &event);
+    } else if topology == Topology::Star {
+        // Only the host learns about peers and opens the connections, the other members only
+        // ever connect to the host
+        if host.as_deref() == Some(player_id) {
+            for member in &connected {
+                announce_peer(core, peer_id, member.peer_id);
+            }
+        } else if let Some(host) = connected.iter().find(|m| host.as_ref() == Some(&m.pubkey)) {
+            announce_peer(core, host.peer_id, peer_id);
+        }
+        let event = if spectator {
+            ServerEvent::SpectatorJoined(entry)
+        } else {
+            ServerEvent::PeerJoined(entry)
         };
+        core.broadcast_event(&lobby_id, Some(player_id), &event);
+    } else if spectator {
+        // Players are not told about spectators as peers. The spectator is the one opening
+        // the connections, like any member does for a newcomer.
+        for player in connected.iter().filter(|member| !member.spectator) {
+            announce_peer(core, peer_id, player.peer_id);
+        }
+        let event = ServerEvent::SpectatorJoined(entry);
+        core.broadcast_event(&lobby_id, Some(player_id), &event);
+    } else {
+        let event = Message::Text(JsonPeerEvent::NewPeer(peer_id).to_string());
+        core.broadcast_to_lobby(&lobby_id, Some(player_id), event);
+        let event = ServerEvent::PeerJoined(entry);
+        core.broadcast_event(&lobby_id, Some(player_id), &event);
+    }
+}
+/// A lobby member's chat and data messages, relayed by the server
+struct Relay<'a> {
+    state: &'a ServerState,
+    lobby_id: Uuid,
+    player_id: &'a str,
+    peer_id: PeerId,
+}
 
-        if let Some(players) = players {
-            let event = Message::Text(JsonPeerEvent::PeerLeft(peer_id).to_string());
-            for player_id_str in players {
-                if player_id_str != player_id {
-                    let players_to_peers = state.players_to_peers.read().unwrap();
-                    if let Some(peer_id) = players_to_peers.get(&player_id_str) {
-                        if let Err(e) = state.try_send(*peer_id, event.clone()) {
-                            error!("error sending to {peer_id:?}: {e:?}");
-                        }
-                    }
+impl Relay<'_> {
+    fn handle(&self, message: ClientMessage, limit: &mut TokenBucket) {
+        match message {
+            ClientMessage::Authenticate { .. } => {
+                self.refuse(ErrorCode::InvalidRequest, "Already authenticated");
+            }
+            ClientMessage::Chat { text } => {
+                if text.len() > self.state.config.chat_max_bytes {
+                    return self.refuse(ErrorCode::MessageTooLarge, "Chat message is too long");
+                }
+                if !limit.try_take() {
+                    return self.refuse(ErrorCode::RateLimited, "Too many messages");
+                }
+                let Some(text) = self.state.chat_filter.apply(&text) else {
+                    return self.refuse(ErrorCode::MessageRejected, "Chat message was rejected");
+                };
+                let (lobby_id, player_id, peer_id) =
+                    (self.lobby_id, self.player_id.to_string(), self.peer_id);
+                let history_len = self.state.config.chat_history_len;
+                self.state.core.cast(move |core| {
+                    let message = ChatMessage {
+                        sender: core.roster_entry(&player_id, peer_id),
+                        text,
+                        sent_at: Utc::now(),
+                    };
+                    core.chat_history
+                        .push(lobby_id, message.clone(), history_len);
+                    let event = ServerEvent::Chat(message);
+                    core.broadcast_event(&lobby_id, None, &event);
+                });
+            }
+            ClientMessage::Data { data } => {
+                if data.to_string().len() > self.state.config.data_max_bytes {
+                    return self.refuse(ErrorCode::MessageTooLarge, "Data message is too large");
+                }
+                if !limit.try_take() {
+                    return self.refuse(ErrorCode::RateLimited, "Too many messages");
                 }
+                let event = ServerEvent::Data {
+                    sender: self.peer_id,
+                    data,
+                };
+                self.state
+                    .broadcast_event(self.lobby_id, Some(self.player_id), event);
             }
         }
     }
+
+    fn refuse(&self, code: ErrorCode, message: &str) {
+        warn!(peer_id = ?self.peer_id, ?code, "Refused relay message");
+        let event = ServerEvent::Error {
+            code,
+            message: message.to_string(),
+        };
+        self.state.send_event(self.peer_id, event);
+    }
+}
+
+/// A connection's signal rate limit and the refusals it was warned about
+struct SignalLimits<'a> {
+    state: &'a ServerState,
+    player_id: &'a str,
+    peer_id: PeerId,
+    sender: &'a UnboundedSender<Result<Message, axum::Error>>,
+    rate: TokenBucket,
+    warnings: u32,
+}
+
+impl SignalLimits<'_> {
+    /// Refuse a message as a protocol violation. Returns true once the connection ran out of
+    /// warnings, after closing it.
+    async fn refuse(&mut self, code: ErrorCode, message: &str) -> bool {
+        let violations = {
+            let player_id = self.player_id.to_string();
+            self.state
+                .core
+                .call(move |core| core.record_violation(&player_id))
+                .await
+                .unwrap_or_default()
+        };
+        if self.warnings >= self.state.config.signal_max_warnings {
+            warn!(peer_id = ?self.peer_id, ?code, violations, "Disconnecting peer for exceeding the signal limits");
+            close(
+                self.sender,
+                SIGNAL_LIMITS_CLOSE_CODE,
+                "Signal limits exceeded",
+            );
+            return true;
+        }
+        self.warnings += 1;
+        // Refusals are bounded per connection but not across them, keep them out of the default
+        // logs
+        debug!(peer_id = ?self.peer_id, ?code, violations, "Refused message");
+        let event = ServerEvent::Error {
+            code,
+            message: message.to_string(),
+        };
+        self.state.send_event(self.peer_id, event);
+        false
+    }
+}
+
+/// Forward a signal to `receiver` if it is in the sender's lobby.
+fn forward_signal(
+    core: &mut Core,
+    player_id: &str,
+    peer_id: PeerId,
+    lobby_id: Uuid,
+    receiver: PeerId,
+    data: serde_json::Value,
+) {
+    // Peers that already left are not an offense, anything else in another lobby is
+    if core
+        .peer_lobby(&receiver)
+        .is_some_and(|lobby| lobby != lobby_id)
+    {
+        let violations = core.record_violation(player_id);
+        warn!(peer_id = ?peer_id, receiver = ?receiver, violations, "Refused signal to a peer of another lobby");
+        let event = ServerEvent::Error {
+            code: ErrorCode::PeerNotInLobby,
+            message: "Receiver is not in your lobby".to_string(),
+        };
+        send_event(core, peer_id, event);
+        return;
+    }
+    let event = JsonPeerEvent::Signal {
+        sender: peer_id,
+        data,
+    };
+    if let Err(e) = core.try_send(receiver, Message::Text(event.to_string())) {
+        error!("error sending to {receiver:?}: {e:?}");
+    }
+}
+
+/// Tell `receiver` to open a connection to `new_peer`.
+fn announce_peer(core: &Core, receiver: PeerId, new_peer: PeerId) {
+    let event = JsonPeerEvent::NewPeer(new_peer).to_string();
+    core.send(receiver, Message::Text(event));
+}
+
+fn send_event(core: &Core, peer_id: PeerId, event: ServerEvent) {
+    core.send_event(peer_id, &event);
+}
+
+/// Close a signaling connection from its state machine.
+fn close(sender: &UnboundedSender<Result<Message, axum::Error>>, code: u16, reason: &'static str) {
+    let frame = CloseFrame {
+        code,
+        reason: reason.into(),
+    };
+    if let Err(e) = try_send(sender, Message::Close(Some(frame))) {
+        warn!(error = ?e, "Failed to close signaling connection");
+    }
+}
+
+/// Wait for the `Authenticate` message of a connection that was upgraded without a token and return
+/// the authenticated player and their username.
+async fn authenticate_first_message(
+    state: &ServerState,
+    peer_id: PeerId,
+    receiver: &mut SplitStream<WebSocket>,
+) -> Option<(String, String)> {
+    let message = tokio::time::timeout(state.config.auth_timeout(), receiver.next()).await;
+    let text = match message {
+        Ok(Some(Ok(Message::Text(text)))) => text,
+        Ok(_) => {
+            warn!(peer_id = ?peer_id, "Expected an Authenticate message");
+            return None;
+        }
+        Err(_) => {
+            warn!(peer_id = ?peer_id, "Timed out waiting for Authenticate message");
+            return None;
+        }
+    };
+    let claims = match serde_json::from_str(&text) {
+        Ok(ClientMessage::Authenticate { token }) => match decode_token(&token, &state.secret) {
+            Ok(claims) => claims,
+            Err(e) => {
+                warn!(peer_id = ?peer_id, error = ?e, "Invalid token in Authenticate message");
+                return None;
+            }
+        },
+        Ok(_) => {
+            warn!(peer_id = ?peer_id, "Expected an Authenticate message");
+            return None;
+        }
+        Err(e) => {
+            warn!(peer_id = ?peer_id, error = ?e, "Expected an Authenticate message");
+            return None;
+        }
+    };
+
+    info!(peer_id = ?peer_id, player_id = %&claims.sub[..8], "Peer authenticated with first message");
+    Some((claims.sub, claims.username))
+}
+
+/// Keep a dropped player's seat for the reconnection grace period. The caller releases it once the
+/// grace period is over, unless they came back in the meantime.
+fn hold_seat(core: &mut Core, player_id: &str, peer_id: PeerId, lobby_id: Uuid) {
+    info!(player_id = %&player_id[..8], peer_id = ?peer_id, "Holding seat for reconnection");
+    core.sessions
+        .hold_seat(player_id, DisconnectedPlayer { peer_id, lobby_id });
+    let event = ServerEvent::PeerDisconnected(peer_id);
+    core.broadcast_event(&lobby_id, Some(player_id), &event);
 }
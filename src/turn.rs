@@ -0,0 +1,767 @@
+use crate::args::Args;
+use crate::ice::turn_credential;
+use crate::lobby::PlayerId;
+use crate::rate_limit::TokenBucket;
+use crate::stun::{
+    self, Class, Message, MessageBuilder, MessageTooLong, ATTR_MESSAGE_INTEGRITY, ATTR_NONCE,
+    ATTR_REALM, ATTR_UNKNOWN_ATTRIBUTES, ATTR_USERNAME, ATTR_XOR_MAPPED_ADDRESS, METHOD_BINDING,
+};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+pub const METHOD_ALLOCATE: u16 = 0x003;
+pub const METHOD_REFRESH: u16 = 0x004;
+pub const METHOD_SEND: u16 = 0x006;
+pub const METHOD_DATA: u16 = 0x007;
+pub const METHOD_CREATE_PERMISSION: u16 = 0x008;
+pub const METHOD_CHANNEL_BIND: u16 = 0x009;
+
+pub const ATTR_CHANNEL_NUMBER: u16 = 0x000C;
+pub const ATTR_LIFETIME: u16 = 0x000D;
+pub const ATTR_XOR_PEER_ADDRESS: u16 = 0x0012;
+pub const ATTR_DATA: u16 = 0x0013;
+pub const ATTR_XOR_RELAYED_ADDRESS: u16 = 0x0016;
+pub const ATTR_REQUESTED_TRANSPORT: u16 = 0x0019;
+pub const ATTR_DONT_FRAGMENT: u16 = 0x001A;
+
+/// Comprehension-required attributes the relay understands
+const KNOWN_ATTRIBUTES: &[u16] = &[
+    ATTR_USERNAME,
+    ATTR_MESSAGE_INTEGRITY,
+    ATTR_REALM,
+    ATTR_NONCE,
+    ATTR_CHANNEL_NUMBER,
+    ATTR_LIFETIME,
+    ATTR_XOR_PEER_ADDRESS,
+    ATTR_DATA,
+    ATTR_REQUESTED_TRANSPORT,
+    ATTR_DONT_FRAGMENT,
+];
+
+const UDP_TRANSPORT: u8 = 17;
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(600);
+const MAX_LIFETIME: Duration = Duration::from_secs(3600);
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+const CHANNEL_LIFETIME: Duration = Duration::from_secs(600);
+const NONCE_LIFETIME: Duration = Duration::from_secs(3600);
+const CHANNEL_NUMBERS: std::ops::RangeInclusive<u16> = 0x4000..=0x7FFF;
+/// Datagrams from peers waiting for the server task. Past it they are dropped, like a congested
+/// link would.
+const PEER_PACKET_QUEUE: usize = 256;
+
+/// A datagram a peer sent to a relayed address
+#[derive(Debug)]
+struct PeerPacket {
+    client: SocketAddr,
+    peer: SocketAddr,
+    data: Vec<u8>,
+}
+
+/// A client's relayed transport address and what it may relay to
+#[derive(Debug)]
+struct Allocation {
+    player_id: PlayerId,
+    username: String,
+    /// Long-term credential key the client authenticated with
+    key: [u8; 16],
+    /// Of the Allocate request, to answer its retransmissions
+    transaction_id: [u8; 12],
+    relay: Arc<UdpSocket>,
+    relayed_address: SocketAddr,
+    expires_at: Instant,
+    permissions: HashMap<IpAddr, Instant>,
+    /// Peer and expiry of each bound channel
+    channels: HashMap<u16, (SocketAddr, Instant)>,
+    reader: JoinHandle<()>,
+}
+
+impl Allocation {
+    fn permits(&self, peer: &SocketAddr, now: Instant) -> bool {
+        self.permissions
+            .get(&peer.ip())
+            .is_some_and(|expires_at| *expires_at > now)
+    }
+
+    fn channel_of(&self, peer: &SocketAddr) -> Option<u16> {
+        self.channels
+            .iter()
+            .find(|(_, (bound, _))| bound == peer)
+            .map(|(channel, _)| *channel)
+    }
+}
+
+/// Allocations and relayed bandwidth of a player, shared by all their allocations
+#[derive(Debug)]
+struct Quota {
+    allocations: usize,
+    bandwidth: TokenBucket,
+}
+
+impl Quota {
+    /// Whether dropping the quota loses nothing. A drained bucket is kept until it refilled, or
+    /// letting an allocation lapse and allocating again would hand out a full one.
+    fn is_idle(&mut self) -> bool {
+        self.allocations == 0 && self.bandwidth.is_full()
+    }
+}
+
+/// Peer addresses the relay refuses to talk to, like coturn's `denied-peer-ip`. Anyone can get TURN
+/// credentials, so without it the relay would be an open proxy into the host's own network.
+#[derive(Debug)]
+struct DeniedPeers {
+    /// Addresses of the server itself
+    own_ips: Vec<IpAddr>,
+    allow_loopback: bool,
+}
+
+impl DeniedPeers {
+    /// IPv6 addresses carrying an IPv4 address are denied when either one is.
+    fn contains(&self, ip: IpAddr) -> bool {
+        let embedded = match ip {
+            IpAddr::V6(ip) => embedded_ipv4(ip).map(IpAddr::V4),
+            IpAddr::V4(_) => None,
+        };
+        self.denies(ip) || embedded.is_some_and(|ip| self.denies(ip))
+    }
+
+    fn denies(&self, ip: IpAddr) -> bool {
+        if ip.is_loopback() {
+            return !self.allow_loopback;
+        }
+        self.own_ips.contains(&ip) || is_internal(ip)
+    }
+}
+
+/// IPv4 address of an IPv4-mapped (`::ffff:0:0/96`), NAT64 (`64:ff9b::/96`) or 6to4
+/// (`2002::/16`) address. Those reach the IPv4 address through the host's stack or a gateway.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let bits = u128::from(ip);
+    if let Some(ip) = ip.to_ipv4_mapped() {
+        Some(ip)
+    } else if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        Some(Ipv4Addr::from(bits as u32))
+    } else if segments[0] == 0x2002 {
+        Some(Ipv4Addr::from((bits >> 80) as u32))
+    } else {
+        None
+    }
+}
+
+/// Unspecified, private, shared (RFC 6598), IETF protocol assignment (`192.0.0.0/24`), benchmarking
+/// (`198.18.0.0/15`), link-local, reserved (`240.0.0.0/4`), site-local (`fec0::/10`), unique
+/// local, multicast and broadcast addresses
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, third, _] = ip.octets();
+            first == 0
+                || ip.is_private()
+                || (first == 100 && second & 0xC0 == 64)
+                || (first == 192 && second == 0 && third == 0)
+                || (first == 198 && second & 0xFE == 18)
+                || ip.is_link_local()
+                || ip.is_multicast()
+                || first >= 240
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_unspecified()
+                || first & 0xFE00 == 0xFC00
+                || first & 0xFFC0 == 0xFE80
+                || first & 0xFFC0 == 0xFEC0
+                || ip.is_multicast()
+        }
+    }
+}
+
+/// Outcome of authenticating a request
+enum Auth {
+    Allowed {
+        player_id: PlayerId,
+        username: String,
+        key: [u8; 16],
+    },
+    Refused(Result<Vec<u8>, MessageTooLong>),
+}
+
+/// TURN relay (RFC 5766, UDP allocations). Clients authenticate with the credentials handed out by
+/// `GET /ice-servers`, see [`turn_credential`], and each player gets at most
+/// `turn_max_allocations` allocations sharing `turn_bandwidth_bytes_per_sec` of relayed traffic.
+/// Players cost nothing to create, so the server as a whole holds at most
+/// `turn_max_total_allocations`.
+///
+/// Everything is owned by the task running [`TurnServer::run`]. Relayed sockets are read by
+/// their own task, which hands the datagrams back to it.
+pub struct TurnServer {
+    socket: UdpSocket,
+    config: Arc<Args>,
+    secret: String,
+    /// Address relayed sockets are bound to
+    relay_ip: IpAddr,
+    /// Address advertised for relayed sockets
+    external_ip: IpAddr,
+    denied_peers: DeniedPeers,
+    nonce_key: [u8; 20],
+    allocations: HashMap<SocketAddr, Allocation>,
+    quotas: HashMap<PlayerId, Quota>,
+    peer_packets: mpsc::Sender<PeerPacket>,
+}
+
+impl TurnServer {
+    /// Serve TURN on `socket`. Relayed addresses are advertised as `turn_external_ip`, or else the
+    /// socket's own address, which must then not be unspecified.
+    pub async fn run(socket: UdpSocket, config: Arc<Args>, secret: String) {
+        let relay_ip = match socket.local_addr() {
+            Ok(addr) => addr.ip(),
+            Err(e) => {
+                warn!(error = ?e, "TURN socket has no local address");
+                return;
+            }
+        };
+        let mut nonce_key = [0u8; 20];
+        rand::thread_rng().fill_bytes(&mut nonce_key);
+        let (peer_packets, mut received) = mpsc::channel(PEER_PACKET_QUEUE);
+        let external_ip = config.turn_external_ip.unwrap_or(relay_ip);
+        let denied_peers = DeniedPeers {
+            own_ips: vec![relay_ip, external_ip, config.host.ip()],
+            allow_loopback: config.turn_allow_loopback_peers,
+        };
+        let mut server = Self {
+            socket,
+            external_ip,
+            denied_peers,
+            config,
+            secret,
+            relay_ip,
+            nonce_key,
+            allocations: HashMap::new(),
+            quotas: HashMap::new(),
+            peer_packets,
+        };
+
+        let mut buf = vec![0u8; 65536];
+        let mut expiry = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                (len, client) = stun::recv_datagram(&server.socket, &mut buf, "TURN") => {
+                    server.handle_client(&buf[..len], client).await
+                }
+                Some(packet) = received.recv() => server.handle_peer(packet).await,
+                _ = expiry.tick() => server.expire(Instant::now()),
+            }
+        }
+    }
+
+    async fn handle_client(&mut self, packet: &[u8], client: SocketAddr) {
+        // ChannelData messages start with 0b01, STUN messages with 0b00
+        if packet.first().is_some_and(|byte| byte & 0xC0 == 0x40) {
+            return self.handle_channel_data(packet, client).await;
+        }
+        let Some(message) = Message::parse(packet) else {
+            return;
+        };
+        let response = match (message.method, message.class) {
+            (METHOD_BINDING, Class::Request) => stun::respond(&message, client),
+            (METHOD_SEND, Class::Indication) => {
+                self.handle_send(&message, client).await;
+                None
+            }
+            (_, Class::Request) => match self.handle_request(&message, client) {
+                Ok(response) => Some(response),
+                Err(e) => {
+                    warn!(client = %client, error = %e, "Failed to encode TURN response");
+                    None
+                }
+            },
+            _ => None,
+        };
+        if let Some(response) = response {
+            self.send_to_client(&response, client).await;
+        }
+    }
+
+    /// Answer an authenticated request: Allocate, Refresh, CreatePermission or ChannelBind.
+    fn handle_request(
+        &mut self,
+        message: &Message,
+        client: SocketAddr,
+    ) -> Result<Vec<u8>, MessageTooLong> {
+        let method = message.method;
+        let transaction_id = message.transaction_id;
+        if !matches!(
+            method,
+            METHOD_ALLOCATE | METHOD_REFRESH | METHOD_CREATE_PERMISSION | METHOD_CHANNEL_BIND
+        ) {
+            return MessageBuilder::error(method, transaction_id, 400, "Bad Request").finish();
+        }
+        let unknown = message.unknown_attributes(KNOWN_ATTRIBUTES);
+        if !unknown.is_empty() {
+            let unknown: Vec<u8> = unknown.iter().flat_map(|kind| kind.to_be_bytes()).collect();
+            return MessageBuilder::error(method, transaction_id, 420, "Unknown Attribute")
+                .attribute(ATTR_UNKNOWN_ATTRIBUTES, &unknown)
+                .finish();
+        }
+        let (player_id, username, key) = match self.authenticate(message) {
+            Auth::Allowed {
+                player_id,
+                username,
+                key,
+            } => (player_id, username, key),
+            Auth::Refused(response) => return response,
+        };
+
+        if method == METHOD_ALLOCATE {
+            return self.allocate(message, client, player_id, username, key);
+        }
+        let now = Instant::now();
+        let Some(allocation) = self.allocations.get_mut(&client) else {
+            return MessageBuilder::error(method, transaction_id, 437, "Allocation Mismatch")
+                .finish_with_integrity(&key);
+        };
+        if allocation.username != username {
+            return MessageBuilder::error(method, transaction_id, 441, "Wrong Credentials")
+                .finish_with_integrity(&key);
+        }
+        let mut response = MessageBuilder::new(method, Class::Success, transaction_id);
+        match method {
+            METHOD_REFRESH => {
+                let lifetime = requested_lifetime(message);
+                response.u32_attribute(ATTR_LIFETIME, lifetime.as_secs() as u32);
+                if lifetime.is_zero() {
+                    self.remove_allocation(&client);
+                } else {
+                    allocation.expires_at = now + lifetime;
+                }
+            }
+            METHOD_CREATE_PERMISSION => {
+                let peers: Option<Vec<SocketAddr>> = message
+                    .attributes(ATTR_XOR_PEER_ADDRESS)
+                    .map(|value| stun::decode_xor_address(value, &transaction_id))
+                    .collect();
+                let peers = match peers {
+                    Some(peers) if !peers.is_empty() => peers,
+                    _ => {
+                        return MessageBuilder::error(method, transaction_id, 400, "Bad Request")
+                            .finish_with_integrity(&key)
+                    }
+                };
+                if peers
+                    .iter()
+                    .any(|peer| self.denied_peers.contains(peer.ip()))
+                {
+                    warn!(client = %client, ?peers, "Refused TURN permission for a denied peer");
+                    return MessageBuilder::error(method, transaction_id, 403, "Forbidden")
+                        .finish_with_integrity(&key);
+                }
+                for peer in peers {
+                    allocation
+                        .permissions
+                        .insert(peer.ip(), now + PERMISSION_LIFETIME);
+                }
+            }
+            METHOD_CHANNEL_BIND => {
+                let channel = message
+                    .attribute(ATTR_CHANNEL_NUMBER)
+                    .filter(|value| value.len() == 4)
+                    .map(|value| u16::from_be_bytes([value[0], value[1]]));
+                let peer = message.xor_address(ATTR_XOR_PEER_ADDRESS);
+                let (Some(channel), Some(peer)) = (channel, peer) else {
+                    return MessageBuilder::error(method, transaction_id, 400, "Bad Request")
+                        .finish_with_integrity(&key);
+                };
+                // A channel is bound to one peer and a peer to one channel
+                let taken = allocation
+                    .channels
+                    .get(&channel)
+                    .is_some_and(|(bound, _)| *bound != peer)
+                    || allocation
+                        .channel_of(&peer)
+                        .is_some_and(|bound| bound != channel);
+                if !CHANNEL_NUMBERS.contains(&channel) || taken {
+                    return MessageBuilder::error(method, transaction_id, 400, "Bad Request")
+                        .finish_with_integrity(&key);
+                }
+                if self.denied_peers.contains(peer.ip()) {
+                    warn!(client = %client, peer = %peer, "Refused TURN channel to a denied peer");
+                    return MessageBuilder::error(method, transaction_id, 403, "Forbidden")
+                        .finish_with_integrity(&key);
+                }
+                allocation
+                    .channels
+                    .insert(channel, (peer, now + CHANNEL_LIFETIME));
+                allocation
+                    .permissions
+                    .insert(peer.ip(), now + PERMISSION_LIFETIME);
+            }
+            _ => unreachable!("checked above"),
+        }
+        response.finish_with_integrity(&key)
+    }
+
+    /// Check the long-term credentials of a request. The password of a username is
+    /// [`turn_credential`] of it, and the username holds its expiry and the player, see
+    /// [`crate::ice::turn_username`].
+    fn authenticate(&self, message: &Message) -> Auth {
+        let method = message.method;
+        let transaction_id = message.transaction_id;
+        let realm = self.config.turn_realm.as_str();
+        let challenge = |code, reason| {
+            Auth::Refused(
+                MessageBuilder::error(method, transaction_id, code, reason)
+                    .attribute(ATTR_REALM, realm.as_bytes())
+                    .attribute(ATTR_NONCE, self.nonce(Utc::now().timestamp()).as_bytes())
+                    .finish(),
+            )
+        };
+        if !message.has_integrity() {
+            return challenge(401, "Unauthorized");
+        }
+        let (Some(username), Some(request_realm), Some(nonce)) = (
+            message.text(ATTR_USERNAME),
+            message.text(ATTR_REALM),
+            message.text(ATTR_NONCE),
+        ) else {
+            return Auth::Refused(
+                MessageBuilder::error(method, transaction_id, 400, "Bad Request").finish(),
+            );
+        };
+        if !self.nonce_is_fresh(nonce) {
+            return challenge(438, "Stale Nonce");
+        }
+        let now = Utc::now().timestamp();
+        let player_id = match username.split_once(':') {
+            Some((expiry, player_id)) if expiry.parse::<i64>().is_ok_and(|e| e > now) => player_id,
+            _ => return challenge(401, "Unauthorized"),
+        };
+        let password = turn_credential(&self.secret, username);
+        let key = md5::compute(format!("{username}:{request_realm}:{password}")).0;
+        if request_realm != realm || !message.check_integrity(&key) {
+            warn!(username, "Refused TURN request with wrong credentials");
+            return challenge(401, "Unauthorized");
+        }
+        Auth::Allowed {
+            player_id: player_id.to_string(),
+            username: username.to_string(),
+            key,
+        }
+    }
+
+    fn allocate(
+        &mut self,
+        message: &Message,
+        client: SocketAddr,
+        player_id: PlayerId,
+        username: String,
+        key: [u8; 16],
+    ) -> Result<Vec<u8>, MessageTooLong> {
+        let transaction_id = message.transaction_id;
+        let error = |code, reason| {
+            MessageBuilder::error(METHOD_ALLOCATE, transaction_id, code, reason)
+                .finish_with_integrity(&key)
+        };
+        if let Some(allocation) = self.allocations.get(&client) {
+            // A retransmission is answered again, anything else is a conflict
+            if allocation.transaction_id == transaction_id {
+                let lifetime = allocation
+                    .expires_at
+                    .saturating_duration_since(Instant::now());
+                return self.allocate_success(message, client, allocation, lifetime);
+            }
+            return error(437, "Allocation Mismatch");
+        }
+        match message.attribute(ATTR_REQUESTED_TRANSPORT) {
+            None => return error(400, "Bad Request"),
+            Some(value) if value.first() != Some(&UDP_TRANSPORT) => {
+                return error(442, "Unsupported Transport Protocol")
+            }
+            Some(_) => {}
+        }
+        let quota = self
+            .quotas
+            .entry(player_id.clone())
+            .or_insert_with(|| Quota {
+                allocations: 0,
+                bandwidth: TokenBucket::new(
+                    self.config.turn_bandwidth_bytes_per_sec,
+                    f64::from(self.config.turn_bandwidth_bytes_per_sec),
+                ),
+            });
+        if quota.allocations >= self.config.turn_max_allocations {
+            warn!(pubkey = %&player_id[..8.min(player_id.len())], "TURN allocation quota reached");
+            return error(486, "Allocation Quota Reached");
+        }
+        if self.allocations.len() >= self.config.turn_max_total_allocations {
+            warn!(
+                allocations = self.allocations.len(),
+                "TURN server allocation limit reached"
+            );
+            return error(508, "Insufficient Capacity");
+        }
+
+        let relay = match std::net::UdpSocket::bind((self.relay_ip, 0))
+            .and_then(|socket| socket.set_nonblocking(true).map(|()| socket))
+            .and_then(UdpSocket::from_std)
+        {
+            Ok(relay) => Arc::new(relay),
+            Err(e) => {
+                warn!(error = ?e, "Failed to bind a relayed socket");
+                return error(508, "Insufficient Capacity");
+            }
+        };
+        let relayed_address = match relay.local_addr() {
+            Ok(addr) => SocketAddr::new(self.external_ip, addr.port()),
+            Err(_) => return error(508, "Insufficient Capacity"),
+        };
+        quota.allocations += 1;
+        let reader = tokio::spawn(read_relayed(
+            relay.clone(),
+            client,
+            self.peer_packets.clone(),
+        ));
+        let lifetime = requested_lifetime(message).max(Duration::from_secs(1));
+        info!(client = %client, relayed = %relayed_address, pubkey = %&player_id[..8.min(player_id.len())], "TURN allocation created");
+        let allocation = Allocation {
+            player_id,
+            username,
+            key,
+            transaction_id,
+            relay,
+            relayed_address,
+            expires_at: Instant::now() + lifetime,
+            permissions: HashMap::new(),
+            channels: HashMap::new(),
+            reader,
+        };
+        let response = self.allocate_success(message, client, &allocation, lifetime);
+        self.allocations.insert(client, allocation);
+        response
+    }
+
+    fn allocate_success(
+        &self,
+        message: &Message,
+        client: SocketAddr,
+        allocation: &Allocation,
+        lifetime: Duration,
+    ) -> Result<Vec<u8>, MessageTooLong> {
+        MessageBuilder::new(METHOD_ALLOCATE, Class::Success, message.transaction_id)
+            .xor_address(ATTR_XOR_RELAYED_ADDRESS, allocation.relayed_address)
+            .xor_address(ATTR_XOR_MAPPED_ADDRESS, client)
+            .u32_attribute(ATTR_LIFETIME, lifetime.as_secs() as u32)
+            .software()
+            .finish_with_integrity(&allocation.key)
+    }
+
+    /// Relay the data of a Send indication to its peer.
+    async fn handle_send(&mut self, message: &Message<'_>, client: SocketAddr) {
+        let (Some(peer), Some(data)) = (
+            message.xor_address(ATTR_XOR_PEER_ADDRESS),
+            message.attribute(ATTR_DATA),
+        ) else {
+            return;
+        };
+        self.relay_to_peer(client, peer, data).await;
+    }
+
+    /// Relay a ChannelData message to the peer bound to its channel.
+    async fn handle_channel_data(&mut self, packet: &[u8], client: SocketAddr) {
+        if packet.len() < 4 {
+            return;
+        }
+        let channel = u16::from_be_bytes([packet[0], packet[1]]);
+        let len = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+        let Some(data) = packet.get(4..4 + len) else {
+            return;
+        };
+        let now = Instant::now();
+        let peer = self
+            .allocations
+            .get(&client)
+            .and_then(|allocation| allocation.channels.get(&channel))
+            .filter(|(_, expires_at)| *expires_at > now)
+            .map(|(peer, _)| *peer);
+        if let Some(peer) = peer {
+            self.relay_to_peer(client, peer, data).await;
+        }
+    }
+
+    async fn relay_to_peer(&mut self, client: SocketAddr, peer: SocketAddr, data: &[u8]) {
+        let Some(allocation) = self.allocations.get(&client) else {
+            return;
+        };
+        if !allocation.permits(&peer, Instant::now()) {
+            return;
+        }
+        let (player_id, relay) = (allocation.player_id.clone(), allocation.relay.clone());
+        if !self.take_bandwidth(&player_id, data.len()) {
+            return;
+        }
+        if let Err(e) = relay.send_to(data, peer).await {
+            debug!(peer = %peer, error = ?e, "Failed to relay to peer");
+        }
+    }
+
+    /// Relay a datagram from a peer to the client, over its channel if one is bound.
+    async fn handle_peer(&mut self, packet: PeerPacket) {
+        let Some(allocation) = self.allocations.get(&packet.client) else {
+            return;
+        };
+        // Datagrams from peers without a permission are dropped silently
+        if !allocation.permits(&packet.peer, Instant::now()) {
+            return;
+        }
+        let message = match allocation.channel_of(&packet.peer) {
+            Some(channel) => {
+                let mut message = Vec::with_capacity(4 + packet.data.len());
+                message.extend_from_slice(&channel.to_be_bytes());
+                message.extend_from_slice(&(packet.data.len() as u16).to_be_bytes());
+                message.extend_from_slice(&packet.data);
+                message
+            }
+            None => {
+                let mut transaction_id = [0u8; 12];
+                rand::thread_rng().fill_bytes(&mut transaction_id);
+                let indication =
+                    MessageBuilder::new(METHOD_DATA, Class::Indication, transaction_id)
+                        .xor_address(ATTR_XOR_PEER_ADDRESS, packet.peer)
+                        .attribute(ATTR_DATA, &packet.data)
+                        .finish();
+                // The largest datagrams do not fit in a Data indication, only in ChannelData
+                match indication {
+                    Ok(indication) => indication,
+                    Err(_) => {
+                        debug!(peer = %packet.peer, len = packet.data.len(), "Dropped a datagram too large for a Data indication");
+                        return;
+                    }
+                }
+            }
+        };
+        let player_id = allocation.player_id.clone();
+        if self.take_bandwidth(&player_id, packet.data.len()) {
+            self.send_to_client(&message, packet.client).await;
+        }
+    }
+
+    /// Count relayed bytes against the player's bandwidth quota.
+    fn take_bandwidth(&mut self, player_id: &str, bytes: usize) -> bool {
+        let Some(quota) = self.quotas.get_mut(player_id) else {
+            return false;
+        };
+        let allowed = quota
+            .bandwidth
+            .try_take_n(u32::try_from(bytes).unwrap_or(u32::MAX));
+        if !allowed {
+            debug!(pubkey = %&player_id[..8.min(player_id.len())], bytes, "TURN bandwidth quota exceeded");
+        }
+        allowed
+    }
+
+    async fn send_to_client(&self, message: &[u8], client: SocketAddr) {
+        if let Err(e) = self.socket.send_to(message, client).await {
+            debug!(client = %client, error = ?e, "Failed to send to TURN client");
+        }
+    }
+
+    fn remove_allocation(&mut self, client: &SocketAddr) {
+        let Some(allocation) = self.allocations.remove(client) else {
+            return;
+        };
+        allocation.reader.abort();
+        if let Some(quota) = self.quotas.get_mut(&allocation.player_id) {
+            quota.allocations -= 1;
+        }
+        info!(client = %client, relayed = %allocation.relayed_address, "TURN allocation removed");
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let expired: Vec<SocketAddr> = self
+            .allocations
+            .iter()
+            .filter(|(_, allocation)| allocation.expires_at <= now)
+            .map(|(client, _)| *client)
+            .collect();
+        for client in expired {
+            self.remove_allocation(&client);
+        }
+        for allocation in self.allocations.values_mut() {
+            allocation
+                .permissions
+                .retain(|_, expires_at| *expires_at > now);
+            allocation
+                .channels
+                .retain(|_, (_, expires_at)| *expires_at > now);
+        }
+        self.quotas.retain(|_, quota| !quota.is_idle());
+    }
+
+    /// A nonce issued at `issued_at`, a Unix timestamp, tagged so that the server can check it
+    /// without remembering it.
+    fn nonce(&self, issued_at: i64) -> String {
+        let mut mac =
+            Hmac::<Sha1>::new_from_slice(&self.nonce_key).expect("HMAC takes keys of any size");
+        mac.update(&issued_at.to_be_bytes());
+        let mut nonce = format!("{issued_at:x}.");
+        for byte in &mac.finalize().into_bytes()[..8] {
+            let _ = write!(nonce, "{byte:02x}");
+        }
+        nonce
+    }
+
+    fn nonce_is_fresh(&self, nonce: &str) -> bool {
+        let Some(issued_at) = nonce
+            .split_once('.')
+            .and_then(|(issued_at, _)| i64::from_str_radix(issued_at, 16).ok())
+        else {
+            return false;
+        };
+        let age = Utc::now().timestamp() - issued_at;
+        self.nonce(issued_at) == nonce && (0..NONCE_LIFETIME.as_secs() as i64).contains(&age)
+    }
+}
+
+/// Lifetime asked for in a request, capped, or the default one
+fn requested_lifetime(message: &Message) -> Duration {
+    message
+        .attribute(ATTR_LIFETIME)
+        .and_then(|value| <[u8; 4]>::try_from(value).ok())
+        .map(|value| Duration::from_secs(u64::from(u32::from_be_bytes(value))))
+        .unwrap_or(DEFAULT_LIFETIME)
+        .min(MAX_LIFETIME)
+}
+
+/// Hand whatever peers send to a relayed socket to the TURN server task, dropping it when the
+/// task is behind.
+async fn read_relayed(
+    relay: Arc<UdpSocket>,
+    client: SocketAddr,
+    peer_packets: mpsc::Sender<PeerPacket>,
+) {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (len, peer) = stun::recv_datagram(&relay, &mut buf, "TURN relayed").await;
+        let packet = PeerPacket {
+            client,
+            peer,
+            data: buf[..len].to_vec(),
+        };
+        match peer_packets.try_send(packet) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                debug!(client = %client, "Dropped a datagram from a peer, the relay is behind");
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => return,
+        }
+    }
+}
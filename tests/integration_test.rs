@@ -1,22 +1,56 @@
-use matchbox_server::helpers;
+use base64::{engine::general_purpose, Engine as _};
+use hmac::{Hmac, Mac};
+use matchbox_server::chat::{ChatFilter, WordListFilter};
+use matchbox_server::{args::Args, helpers, stun, turn};
 use reqwest::Client;
 use serde_json::{json, Value};
 use serial_test::serial;
+use sha1::Sha1;
 use std::net::SocketAddr;
 use tokio::net::TcpListener;
 use tokio::time::{sleep, Duration};
 
 async fn spawn_app() -> SocketAddr {
+    spawn_app_with_args(Args::default()).await
+}
+
+async fn spawn_app_with_args(mut args: Args) -> SocketAddr {
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
     let addr = listener.local_addr().unwrap();
     drop(listener);
+    args.host = addr;
     tokio::spawn(async move {
-        matchbox_server::run(addr).await.unwrap();
+        matchbox_server::run_with_args(args).await.unwrap();
     });
     sleep(Duration::from_millis(100)).await;
     addr
 }
 
+async fn authenticate_and_get_token(addr: SocketAddr, username: &str, password: &str) -> String {
+    let client = Client::new();
+
+    // Get challenge
+    let response = client
+        .post(format!("http://{}/auth/challenge", addr))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = response.json().await.unwrap();
+    let challenge = body["challenge"].as_str().unwrap();
+
+    // Login
+    let login_payload = helpers::generate_login_payload(username, password, challenge).unwrap();
+    let response = client
+        .post(format!("http://{}/auth/login", addr))
+        .header("Content-Type", "application/json")
+        .body(login_payload)
+        .send()
+        .await
+        .unwrap();
+    let body: Value = response.json().await.unwrap();
+    body["token"].as_str().unwrap().to_string()
+}
+
 #[tokio::test]
 #[serial]
 async fn test_authentication_flow() {
@@ -844,3 +878,2153 @@ async fn test_lobby_without_whitelist_allows_all_players() {
         .unwrap();
     assert_eq!(response.status().as_u16(), 200);
 }
+
+#[tokio::test]
+#[serial]
+async fn test_invite_accept_flow() {
+    let addr = spawn_app().await;
+    let client = Client::new();
+
+    let token_owner = authenticate_and_get_token(addr, "owner", "pass").await;
+    let token_guest = authenticate_and_get_token(addr, "guest", "pass").await;
+    let pubkey_owner = helpers::get_public_key("owner", "pass").unwrap();
+    let pubkey_guest = helpers::get_public_key("guest", "pass").unwrap();
+
+    // Private lobby whose whitelist only contains the owner
+    let response = client
+        .post(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({ "is_private": true, "whitelist": [pubkey_owner] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: Value = response.json().await.unwrap();
+    let lobby_id = body["id"].as_str().unwrap().to_string();
+
+    // Guest cannot join yet
+    let response = client
+        .post(format!("http://{}/lobbies/{}/join", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+
+    // Owner invites the guest
+    let response = client
+        .post(format!("http://{}/lobbies/{}/invites", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({ "invitee": pubkey_guest }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    // Guest sees the pending invite
+    let response = client
+        .get(format!("http://{}/invites", addr))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let invites: Vec<Value> = response.json().await.unwrap();
+    assert_eq!(invites.len(), 1);
+    assert_eq!(invites[0]["lobby_id"].as_str().unwrap(), lobby_id);
+    let invite_id = invites[0]["id"].as_str().unwrap();
+
+    // Accepting whitelists the guest and joins the lobby
+    let response = client
+        .post(format!("http://{}/invites/{}/accept", addr, invite_id))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let lobby: Value = response.json().await.unwrap();
+    let players = lobby["players"].as_array().unwrap();
+    assert!(players
+        .iter()
+        .any(|p| p.as_str() == Some(pubkey_guest.as_str())));
+    let whitelist = lobby["whitelist"].as_array().unwrap();
+    assert!(whitelist
+        .iter()
+        .any(|p| p.as_str() == Some(pubkey_guest.as_str())));
+
+    // The invite is consumed
+    let response = client
+        .post(format!("http://{}/invites/{}/accept", addr, invite_id))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_invite_expires_after_ttl() {
+    let addr = spawn_app_with_args(Args {
+        invite_ttl_secs: 1,
+        ..Args::default()
+    })
+    .await;
+    let client = Client::new();
+
+    let token_owner = authenticate_and_get_token(addr, "owner", "pass").await;
+    let token_guest = authenticate_and_get_token(addr, "guest", "pass").await;
+    let pubkey_guest = helpers::get_public_key("guest", "pass").unwrap();
+
+    let response = client
+        .post(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({ "is_private": true }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = response.json().await.unwrap();
+    let lobby_id = body["id"].as_str().unwrap().to_string();
+
+    let response = client
+        .post(format!("http://{}/lobbies/{}/invites", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({ "invitee": pubkey_guest }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let invite: Value = response.json().await.unwrap();
+    let invite_id = invite["id"].as_str().unwrap();
+
+    sleep(Duration::from_millis(1100)).await;
+
+    // The invite expired and is dropped
+    let response = client
+        .post(format!("http://{}/invites/{}/accept", addr, invite_id))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 410);
+    let response = client
+        .post(format!("http://{}/invites/{}/accept", addr, invite_id))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_invite_accept_refused_keeps_invite() {
+    let addr = spawn_app().await;
+    let client = Client::new();
+
+    let token_owner = authenticate_and_get_token(addr, "owner", "pass").await;
+    let token_guest = authenticate_and_get_token(addr, "guest", "pass").await;
+    let pubkey_guest = helpers::get_public_key("guest", "pass").unwrap();
+
+    let response = client
+        .post(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({ "is_private": true, "max_players": 1 }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = response.json().await.unwrap();
+    let lobby_id = body["id"].as_str().unwrap().to_string();
+
+    let response = client
+        .post(format!("http://{}/lobbies/{}/invites", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({ "invitee": pubkey_guest }))
+        .send()
+        .await
+        .unwrap();
+    let invite: Value = response.json().await.unwrap();
+    let invite_id = invite["id"].as_str().unwrap();
+
+    // The lobby is full: accepting fails but the invite survives
+    let response = client
+        .post(format!("http://{}/invites/{}/accept", addr, invite_id))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 409);
+    let response = client
+        .get(format!("http://{}/invites", addr))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .send()
+        .await
+        .unwrap();
+    let invites: Vec<Value> = response.json().await.unwrap();
+    assert_eq!(invites.len(), 1);
+
+    // An invite does not get a player into a started game
+    let response = client
+        .post(format!("http://{}/lobbies/{}/start", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let response = client
+        .post(format!("http://{}/invites/{}/accept", addr, invite_id))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 409);
+    assert_eq!(response.text().await.unwrap(), "Lobby already started");
+}
+
+#[tokio::test]
+#[serial]
+async fn test_invites_are_validated_and_capped() {
+    use clap::Parser;
+    use matchbox_server::{args::MAX_INVITE_TTL_SECS, lobby::MAX_PENDING_INVITES};
+
+    let too_long = (MAX_INVITE_TTL_SECS + 1).to_string();
+    assert!(Args::try_parse_from(["made_in_heaven", "--invite-ttl-secs", &too_long]).is_err());
+
+    let addr = spawn_app().await;
+    let client = Client::new();
+    let token_owner = authenticate_and_get_token(addr, "owner", "pass").await;
+    let response = client
+        .post(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({ "is_private": true }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = response.json().await.unwrap();
+    let lobby_id = body["id"].as_str().unwrap().to_string();
+    let invite = |invitee: String| {
+        client
+            .post(format!("http://{}/lobbies/{}/invites", addr, lobby_id))
+            .header("Authorization", format!("Bearer {}", token_owner))
+            .json(&json!({ "invitee": invitee }))
+            .send()
+    };
+
+    let response = invite("not a key".to_string()).await.unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+
+    let pubkeys: Vec<String> = (0..=MAX_PENDING_INVITES)
+        .map(|i| helpers::get_public_key(&format!("guest_{i}"), "pass").unwrap())
+        .collect();
+    for pubkey in &pubkeys[..MAX_PENDING_INVITES] {
+        let response = invite(pubkey.clone()).await.unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    let response = invite(pubkeys[MAX_PENDING_INVITES].clone()).await.unwrap();
+    assert_eq!(response.status().as_u16(), 409);
+    // Inviting someone again replaces their invite
+    let response = invite(pubkeys[0].clone()).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_out_of_range_invite_ttl_is_an_error() {
+    let addr = spawn_app_with_args(Args {
+        invite_ttl_secs: u64::MAX,
+        ..Args::default()
+    })
+    .await;
+    let client = Client::new();
+    let token_owner = authenticate_and_get_token(addr, "owner", "pass").await;
+    let pubkey_guest = helpers::get_public_key("guest", "pass").unwrap();
+    let response = client
+        .post(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({ "is_private": true }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = response.json().await.unwrap();
+    let lobby_id = body["id"].as_str().unwrap();
+
+    let response = client
+        .post(format!("http://{}/lobbies/{}/invites", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({ "invitee": pubkey_guest }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 500);
+
+    // The server is still serving
+    let response = client
+        .get(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_invite_decline_and_owner_only() {
+    let addr = spawn_app().await;
+    let client = Client::new();
+
+    let token_owner = authenticate_and_get_token(addr, "owner", "pass").await;
+    let token_guest = authenticate_and_get_token(addr, "guest", "pass").await;
+    let pubkey_guest = helpers::get_public_key("guest", "pass").unwrap();
+    let pubkey_other = helpers::get_public_key("other", "pass").unwrap();
+
+    let response = client
+        .post(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({ "is_private": true }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = response.json().await.unwrap();
+    let lobby_id = body["id"].as_str().unwrap().to_string();
+
+    // Only the owner can invite
+    let response = client
+        .post(format!("http://{}/lobbies/{}/invites", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .json(&json!({ "invitee": pubkey_other }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+
+    let response = client
+        .post(format!("http://{}/lobbies/{}/invites", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({ "invitee": pubkey_guest }))
+        .send()
+        .await
+        .unwrap();
+    let invite: Value = response.json().await.unwrap();
+    let invite_id = invite["id"].as_str().unwrap();
+
+    // Owner cannot act on an invite addressed to someone else
+    let response = client
+        .post(format!("http://{}/invites/{}/decline", addr, invite_id))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+
+    let response = client
+        .post(format!("http://{}/invites/{}/decline", addr, invite_id))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    let response = client
+        .get(format!("http://{}/invites", addr))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .send()
+        .await
+        .unwrap();
+    let invites: Vec<Value> = response.json().await.unwrap();
+    assert!(invites.is_empty());
+}
+
+#[tokio::test]
+#[serial]
+async fn test_owner_edits_whitelist_and_privacy() {
+    let addr = spawn_app().await;
+    let client = Client::new();
+
+    let token_owner = authenticate_and_get_token(addr, "owner", "pass").await;
+    let token_guest = authenticate_and_get_token(addr, "guest", "pass").await;
+    let pubkey_owner = helpers::get_public_key("owner", "pass").unwrap();
+    let pubkey_guest = helpers::get_public_key("guest", "pass").unwrap();
+
+    let response = client
+        .post(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({ "is_private": true, "whitelist": [pubkey_owner] }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = response.json().await.unwrap();
+    let lobby_id = body["id"].as_str().unwrap().to_string();
+
+    // Non-owners cannot edit the whitelist
+    let response = client
+        .post(format!("http://{}/lobbies/{}/whitelist", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .json(&json!({ "pubkeys": [pubkey_guest] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+
+    // Owner adds the guest, who can then discover and join
+    let response = client
+        .post(format!("http://{}/lobbies/{}/whitelist", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({ "pubkeys": [pubkey_guest] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    let response = client
+        .get(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .send()
+        .await
+        .unwrap();
+    let lobbies: Vec<Value> = response.json().await.unwrap();
+    assert_eq!(lobbies.len(), 1);
+
+    let response = client
+        .post(format!("http://{}/lobbies/{}/join", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    // The owner cannot be removed from their own whitelist
+    let response = client
+        .delete(format!("http://{}/lobbies/{}/whitelist", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({ "pubkeys": [pubkey_owner] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+
+    // Removing the guest drops them from the lobby
+    let response = client
+        .delete(format!("http://{}/lobbies/{}/whitelist", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({ "pubkeys": [pubkey_guest] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let lobby: Value = response.json().await.unwrap();
+    let players = lobby["players"].as_array().unwrap();
+    assert_eq!(players.len(), 1);
+    assert_eq!(players[0].as_str().unwrap(), pubkey_owner);
+
+    // Switch the lobby to public
+    let response = client
+        .put(format!("http://{}/lobbies/{}/privacy", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({ "is_private": false }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let lobby: Value = response.json().await.unwrap();
+    assert!(!lobby["is_private"].as_bool().unwrap());
+}
+
+#[tokio::test]
+#[serial]
+async fn test_lobby_metadata() {
+    let addr = spawn_app().await;
+    let client = Client::new();
+
+    let token_owner = authenticate_and_get_token(addr, "owner", "pass").await;
+    let token_guest = authenticate_and_get_token(addr, "guest", "pass").await;
+
+    let response = client
+        .post(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({
+            "is_private": false,
+            "name": "Friday night",
+            "game_id": "space_race",
+            "game_version": "1.2.0",
+            "mode": "ctf",
+            "attributes": { "map": "nebula" }
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: Value = response.json().await.unwrap();
+    let lobby_id = body["id"].as_str().unwrap().to_string();
+
+    // Metadata is visible to other players browsing lobbies
+    let response = client
+        .get(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .send()
+        .await
+        .unwrap();
+    let lobbies: Vec<Value> = response.json().await.unwrap();
+    assert_eq!(lobbies.len(), 1);
+    assert_eq!(lobbies[0]["name"], "Friday night");
+    assert_eq!(lobbies[0]["game_id"], "space_race");
+    assert_eq!(lobbies[0]["game_version"], "1.2.0");
+    assert_eq!(lobbies[0]["mode"], "ctf");
+    assert_eq!(lobbies[0]["attributes"]["map"], "nebula");
+
+    // Only the owner can update it
+    let update = json!({ "name": "Saturday night", "mode": "deathmatch" });
+    let response = client
+        .put(format!("http://{}/lobbies/{}/metadata", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .json(&update)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+
+    let response = client
+        .put(format!("http://{}/lobbies/{}/metadata", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&update)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let lobby: Value = response.json().await.unwrap();
+    assert_eq!(lobby["name"], "Saturday night");
+    assert_eq!(lobby["mode"], "deathmatch");
+    assert!(lobby["game_id"].is_null());
+
+    // Oversized metadata is rejected
+    let attributes: serde_json::Map<String, Value> = (0..17)
+        .map(|i| (format!("key{i}"), Value::String("v".to_string())))
+        .collect();
+    let response = client
+        .put(format!("http://{}/lobbies/{}/metadata", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({ "attributes": attributes }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_lobby_filtering_sorting_and_pagination() {
+    let addr = spawn_app().await;
+    let client = Client::new();
+
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let token_c = authenticate_and_get_token(addr, "player_c", "pass_c").await;
+    let pubkey_b = helpers::get_public_key("player_b", "pass_b").unwrap();
+
+    let lobbies = [
+        (
+            &token_a,
+            json!({ "is_private": false, "game_id": "racer", "max_players": 2, "attributes": { "map": "dunes" } }),
+        ),
+        (
+            &token_b,
+            json!({ "is_private": false, "game_id": "racer", "max_players": 4, "attributes": { "map": "city" } }),
+        ),
+        (
+            &token_c,
+            json!({ "is_private": false, "game_id": "shooter" }),
+        ),
+    ];
+    let mut ids = Vec::new();
+    for (token, body) in lobbies {
+        let response = client
+            .post(format!("http://{}/lobbies", addr))
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        let body: Value = response.json().await.unwrap();
+        ids.push(body["id"].as_str().unwrap().to_string());
+    }
+
+    let list = |query: Vec<(&'static str, String)>| {
+        let client = client.clone();
+        async move {
+            let response = client
+                .get(format!("http://{}/lobbies", addr))
+                .query(&query)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status().as_u16(), 200);
+            let cursor = response
+                .headers()
+                .get("x-next-cursor")
+                .map(|v| v.to_str().unwrap().to_string());
+            let lobbies: Vec<Value> = response.json().await.unwrap();
+            let ids: Vec<String> = lobbies
+                .iter()
+                .map(|l| l["id"].as_str().unwrap().to_string())
+                .collect();
+            (ids, cursor)
+        }
+    };
+
+    // Default ordering is newest first
+    let (all, _) = list(vec![]).await;
+    assert_eq!(all, vec![ids[2].clone(), ids[1].clone(), ids[0].clone()]);
+
+    let (racer, _) = list(vec![("game_id", "racer".into()), ("order", "asc".into())]).await;
+    assert_eq!(racer, vec![ids[0].clone(), ids[1].clone()]);
+
+    let (city, _) = list(vec![("attributes", "map:city".into())]).await;
+    assert_eq!(city, vec![ids[1].clone()]);
+
+    // Lobby A has one of two seats taken; B has three free; C is unbounded
+    let (roomy, _) = list(vec![("min_free_slots", "2".into())]).await;
+    assert_eq!(roomy, vec![ids[2].clone(), ids[1].clone()]);
+
+    let (friends, _) = list(vec![("friends", pubkey_b.clone())]).await;
+    assert_eq!(friends, vec![ids[1].clone()]);
+
+    // Player D joins B's lobby, making it the most populated
+    let token_d = authenticate_and_get_token(addr, "player_d", "pass_d").await;
+    let response = client
+        .post(format!("http://{}/lobbies/{}/join", addr, ids[1]))
+        .header("Authorization", format!("Bearer {}", token_d))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let (by_players, _) = list(vec![("sort", "player_count".into())]).await;
+    assert_eq!(by_players[0], ids[1]);
+
+    // Walk all pages one lobby at a time
+    let mut seen = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let mut query = vec![("limit", "1".to_string())];
+        if let Some(cursor) = cursor.take() {
+            query.push(("cursor", cursor));
+        }
+        let (page, next) = list(query).await;
+        assert_eq!(page.len(), 1);
+        seen.extend(page);
+        match next {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+    assert_eq!(seen, all);
+
+    let response = client
+        .get(format!("http://{}/lobbies?cursor=garbage", addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_full_lobby_rejects_join() {
+    let addr = spawn_app().await;
+    let client = Client::new();
+
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+
+    let response = client
+        .post(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token_a))
+        .json(&json!({ "is_private": false, "max_players": 1 }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = response.json().await.unwrap();
+    let lobby_id = body["id"].as_str().unwrap();
+
+    let response = client
+        .post(format!("http://{}/lobbies/{}/join", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_b))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 409);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_role_switch_inside_a_lobby_is_refused() {
+    let addr = spawn_app().await;
+    let client = Client::new();
+
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let token_c = authenticate_and_get_token(addr, "player_c", "pass_c").await;
+
+    let response = client
+        .post(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token_a))
+        .json(&json!({ "is_private": false }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = response.json().await.unwrap();
+    let lobby_id = body["id"].as_str().unwrap();
+    let join = |token: &str, body: Value| {
+        client
+            .post(format!("http://{}/lobbies/{}/join", addr, lobby_id))
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&body)
+            .send()
+    };
+
+    // The owner is told why they cannot spectate
+    let response = join(&token_a, json!({ "spectate": true })).await.unwrap();
+    assert_eq!(response.status().as_u16(), 409);
+    assert_eq!(
+        response.text().await.unwrap(),
+        "The lobby owner cannot spectate"
+    );
+
+    // A player stays a player, a spectator a spectator
+    let response = join(&token_b, json!({})).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let response = join(&token_b, json!({ "spectate": true })).await.unwrap();
+    assert_eq!(response.status().as_u16(), 409);
+    let response = join(&token_c, json!({ "spectate": true })).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let response = join(&token_c, json!({})).await.unwrap();
+    assert_eq!(response.status().as_u16(), 409);
+
+    // Joining again with the same role is fine
+    let response = join(&token_b, json!({})).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let response = join(&token_c, json!({ "spectate": true })).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    let response = client
+        .get(format!("http://{}/lobbies", addr))
+        .send()
+        .await
+        .unwrap();
+    let lobbies: Vec<Value> = response.json().await.unwrap();
+    let lobby = &lobbies[0];
+    assert_eq!(lobby["players"].as_array().unwrap().len(), 2);
+    assert_eq!(lobby["spectators"].as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_password_protected_lobby() {
+    let addr = spawn_app().await;
+    let client = Client::new();
+
+    let token_owner = authenticate_and_get_token(addr, "owner", "pass").await;
+    let token_guest = authenticate_and_get_token(addr, "guest", "pass").await;
+
+    let response = client
+        .post(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({ "is_private": false, "password": "hunter2" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: Value = response.json().await.unwrap();
+    let lobby_id = body["id"].as_str().unwrap().to_string();
+    assert!(body.get("password").is_none());
+
+    // Listings only reveal that a password is required
+    let response = client
+        .get(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .send()
+        .await
+        .unwrap();
+    let lobbies: Vec<Value> = response.json().await.unwrap();
+    assert_eq!(lobbies[0]["has_password"], true);
+    assert!(!lobbies[0].to_string().contains("argon2"));
+
+    // Missing and wrong passwords are rejected
+    let response = client
+        .post(format!("http://{}/lobbies/{}/join", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+
+    let response = client
+        .post(format!("http://{}/lobbies/{}/join", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .json(&json!({ "password": "hunter3" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+
+    let response = client
+        .post(format!("http://{}/lobbies/{}/join", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .json(&json!({ "password": "hunter2" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_lobby_password_attempt_limit() {
+    let addr = spawn_app().await;
+    let client = Client::new();
+
+    let token_owner = authenticate_and_get_token(addr, "owner", "pass").await;
+    let token_guest = authenticate_and_get_token(addr, "guest", "pass").await;
+
+    let response = client
+        .post(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({ "is_private": false, "password": "hunter2" }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = response.json().await.unwrap();
+    let lobby_id = body["id"].as_str().unwrap().to_string();
+
+    for _ in 0..5 {
+        let response = client
+            .post(format!("http://{}/lobbies/{}/join", addr, lobby_id))
+            .header("Authorization", format!("Bearer {}", token_guest))
+            .json(&json!({ "password": "guess" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 403);
+    }
+
+    // Out of attempts: even the right password is refused for now
+    let response = client
+        .post(format!("http://{}/lobbies/{}/join", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .json(&json!({ "password": "hunter2" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 429);
+
+    // The attempts belong to the client address, a fresh key doesn't get new ones
+    let token_other = authenticate_and_get_token(addr, "other", "pass").await;
+    let response = client
+        .post(format!("http://{}/lobbies/{}/join", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_other))
+        .json(&json!({ "password": "hunter2" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 429);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_password_attempts_honour_forwarded_for_only_from_trusted_proxies() {
+    async fn attempt_statuses(args: Args) -> Vec<u16> {
+        let addr = spawn_app_with_args(args).await;
+        let client = Client::new();
+        let token_owner = authenticate_and_get_token(addr, "owner", "pass").await;
+        let token_guest = authenticate_and_get_token(addr, "guest", "pass").await;
+        let response = client
+            .post(format!("http://{}/lobbies", addr))
+            .header("Authorization", format!("Bearer {}", token_owner))
+            .json(&json!({ "is_private": false, "password": "hunter2" }))
+            .send()
+            .await
+            .unwrap();
+        let body: Value = response.json().await.unwrap();
+        let lobby_id = body["id"].as_str().unwrap().to_string();
+
+        // Every attempt claims to come from another client
+        let mut statuses = Vec::new();
+        for i in 0..6 {
+            let response = client
+                .post(format!("http://{}/lobbies/{}/join", addr, lobby_id))
+                .header("Authorization", format!("Bearer {}", token_guest))
+                .header("X-Forwarded-For", format!("203.0.113.{i}"))
+                .json(&json!({ "password": "guess" }))
+                .send()
+                .await
+                .unwrap();
+            statuses.push(response.status().as_u16());
+        }
+        statuses
+    }
+
+    // From an untrusted peer the header is ignored, all attempts count against 127.0.0.1
+    let statuses = attempt_statuses(Args::default()).await;
+    assert_eq!(statuses, [403, 403, 403, 403, 403, 429]);
+
+    // Behind a trusted proxy each forwarded client gets its own attempts
+    let statuses = attempt_statuses(Args {
+        trusted_proxies: vec!["127.0.0.1".parse().unwrap()],
+        ..Args::default()
+    })
+    .await;
+    assert_eq!(statuses, [403; 6]);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_create_then_join_elsewhere_moves_player() {
+    let addr = spawn_app().await;
+    let client = Client::new();
+
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let pubkey_a = helpers::get_public_key("player_a", "pass_a").unwrap();
+
+    let mut lobby_ids = Vec::new();
+    for token in [&token_a, &token_b] {
+        let response = client
+            .post(format!("http://{}/lobbies", addr))
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&json!({ "is_private": false }))
+            .send()
+            .await
+            .unwrap();
+        let body: Value = response.json().await.unwrap();
+        lobby_ids.push(body["id"].as_str().unwrap().to_string());
+    }
+
+    // Player A created the first lobby and now joins B's
+    let response = client
+        .post(format!("http://{}/lobbies/{}/join", addr, lobby_ids[1]))
+        .header("Authorization", format!("Bearer {}", token_a))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    let response = client
+        .get(format!("http://{}/lobbies", addr))
+        .send()
+        .await
+        .unwrap();
+    let lobbies: Vec<Value> = response.json().await.unwrap();
+    // The lobby A left empty is gone
+    assert!(lobbies.iter().all(|l| l["id"] != lobby_ids[0].as_str()));
+    let players_of = |id: &str| -> Vec<String> {
+        lobbies.iter().find(|l| l["id"] == id).unwrap()["players"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p.as_str().unwrap().to_string())
+            .collect()
+    };
+    let players = players_of(&lobby_ids[1]);
+    assert_eq!(players.len(), 2);
+    assert!(players.contains(&pubkey_a));
+
+    // A no longer owns anything there
+    let response = client
+        .post(format!("http://{}/lobbies/{}/invites", addr, lobby_ids[0]))
+        .header("Authorization", format!("Bearer {}", token_a))
+        .json(&json!({ "invitee": pubkey_a }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+
+    // Creating yet another lobby moves A again
+    let response = client
+        .post(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token_a))
+        .json(&json!({ "is_private": false }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let response = client
+        .get(format!("http://{}/lobbies", addr))
+        .send()
+        .await
+        .unwrap();
+    let lobbies: Vec<Value> = response.json().await.unwrap();
+    let seats: usize = lobbies
+        .iter()
+        .filter(|l| {
+            l["players"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|p| p.as_str() == Some(pubkey_a.as_str()))
+        })
+        .count();
+    assert_eq!(seats, 1);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_min_players_must_fit_max_players() {
+    let addr = spawn_app().await;
+    let client = Client::new();
+    let token = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+
+    for (body, expected) in [
+        (json!({ "is_private": false, "min_players": 0 }), 400),
+        (
+            json!({ "is_private": false, "min_players": 5, "max_players": 4 }),
+            400,
+        ),
+        // Two teams of two hold four players
+        (
+            json!({ "is_private": false, "min_players": 5, "team_count": 2, "team_size": 2 }),
+            400,
+        ),
+        (
+            json!({ "is_private": false, "min_players": 4, "team_count": 2, "team_size": 2 }),
+            200,
+        ),
+        (json!({ "is_private": false, "min_players": 3 }), 200),
+    ] {
+        let response = client
+            .post(format!("http://{}/lobbies", addr))
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), expected, "{body}");
+    }
+}
+
+#[tokio::test]
+#[serial]
+async fn test_team_picks_and_balancing() {
+    let addr = spawn_app().await;
+    let client = Client::new();
+
+    // Team layouts are validated
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    for body in [
+        json!({ "is_private": false, "team_count": 2 }),
+        json!({ "is_private": false, "team_count": 0, "team_size": 2 }),
+        json!({ "is_private": false, "team_count": 2, "team_size": 2, "max_players": 5 }),
+    ] {
+        let response = client
+            .post(format!("http://{}/lobbies", addr))
+            .header("Authorization", format!("Bearer {}", token_a))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 400, "{body}");
+    }
+
+    let response = client
+        .post(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token_a))
+        .json(&json!({ "is_private": false, "team_count": 2, "team_size": 2 }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: Value = response.json().await.unwrap();
+    let lobby_id = body["id"].as_str().unwrap().to_string();
+    assert_eq!(body["max_players"], 4);
+    assert_eq!(
+        body["teams"],
+        json!({ "count": 2, "size": 2, "rosters": [[], []] })
+    );
+
+    let players = [
+        ("player_a", "pass_a", 1500),
+        ("player_b", "pass_b", 1400),
+        ("player_c", "pass_c", 1000),
+        ("player_d", "pass_d", 900),
+    ];
+    let mut tokens = Vec::new();
+    for (username, password, rating) in players {
+        let token = authenticate_and_get_token(addr, username, password).await;
+        if username != "player_a" {
+            let response = client
+                .post(format!("http://{}/lobbies/{}/join", addr, lobby_id))
+                .header("Authorization", format!("Bearer {}", token))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status().as_u16(), 200);
+        }
+        // Players cannot rate themselves, only the owner rates them
+        let response = client
+            .patch(format!("http://{}/lobbies/{}/members/me", addr, lobby_id))
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&json!({ "rating": 5000 }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 422);
+        let pubkey = helpers::get_public_key(username, password).unwrap();
+        let rate = |token: &String, rating: i32| {
+            client
+                .put(format!("http://{}/lobbies/{}/ratings", addr, lobby_id))
+                .header("Authorization", format!("Bearer {}", token))
+                .json(&json!({ "pubkey": pubkey, "rating": rating }))
+                .send()
+        };
+        if !tokens.is_empty() {
+            let response = rate(&token, 5000).await.unwrap();
+            assert_eq!(response.status().as_u16(), 403);
+        }
+        let owner_token = tokens.first().unwrap_or(&token);
+        let response = rate(owner_token, rating).await.unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        let response = client
+            .patch(format!("http://{}/lobbies/{}/members/me", addr, lobby_id))
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&json!({ "team": 0 }))
+            .send()
+            .await
+            .unwrap();
+        // Team 0 only has two seats
+        let expected = if tokens.len() < 2 { 200 } else { 409 };
+        assert_eq!(response.status().as_u16(), expected, "{username}");
+        tokens.push(token);
+    }
+
+    let response = client
+        .patch(format!("http://{}/lobbies/{}/members/me", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", tokens[2]))
+        .json(&json!({ "team": 2 }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+
+    let response = client
+        .post(format!(
+            "http://{}/lobbies/{}/teams/balance",
+            addr, lobby_id
+        ))
+        .header("Authorization", format!("Bearer {}", tokens[1]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+
+    // The two strongest players end up on opposite teams
+    let response = client
+        .post(format!(
+            "http://{}/lobbies/{}/teams/balance",
+            addr, lobby_id
+        ))
+        .header("Authorization", format!("Bearer {}", tokens[0]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: Value = response.json().await.unwrap();
+    let pubkey = |username: &str, password: &str| {
+        Value::String(helpers::get_public_key(username, password).unwrap())
+    };
+    let team_of = |pubkey: &Value| {
+        body["teams"]["rosters"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .position(|roster| roster.as_array().unwrap().contains(pubkey))
+            .unwrap()
+    };
+    let (a, b, c, d) = (
+        pubkey("player_a", "pass_a"),
+        pubkey("player_b", "pass_b"),
+        pubkey("player_c", "pass_c"),
+        pubkey("player_d", "pass_d"),
+    );
+    assert_ne!(team_of(&a), team_of(&b));
+    assert_eq!(team_of(&a), team_of(&d));
+    assert_eq!(team_of(&b), team_of(&c));
+    assert_eq!(body["members"][a.as_str().unwrap()]["team"], team_of(&a));
+}
+
+#[tokio::test]
+#[serial]
+async fn test_team_layout_is_bounded() {
+    let addr = spawn_app().await;
+    let client = Client::new();
+    let token = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+
+    // Oversized layouts are refused instead of overflowing or allocating rosters for them
+    for body in [
+        json!({ "is_private": false, "team_count": 1_000_000_000_000u64, "team_size": 1 }),
+        json!({ "is_private": false, "team_count": usize::MAX, "team_size": usize::MAX }),
+        json!({ "is_private": false, "team_count": 17, "team_size": 2 }),
+        json!({ "is_private": false, "team_count": 2, "team_size": 65 }),
+    ] {
+        let response = client
+            .post(format!("http://{}/lobbies", addr))
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 400, "{body}");
+    }
+
+    let response = client
+        .post(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token))
+        .json(&json!({ "is_private": false, "team_count": 16, "team_size": 64 }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let lobby: Value = response.json().await.unwrap();
+    assert_eq!(lobby["max_players"], 1024);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_concurrent_joins_leave_one_seat() {
+    let addr = spawn_app().await;
+    let client = Client::new();
+
+    let mut lobby_ids = Vec::new();
+    for i in 0..4 {
+        let token = authenticate_and_get_token(addr, &format!("owner_{i}"), "pass").await;
+        let response = client
+            .post(format!("http://{}/lobbies", addr))
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&json!({ "is_private": false }))
+            .send()
+            .await
+            .unwrap();
+        let body: Value = response.json().await.unwrap();
+        lobby_ids.push(body["id"].as_str().unwrap().to_string());
+    }
+    let token = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let pubkey_a = helpers::get_public_key("player_a", "pass_a").unwrap();
+
+    // The same player races joins to every lobby
+    let joins = (0..20).map(|i| {
+        client
+            .post(format!(
+                "http://{}/lobbies/{}/join",
+                addr,
+                lobby_ids[i % lobby_ids.len()]
+            ))
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+    });
+    for response in futures_util::future::join_all(joins).await {
+        assert_eq!(response.unwrap().status().as_u16(), 200);
+    }
+
+    let response = client
+        .get(format!("http://{}/lobbies", addr))
+        .send()
+        .await
+        .unwrap();
+    let lobbies: Vec<Value> = response.json().await.unwrap();
+    let seats: Vec<usize> = lobbies
+        .iter()
+        .map(|l| l["players"].as_array().unwrap().len())
+        .collect();
+    assert_eq!(seats.iter().sum::<usize>(), 5);
+    let seated_in = lobbies
+        .iter()
+        .filter(|l| {
+            l["players"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|p| p.as_str() == Some(pubkey_a.as_str()))
+        })
+        .count();
+    assert_eq!(seated_in, 1);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_ice_servers_issue_turn_credentials() {
+    let addr = spawn_app_with_args(Args {
+        stun_urls: vec!["stun:stun.example.com:3478".to_string()],
+        turn_urls: vec![
+            "turn:turn.example.com:3478?transport=udp".to_string(),
+            "turns:turn.example.com:5349".to_string(),
+        ],
+        turn_secret: Some("turn-secret".to_string()),
+        turn_credential_ttl_secs: 600,
+        ..Args::default()
+    })
+    .await;
+    let client = Client::new();
+
+    let response = client
+        .get(format!("http://{}/ice-servers", addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+
+    let token = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let pubkey = helpers::get_public_key("player_a", "pass_a").unwrap();
+    let response = client
+        .get(format!("http://{}/ice-servers", addr))
+        .header("Authorization", format!("Bearer {}", token))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: Value = response.json().await.unwrap();
+    let servers = body["ice_servers"].as_array().unwrap();
+    assert_eq!(servers.len(), 2);
+    assert_eq!(servers[0], json!({"urls": ["stun:stun.example.com:3478"]}));
+
+    let turn = &servers[1];
+    assert_eq!(turn["urls"].as_array().unwrap().len(), 2);
+    // coturn REST API scheme: `<expiry>:<user>`, credential is base64(HMAC-SHA1(secret, username))
+    let username = turn["username"].as_str().unwrap();
+    let (expiry, user) = username.split_once(':').unwrap();
+    assert_eq!(user, pubkey);
+    let ttl = expiry.parse::<i64>().unwrap() - chrono::Utc::now().timestamp();
+    assert!((590..=600).contains(&ttl), "unexpected ttl {ttl}");
+    let mut mac = Hmac::<Sha1>::new_from_slice(b"turn-secret").unwrap();
+    mac.update(username.as_bytes());
+    let expected = general_purpose::STANDARD.encode(mac.finalize().into_bytes());
+    assert_eq!(turn["credential"], expected);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_ice_servers_without_turn_secret() {
+    let addr = spawn_app_with_args(Args {
+        stun_urls: vec!["stun:stun.example.com:3478".to_string()],
+        turn_urls: vec!["turn:turn.example.com:3478".to_string()],
+        ..Args::default()
+    })
+    .await;
+    let token = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let response = Client::new()
+        .get(format!("http://{}/ice-servers", addr))
+        .header("Authorization", format!("Bearer {}", token))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(
+        body,
+        json!({"ice_servers": [{"urls": ["stun:stun.example.com:3478"]}]})
+    );
+}
+
+/// Send a STUN Binding request with the given attributes and return the response.
+async fn stun_binding(
+    socket: &tokio::net::UdpSocket,
+    server: &str,
+    transaction_id: [u8; 12],
+    attributes: &[u8],
+) -> Vec<u8> {
+    let mut request = vec![0x00, 0x01];
+    request.extend_from_slice(&(attributes.len() as u16).to_be_bytes());
+    request.extend_from_slice(&0x2112_A442u32.to_be_bytes());
+    request.extend_from_slice(&transaction_id);
+    request.extend_from_slice(attributes);
+    socket.send_to(&request, server).await.unwrap();
+    let mut buf = [0u8; 1500];
+    let len = tokio::time::timeout(Duration::from_secs(2), socket.recv(&mut buf))
+        .await
+        .expect("no STUN response")
+        .unwrap();
+    buf[..len].to_vec()
+}
+
+/// Value of the first attribute of the given type in a STUN message.
+fn stun_attribute(message: &[u8], kind: u16) -> Option<&[u8]> {
+    let mut attributes = &message[20..];
+    while attributes.len() >= 4 {
+        let attr_kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let len = u16::from_be_bytes([attributes[2], attributes[3]]) as usize;
+        if attr_kind == kind {
+            return Some(&attributes[4..4 + len]);
+        }
+        attributes = &attributes[4 + len.div_ceil(4) * 4..];
+    }
+    None
+}
+
+#[tokio::test]
+#[serial]
+async fn test_embedded_stun_server() {
+    let stun_port = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let addr = spawn_app_with_args(Args {
+        stun_port: Some(stun_port),
+        stun_urls: vec!["stun:stun.example.com:3478".to_string()],
+        ..Args::default()
+    })
+    .await;
+
+    // The embedded server is advertised first, at the host the client reached
+    let token = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let response = Client::new()
+        .get(format!("http://{}/ice-servers", addr))
+        .header("Authorization", format!("Bearer {}", token))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = response.json().await.unwrap();
+    let stun_url = format!("stun:127.0.0.1:{stun_port}");
+    assert_eq!(
+        body["ice_servers"][0]["urls"],
+        json!([stun_url, "stun:stun.example.com:3478"])
+    );
+    let server = stun_url.strip_prefix("stun:").unwrap();
+
+    // A Binding request is answered with the client's address, XORed with the magic cookie
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client_addr = socket.local_addr().unwrap();
+    let transaction_id = *b"matchbox-tid";
+    let response = stun_binding(&socket, server, transaction_id, &[]).await;
+    assert_eq!(&response[..2], &[0x01, 0x01]);
+    assert_eq!(
+        u16::from_be_bytes([response[2], response[3]]) as usize,
+        response.len() - 20
+    );
+    assert_eq!(&response[4..8], &0x2112_A442u32.to_be_bytes());
+    assert_eq!(&response[8..20], &transaction_id);
+    let mapped = stun_attribute(&response, 0x0020).expect("no XOR-MAPPED-ADDRESS");
+    assert_eq!(mapped[1], 0x01);
+    let port = u16::from_be_bytes([mapped[2], mapped[3]]) ^ 0x2112;
+    let ip: Vec<u8> = mapped[4..8]
+        .iter()
+        .zip(0x2112_A442u32.to_be_bytes())
+        .map(|(a, b)| a ^ b)
+        .collect();
+    let ip = std::net::Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]);
+    assert_eq!(std::net::SocketAddr::from((ip, port)), client_addr);
+
+    // Unknown comprehension-required attributes are refused with a 420
+    let attribute = [0x00, 0x7F, 0x00, 0x04, 1, 2, 3, 4];
+    let response = stun_binding(&socket, server, transaction_id, &attribute).await;
+    assert_eq!(&response[..2], &[0x01, 0x11]);
+    let error = stun_attribute(&response, 0x0009).expect("no ERROR-CODE");
+    assert_eq!(error[2] as u16 * 100 + error[3] as u16, 420);
+    assert_eq!(stun_attribute(&response, 0x000A), Some(&[0x00, 0x7F][..]));
+}
+
+/// Bytes of a hex dump, whitespace ignored.
+fn hex(dump: &str) -> Vec<u8> {
+    let digits: Vec<char> = dump.chars().filter(|c| !c.is_whitespace()).collect();
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(&pair.iter().collect::<String>(), 16).unwrap())
+        .collect()
+}
+
+/// Check the `MESSAGE-INTEGRITY` of a STUN message by hand (RFC 5389 section 15.4), without the
+/// crate's own STUN code.
+fn integrity_matches(message: &[u8], key: &[u8]) -> bool {
+    let mut offset = 20;
+    while offset + 4 <= message.len() {
+        let kind = u16::from_be_bytes([message[offset], message[offset + 1]]);
+        let len = u16::from_be_bytes([message[offset + 2], message[offset + 3]]) as usize;
+        if kind == 0x0008 {
+            // The length covers the attributes up to and including MESSAGE-INTEGRITY
+            let mut covered = message[..offset].to_vec();
+            covered[2..4].copy_from_slice(&((offset + 24 - 20) as u16).to_be_bytes());
+            let mut mac = Hmac::<Sha1>::new_from_slice(key).unwrap();
+            mac.update(&covered);
+            return mac.verify_slice(&message[offset + 4..offset + 24]).is_ok();
+        }
+        offset += 4 + len.div_ceil(4) * 4;
+    }
+    false
+}
+
+/// Decode an IPv4 `XOR-*-ADDRESS` value by hand.
+fn xor_ipv4_by_hand(value: &[u8]) -> std::net::SocketAddr {
+    assert_eq!(value[1], 0x01);
+    let port = u16::from_be_bytes([value[2], value[3]]) ^ 0x2112;
+    let ip: Vec<u8> = value[4..8]
+        .iter()
+        .zip(0x2112_A442u32.to_be_bytes())
+        .map(|(a, b)| a ^ b)
+        .collect();
+    std::net::SocketAddr::from(([ip[0], ip[1], ip[2], ip[3]], port))
+}
+
+#[test]
+fn test_stun_rfc5769_vectors() {
+    // 2.1 Sample Request, short-term credentials
+    let request = hex("0001 0058 2112a442 b7e7a701bc34d686fa87dfae
+         8022 0010 5354554e 20746573 7420636c 69656e74
+         0024 0004 6e0001ff
+         8029 0008 932ff9b1 51263b36
+         0006 0009 6576746a 3a683676 59202020
+         0008 0014 9aeaa70c bfd8cb56 781ef2b5 b2d3f249 c1b571a2
+         8028 0004 e57a3bcf");
+    let password = b"VOkJxbRl1RmTxUk/WvJxBt";
+    let message = stun::Message::parse(&request).unwrap();
+    assert_eq!(
+        (message.method, message.class),
+        (stun::METHOD_BINDING, stun::Class::Request)
+    );
+    assert_eq!(message.text(stun::ATTR_USERNAME), Some("evtj:h6vY"));
+    assert!(message.check_integrity(password));
+    assert!(!message.check_integrity(b"wrong password"));
+
+    // 2.2 Sample IPv4 Response and 2.3 Sample IPv6 Response
+    let transaction_id: [u8; 12] = hex("b7e7a701bc34d686fa87dfae").try_into().unwrap();
+    let ipv4_response = hex("0101 003c 2112a442 b7e7a701bc34d686fa87dfae
+         8022 000b 74657374 20766563 746f7220
+         0020 0008 0001a147 e112a643
+         0008 0014 2b91f599 fd9e90c3 8c7489f9 2af9ba53 f06be7d7
+         8028 0004 c07d4c96");
+    let ipv6_response = hex("0101 0048 2112a442 b7e7a701bc34d686fa87dfae
+         8022 000b 74657374 20766563 746f7220
+         0020 0014 0002a147 0113a9fa a5d3f179 bc25f4b5 bed2b9d9
+         0008 0014 a382954e 4be67bf1 1784c97c 8292c275 bfe3ed41
+         8028 0004 c8fb0b4c");
+    let ipv4: std::net::SocketAddr = "192.0.2.1:32853".parse().unwrap();
+    let ipv6: std::net::SocketAddr = "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+        .parse()
+        .unwrap();
+    for (response, address) in [(&ipv4_response, ipv4), (&ipv6_response, ipv6)] {
+        let message = stun::Message::parse(response).unwrap();
+        assert_eq!(message.class, stun::Class::Success);
+        assert_eq!(
+            message.xor_address(stun::ATTR_XOR_MAPPED_ADDRESS),
+            Some(address)
+        );
+        assert!(message.check_integrity(password));
+        assert_eq!(
+            stun::encode_xor_address(address, &transaction_id),
+            stun_attribute(response, stun::ATTR_XOR_MAPPED_ADDRESS).unwrap()
+        );
+    }
+
+    // 2.4 Sample Request with Long-Term Authentication. The password `The<U+00AD>M<U+00AA>tr<U+2168>`
+    // is `TheMatrIX` after SASLprep.
+    let request = hex("0001 0060 2112a442 78ad3433c6ad72c029da412e
+         0006 0012 e3839ee3 8388e383 aae38383 e382afe3 82b90000
+         0015 001c 662f2f34 39396b39 35346436 4f4c3334 6f4c3946 53547679 36347341
+         0014 000b 6578616d 706c652e 6f726700
+         0008 0014 f6702465 6dd64a3e 02b8e071 2e85c9a2 8ca89666");
+    let username = "\u{30DE}\u{30C8}\u{30EA}\u{30C3}\u{30AF}\u{30B9}";
+    let key = md5::compute(format!("{username}:example.org:TheMatrIX")).0;
+    let message = stun::Message::parse(&request).unwrap();
+    assert_eq!(message.text(stun::ATTR_USERNAME), Some(username));
+    assert_eq!(message.text(stun::ATTR_REALM), Some("example.org"));
+    assert!(message.check_integrity(&key));
+    assert!(integrity_matches(&request, &key));
+    let transaction_id: [u8; 12] = request[8..20].try_into().unwrap();
+    let rebuilt =
+        stun::MessageBuilder::new(stun::METHOD_BINDING, stun::Class::Request, transaction_id)
+            .attribute(stun::ATTR_USERNAME, username.as_bytes())
+            .attribute(stun::ATTR_NONCE, b"f//499k954d6OL34oL9FSTvy64sA")
+            .attribute(stun::ATTR_REALM, b"example.org")
+            .finish_with_integrity(&key)
+            .unwrap();
+    assert_eq!(rebuilt, request);
+}
+
+/// A free UDP port on loopback.
+fn free_udp_port() -> u16 {
+    std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Next datagram on `socket`, if one arrives soon.
+async fn recv_datagram(socket: &tokio::net::UdpSocket) -> Option<(Vec<u8>, std::net::SocketAddr)> {
+    let mut buf = [0u8; 2048];
+    let (len, from) = tokio::time::timeout(Duration::from_millis(500), socket.recv_from(&mut buf))
+        .await
+        .ok()?
+        .unwrap();
+    Some((buf[..len].to_vec(), from))
+}
+
+/// Long-term TURN credentials of a player, from `GET /ice-servers`
+struct TurnCredentials {
+    url: String,
+    username: String,
+    password: String,
+}
+
+async fn turn_credentials(addr: SocketAddr, token: &str) -> TurnCredentials {
+    let response = Client::new()
+        .get(format!("http://{}/ice-servers", addr))
+        .header("Authorization", format!("Bearer {}", token))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = response.json().await.unwrap();
+    let turn = body["ice_servers"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|server| server.get("username").is_some())
+        .unwrap();
+    TurnCredentials {
+        url: turn["urls"][0].as_str().unwrap().to_string(),
+        username: turn["username"].as_str().unwrap().to_string(),
+        password: turn["credential"].as_str().unwrap().to_string(),
+    }
+}
+
+/// A TURN client session authenticated with long-term credentials
+struct TurnClient {
+    socket: tokio::net::UdpSocket,
+    server: std::net::SocketAddr,
+    username: String,
+    realm: String,
+    nonce: String,
+    key: [u8; 16],
+}
+
+impl TurnClient {
+    /// Learn the realm and nonce from the 401 answering an unauthenticated Allocate.
+    async fn connect(server: std::net::SocketAddr, credentials: &TurnCredentials) -> Self {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request =
+            stun::MessageBuilder::new(turn::METHOD_ALLOCATE, stun::Class::Request, rand::random())
+                .u32_attribute(turn::ATTR_REQUESTED_TRANSPORT, 17 << 24)
+                .finish()
+                .unwrap();
+        socket.send_to(&request, server).await.unwrap();
+        let (response, _) = recv_datagram(&socket).await.expect("no TURN response");
+        let response = stun::Message::parse(&response).unwrap();
+        assert_eq!(response.class, stun::Class::Error);
+        assert_eq!(error_code(&response), 401);
+        let realm = response.text(stun::ATTR_REALM).unwrap().to_string();
+        let nonce = response.text(stun::ATTR_NONCE).unwrap().to_string();
+        let key = md5::compute(format!(
+            "{}:{}:{}",
+            credentials.username, realm, credentials.password
+        ))
+        .0;
+        Self {
+            socket,
+            server,
+            username: credentials.username.clone(),
+            realm,
+            nonce,
+            key,
+        }
+    }
+
+    /// Send an authenticated request built by `build` and return the response.
+    async fn request(&self, method: u16, build: impl FnOnce(&mut stun::MessageBuilder)) -> Vec<u8> {
+        let mut request = stun::MessageBuilder::new(method, stun::Class::Request, rand::random());
+        build(&mut request);
+        request
+            .attribute(stun::ATTR_USERNAME, self.username.as_bytes())
+            .attribute(stun::ATTR_REALM, self.realm.as_bytes())
+            .attribute(stun::ATTR_NONCE, self.nonce.as_bytes());
+        let request = request.finish_with_integrity(&self.key).unwrap();
+        self.socket.send_to(&request, self.server).await.unwrap();
+        recv_datagram(&self.socket)
+            .await
+            .expect("no TURN response")
+            .0
+    }
+
+    async fn allocate(&self) -> Vec<u8> {
+        self.request(turn::METHOD_ALLOCATE, |request| {
+            request.u32_attribute(turn::ATTR_REQUESTED_TRANSPORT, 17 << 24);
+        })
+        .await
+    }
+
+    async fn create_permission(&self, peer: std::net::SocketAddr) {
+        let response = self
+            .request(turn::METHOD_CREATE_PERMISSION, |request| {
+                request.xor_address(turn::ATTR_XOR_PEER_ADDRESS, peer);
+            })
+            .await;
+        self.assert_success(&response);
+    }
+
+    async fn send_indication(&self, peer: std::net::SocketAddr, data: &[u8]) {
+        let indication =
+            stun::MessageBuilder::new(turn::METHOD_SEND, stun::Class::Indication, rand::random())
+                .xor_address(turn::ATTR_XOR_PEER_ADDRESS, peer)
+                .attribute(turn::ATTR_DATA, data)
+                .finish()
+                .unwrap();
+        self.socket.send_to(&indication, self.server).await.unwrap();
+    }
+
+    /// Check that a response succeeded and carries a valid `MESSAGE-INTEGRITY`.
+    fn assert_success<'a>(&self, bytes: &'a [u8]) -> stun::Message<'a> {
+        let response = stun::Message::parse(bytes).unwrap();
+        assert_eq!(response.class, stun::Class::Success, "{response:?}");
+        assert!(response.check_integrity(&self.key));
+        assert!(integrity_matches(bytes, &self.key));
+        response
+    }
+}
+
+fn error_code(message: &stun::Message) -> u16 {
+    let value = message.attribute(stun::ATTR_ERROR_CODE).unwrap();
+    u16::from(value[2]) * 100 + u16::from(value[3])
+}
+
+#[tokio::test]
+#[serial]
+async fn test_embedded_turn_relay() {
+    let turn_port = free_udp_port();
+    let addr = spawn_app_with_args(Args {
+        turn_port: Some(turn_port),
+        turn_secret: Some("turn-secret".to_string()),
+        turn_allow_loopback_peers: true,
+        ..Args::default()
+    })
+    .await;
+    let token = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let credentials = turn_credentials(addr, &token).await;
+    assert_eq!(
+        credentials.url,
+        format!("turn:127.0.0.1:{turn_port}?transport=udp")
+    );
+    let server = std::net::SocketAddr::from(([127, 0, 0, 1], turn_port));
+
+    // Wrong credentials are refused
+    let intruder = TurnClient::connect(
+        server,
+        &TurnCredentials {
+            password: "guess".to_string(),
+            ..turn_credentials(addr, &token).await
+        },
+    )
+    .await;
+    let response = intruder.allocate().await;
+    assert_eq!(error_code(&stun::Message::parse(&response).unwrap()), 401);
+
+    let client = TurnClient::connect(server, &credentials).await;
+    let response = client.allocate().await;
+    let response = client.assert_success(&response);
+    let relayed = response
+        .xor_address(turn::ATTR_XOR_RELAYED_ADDRESS)
+        .unwrap();
+    assert_eq!(relayed.ip(), std::net::Ipv4Addr::LOCALHOST);
+    assert_eq!(
+        xor_ipv4_by_hand(response.attribute(turn::ATTR_XOR_RELAYED_ADDRESS).unwrap()),
+        relayed
+    );
+    assert_eq!(
+        response.xor_address(stun::ATTR_XOR_MAPPED_ADDRESS),
+        Some(client.socket.local_addr().unwrap())
+    );
+
+    // Peers without a permission are not relayed
+    let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    peer.send_to(b"too early", relayed).await.unwrap();
+    assert!(recv_datagram(&client.socket).await.is_none());
+
+    // Send and Data indications
+    client.create_permission(peer_addr).await;
+    client.send_indication(peer_addr, b"hello peer").await;
+    let (data, from) = recv_datagram(&peer).await.expect("peer got nothing");
+    assert_eq!((data.as_slice(), from), (&b"hello peer"[..], relayed));
+    peer.send_to(b"hello client", relayed).await.unwrap();
+    let (data, _) = recv_datagram(&client.socket)
+        .await
+        .expect("client got nothing");
+    let indication = stun::Message::parse(&data).unwrap();
+    assert_eq!(
+        (indication.method, indication.class),
+        (turn::METHOD_DATA, stun::Class::Indication)
+    );
+    assert_eq!(
+        indication.xor_address(turn::ATTR_XOR_PEER_ADDRESS),
+        Some(peer_addr)
+    );
+    assert_eq!(
+        indication.attribute(turn::ATTR_DATA),
+        Some(&b"hello client"[..])
+    );
+
+    // Channels
+    let response = client
+        .request(turn::METHOD_CHANNEL_BIND, |request| {
+            request
+                .u32_attribute(turn::ATTR_CHANNEL_NUMBER, 0x4000 << 16)
+                .xor_address(turn::ATTR_XOR_PEER_ADDRESS, peer_addr);
+        })
+        .await;
+    client.assert_success(&response);
+    let mut channel_data = vec![0x40, 0x00, 0x00, 0x04];
+    channel_data.extend_from_slice(b"ping");
+    client.socket.send_to(&channel_data, server).await.unwrap();
+    let (data, _) = recv_datagram(&peer).await.expect("peer got nothing");
+    assert_eq!(data, b"ping");
+    peer.send_to(b"pong", relayed).await.unwrap();
+    let (data, _) = recv_datagram(&client.socket)
+        .await
+        .expect("client got nothing");
+    assert_eq!(data, [0x40, 0x00, 0x00, 0x04, b'p', b'o', b'n', b'g']);
+
+    // A zero lifetime refresh deletes the allocation
+    let response = client
+        .request(turn::METHOD_REFRESH, |request| {
+            request.u32_attribute(turn::ATTR_LIFETIME, 0);
+        })
+        .await;
+    client.assert_success(&response);
+    peer.send_to(b"gone", relayed).await.unwrap();
+    assert!(recv_datagram(&client.socket).await.is_none());
+}
+
+#[tokio::test]
+#[serial]
+async fn test_embedded_turn_relay_quotas() {
+    let turn_port = free_udp_port();
+    let addr = spawn_app_with_args(Args {
+        turn_port: Some(turn_port),
+        turn_secret: Some("turn-secret".to_string()),
+        turn_max_allocations: 1,
+        turn_bandwidth_bytes_per_sec: 1000,
+        turn_allow_loopback_peers: true,
+        ..Args::default()
+    })
+    .await;
+    let token = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let credentials = turn_credentials(addr, &token).await;
+    let server = std::net::SocketAddr::from(([127, 0, 0, 1], turn_port));
+
+    let client = TurnClient::connect(server, &credentials).await;
+    let response = client.allocate().await;
+    client.assert_success(&response);
+
+    // The player's allocation quota is shared by all their clients
+    let second = TurnClient::connect(server, &credentials).await;
+    let response = second.allocate().await;
+    let response = stun::Message::parse(&response).unwrap();
+    assert_eq!(error_code(&response), 486);
+
+    // 1000 bytes per second: the second 600 byte datagram is dropped
+    let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    client.create_permission(peer_addr).await;
+    for _ in 0..2 {
+        client.send_indication(peer_addr, &[7; 600]).await;
+    }
+    let (data, _) = recv_datagram(&peer).await.expect("peer got nothing");
+    assert_eq!(data.len(), 600);
+    assert!(recv_datagram(&peer).await.is_none());
+}
+
+#[tokio::test]
+#[serial]
+async fn test_embedded_turn_relay_server_wide_allocation_limit() {
+    let turn_port = free_udp_port();
+    let addr = spawn_app_with_args(Args {
+        turn_port: Some(turn_port),
+        turn_secret: Some("turn-secret".to_string()),
+        turn_max_total_allocations: 1,
+        ..Args::default()
+    })
+    .await;
+    let server = std::net::SocketAddr::from(([127, 0, 0, 1], turn_port));
+
+    let token = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let client = TurnClient::connect(server, &turn_credentials(addr, &token).await).await;
+    let response = client.allocate().await;
+    client.assert_success(&response);
+
+    // Another player, with their own quota left, still finds the relay full
+    let token = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let other = TurnClient::connect(server, &turn_credentials(addr, &token).await).await;
+    let response = other.allocate().await;
+    let response = stun::Message::parse(&response).unwrap();
+    assert_eq!(error_code(&response), 508);
+
+    // Freeing the allocation makes room again
+    let response = client
+        .request(turn::METHOD_REFRESH, |request| {
+            request.u32_attribute(turn::ATTR_LIFETIME, 0);
+        })
+        .await;
+    client.assert_success(&response);
+    let response = other.allocate().await;
+    other.assert_success(&response);
+}
+
+#[test]
+fn test_stun_message_too_long_is_an_error() {
+    let mut message =
+        stun::MessageBuilder::new(turn::METHOD_DATA, stun::Class::Indication, rand::random());
+    message.attribute(turn::ATTR_DATA, &[0; 65_536]);
+    assert_eq!(message.finish(), Err(stun::MessageTooLong));
+
+    // Each attribute fits, but not the message
+    let mut message =
+        stun::MessageBuilder::new(turn::METHOD_DATA, stun::Class::Indication, rand::random());
+    message
+        .xor_address(
+            turn::ATTR_XOR_PEER_ADDRESS,
+            "[2001:db8::1]:3478".parse().unwrap(),
+        )
+        .attribute(turn::ATTR_DATA, &[0; 65_527]);
+    assert_eq!(message.finish(), Err(stun::MessageTooLong));
+}
+
+#[tokio::test]
+#[serial]
+async fn test_embedded_turn_relay_denies_internal_peers() {
+    let turn_port = free_udp_port();
+    let addr = spawn_app_with_args(Args {
+        turn_port: Some(turn_port),
+        turn_secret: Some("turn-secret".to_string()),
+        ..Args::default()
+    })
+    .await;
+    let token = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let credentials = turn_credentials(addr, &token).await;
+    let server = std::net::SocketAddr::from(([127, 0, 0, 1], turn_port));
+    let client = TurnClient::connect(server, &credentials).await;
+    let response = client.allocate().await;
+    client.assert_success(&response);
+
+    // Loopback, private, link-local and unspecified peers are forbidden
+    for peer in [
+        "127.0.0.1:9000",
+        "10.1.2.3:9000",
+        "192.168.1.1:9000",
+        "169.254.0.1:9000",
+        "0.0.0.0:9000",
+        "[fd00::1]:9000",
+        // Reserved, IETF protocol assignments and benchmarking
+        "240.0.0.1:9000",
+        "255.255.255.255:9000",
+        "192.0.0.8:9000",
+        "198.18.0.1:9000",
+        "198.19.255.254:9000",
+        // Site-local
+        "[fec0::1]:9000",
+        // IPv4-mapped, NAT64 and 6to4 addresses of internal IPv4 peers
+        "[::ffff:172.16.0.1]:9000",
+        "[::ffff:127.0.0.1]:9000",
+        "[64:ff9b::10.0.0.1]:9000",
+        "[64:ff9b::127.0.0.1]:9000",
+        "[2002:c0a8:101::1]:9000",
+        "[2002:a9fe:1::1]:9000",
+    ] {
+        let peer: std::net::SocketAddr = peer.parse().unwrap();
+        let response = client
+            .request(turn::METHOD_CREATE_PERMISSION, |request| {
+                request.xor_address(turn::ATTR_XOR_PEER_ADDRESS, peer);
+            })
+            .await;
+        assert_eq!(
+            error_code(&stun::Message::parse(&response).unwrap()),
+            403,
+            "{peer}"
+        );
+    }
+    let response = client
+        .request(turn::METHOD_CHANNEL_BIND, |request| {
+            request
+                .u32_attribute(turn::ATTR_CHANNEL_NUMBER, 0x4000 << 16)
+                .xor_address(
+                    turn::ATTR_XOR_PEER_ADDRESS,
+                    "10.0.0.1:9000".parse().unwrap(),
+                );
+        })
+        .await;
+    assert_eq!(error_code(&stun::Message::parse(&response).unwrap()), 403);
+
+    // Public peers are allowed, also behind NAT64 and 6to4
+    for peer in [
+        "1.2.3.4:9000",
+        "[::ffff:1.2.3.4]:9000",
+        "[64:ff9b::1.2.3.4]:9000",
+        "[2002:102:304::1]:9000",
+    ] {
+        client.create_permission(peer.parse().unwrap()).await;
+    }
+}
+
+#[tokio::test]
+#[serial]
+async fn test_embedded_turn_relay_needs_external_ip_on_unspecified_host() {
+    let result = matchbox_server::run_with_args(Args {
+        host: "0.0.0.0:0".parse().unwrap(),
+        turn_port: Some(free_udp_port()),
+        turn_secret: Some("turn-secret".to_string()),
+        ..Args::default()
+    })
+    .await;
+    let error = result.unwrap_err().to_string();
+    assert!(error.contains("TURN external IP"), "{error}");
+}
+
+#[tokio::test]
+#[serial]
+async fn test_keepalive_settings_are_validated() {
+    use clap::Parser;
+
+    assert!(Args::try_parse_from(["made_in_heaven", "--ping-interval-secs", "0"]).is_err());
+    assert!(Args::try_parse_from(["made_in_heaven", "--ping-interval-secs", "1"]).is_ok());
+
+    let result = matchbox_server::run_with_args(Args {
+        host: "127.0.0.1:0".parse().unwrap(),
+        ping_interval_secs: 0,
+        ..Args::default()
+    })
+    .await;
+    let error = result.unwrap_err().to_string();
+    assert!(error.contains("ping interval"), "{error}");
+
+    let result = matchbox_server::run_with_args(Args {
+        host: "127.0.0.1:0".parse().unwrap(),
+        ping_interval_secs: 30,
+        idle_timeout_secs: 10,
+        ..Args::default()
+    })
+    .await;
+    let error = result.unwrap_err().to_string();
+    assert!(error.contains("idle timeout"), "{error}");
+}
+
+#[tokio::test]
+#[serial]
+async fn test_default_args_ignore_environment() {
+    std::env::set_var("HOST", "myhost");
+    std::env::set_var("PING_INTERVAL_SECS", "x");
+    let args = Args::default();
+    std::env::remove_var("HOST");
+    std::env::remove_var("PING_INTERVAL_SECS");
+    assert_eq!(args.host, "0.0.0.0:3536".parse::<SocketAddr>().unwrap());
+    assert_eq!(args.ping_interval_secs, 15);
+}
+
+#[test]
+#[serial]
+fn test_default_args_match_command_line_defaults() {
+    use clap::{CommandFactory, Parser};
+
+    for arg in Args::command().get_arguments() {
+        if let Some(name) = arg.get_env() {
+            std::env::remove_var(name);
+        }
+    }
+    let parsed = Args::try_parse_from(["made_in_heaven"]).unwrap();
+    assert_eq!(format!("{parsed:?}"), format!("{:?}", Args::default()));
+}
+
+#[test]
+fn test_chat_word_filter_with_multi_char_lowercase() {
+    let filter = WordListFilter::new(["darn".to_string()]);
+    // `İ` lowercases to two characters, it must not turn the filter off for the whole line
+    assert_eq!(
+        filter.filter("İstanbul, DARN it").as_deref(),
+        Some("İstanbul, **** it")
+    );
+    assert_eq!(filter.filter("dİrn darn").as_deref(), Some("dİrn ****"));
+    let filter = WordListFilter::new(["bit".to_string()]);
+    assert_eq!(filter.filter("a bİt").as_deref(), Some("a ***"));
+}
+
+#[test]
+fn test_usernames_are_forgotten_when_the_session_ends() {
+    use matchbox_protocol::PeerId;
+    use matchbox_server::state::Core;
+
+    let mut core = Core::default();
+    let lobby_id = uuid::Uuid::new_v4();
+    let peer_id = PeerId(uuid::Uuid::new_v4());
+    core.sessions
+        .start_session("player_a", peer_id, lobby_id, false);
+    core.record_username("player_a", "alice");
+    assert_eq!(core.roster_entry("player_a", peer_id).username, "alice");
+
+    core.leave_lobby("player_a", peer_id, lobby_id);
+    assert!(core.usernames.is_empty());
+}
+
+#[tokio::test]
+async fn test_state_actor_stops_after_a_panic() {
+    use matchbox_server::actor::{Actor, ActorError};
+
+    let actor = Actor::spawn(0u32, 8);
+    let result = actor
+        .call(|value| {
+            *value += 1;
+            *value
+        })
+        .await;
+    assert_eq!(result, Ok(1));
+
+    // The value may be half updated, nothing is served from it anymore
+    let result = actor.call(|_| -> u32 { panic!("command failed") }).await;
+    assert_eq!(result, Err(ActorError::Stopped));
+    assert_eq!(actor.call(|value| *value).await, Err(ActorError::Stopped));
+    actor.stopped().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_state_actor_refuses_commands_beyond_its_queue() {
+    use matchbox_server::actor::{Actor, ActorError};
+
+    let actor = Actor::spawn(0u32, 1);
+    let (started, is_started) = tokio::sync::oneshot::channel();
+    let (release, released) = std::sync::mpsc::channel::<()>();
+    actor.cast(move |_| {
+        let _ = started.send(());
+        let _ = released.recv();
+    });
+    is_started.await.unwrap();
+
+    // One command fits in the queue behind the blocked one, the next is refused
+    let queued = tokio::spawn({
+        let actor = actor.clone();
+        async move {
+            actor
+                .call_queued(|value| {
+                    *value += 1;
+                    *value
+                })
+                .await
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(
+        actor.call(|value| *value).await,
+        Err(ActorError::Overloaded)
+    );
+
+    release.send(()).unwrap();
+    assert_eq!(queued.await.unwrap(), Ok(1));
+    assert_eq!(actor.call(|value| *value).await, Ok(1));
+}
+
+#[test]
+fn test_violations_expire() {
+    use matchbox_server::rate_limit::ViolationLog;
+
+    let mut violations = ViolationLog::new(2, std::time::Duration::from_millis(50));
+    violations.record("player_a");
+    assert!(!violations.is_banned("player_a"));
+    assert_eq!(violations.record("player_a"), 2);
+    assert!(violations.is_banned("player_a"));
+
+    std::thread::sleep(std::time::Duration::from_millis(60));
+    assert!(!violations.is_banned("player_a"));
+    assert_eq!(violations.record("player_a"), 1);
+    std::thread::sleep(std::time::Duration::from_millis(60));
+    violations.cleanup_expired();
+    assert_eq!(violations.count("player_a"), 0);
+}
@@ -1,19 +1,37 @@
-use futures_util::StreamExt;
-use matchbox_server::helpers;
+use futures_util::{SinkExt, StreamExt};
+use matchbox_server::{args::Args, helpers};
 use reqwest::Client;
-use serde_json::Value;
+use serde_json::{json, Value};
 use serial_test::serial;
 use std::net::SocketAddr;
 use tokio::net::TcpListener;
 use tokio::time::{sleep, Duration};
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{
+        client::IntoClientRequest, http::HeaderValue, protocol::frame::coding::CloseCode, Message,
+    },
+};
+
+/// Most tests pass the token in the path for brevity, which servers must opt into
+fn test_args() -> Args {
+    Args {
+        allow_token_in_path: true,
+        ..Args::default()
+    }
+}
 
 async fn spawn_app() -> SocketAddr {
+    spawn_app_with_args(test_args()).await
+}
+
+async fn spawn_app_with_args(mut args: Args) -> SocketAddr {
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
     let addr = listener.local_addr().unwrap();
     drop(listener);
+    args.host = addr;
     tokio::spawn(async move {
-        matchbox_server::run(addr).await.unwrap();
+        matchbox_server::run_with_args(args).await.unwrap();
     });
     sleep(Duration::from_millis(100)).await;
     addr
@@ -44,6 +62,95 @@ async fn authenticate_and_get_token(addr: SocketAddr, username: &str, password:
     body["token"].as_str().unwrap().to_string()
 }
 
+type WsRead = futures_util::stream::SplitStream<
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
+>;
+
+/// Wait for the next JSON event of the given kind (e.g. `IdAssigned`, `NewPeer`) and return its
+/// payload.
+async fn wait_for_event(read: &mut WsRead, kind: &str) -> Value {
+    tokio::time::timeout(Duration::from_secs(2), async {
+        while let Some(msg) = read.next().await {
+            if let Ok(Message::Text(text)) = msg {
+                let parsed: Value = serde_json::from_str(&text).unwrap();
+                if let Some(payload) = parsed.get(kind) {
+                    return payload.clone();
+                }
+            }
+        }
+        panic!("socket closed before {kind}");
+    })
+    .await
+    .unwrap_or_else(|_| panic!("timed out waiting for {kind}"))
+}
+
+/// Wait for the next control event of the given type (e.g. `OwnerChanged`) and return its data.
+async fn wait_for_control(read: &mut WsRead, kind: &str) -> Value {
+    tokio::time::timeout(Duration::from_secs(2), async {
+        while let Some(msg) = read.next().await {
+            if let Ok(Message::Text(text)) = msg {
+                let parsed: Value = serde_json::from_str(&text).unwrap();
+                let Some(control) = parsed.get("Control") else {
+                    continue;
+                };
+                assert_eq!(control["version"], 1);
+                if control["type"] == kind {
+                    return control.get("data").cloned().unwrap_or(Value::Null);
+                }
+            }
+        }
+        panic!("socket closed before {kind}");
+    })
+    .await
+    .unwrap_or_else(|_| panic!("timed out waiting for {kind}"))
+}
+
+/// Wait for the server to close the socket and return the close code.
+async fn wait_for_close(read: &mut WsRead) -> Option<CloseCode> {
+    tokio::time::timeout(Duration::from_secs(2), async {
+        while let Some(msg) = read.next().await {
+            if let Ok(Message::Close(frame)) = msg {
+                return frame.map(|f| f.code);
+            }
+        }
+        None
+    })
+    .await
+    .unwrap()
+}
+
+/// Status code of a refused WebSocket upgrade.
+fn http_error_status<T>(result: Result<T, tokio_tungstenite::tungstenite::Error>) -> u16 {
+    match result {
+        Err(tokio_tungstenite::tungstenite::Error::Http(response)) => response.status().as_u16(),
+        Err(e) => panic!("unexpected connection error: {e:?}"),
+        Ok(_) => panic!("upgrade should have been refused"),
+    }
+}
+
+async fn create_lobby(addr: SocketAddr, token: &str, body: Value) -> String {
+    let response = Client::new()
+        .post(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token))
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: Value = response.json().await.unwrap();
+    body["id"].as_str().unwrap().to_string()
+}
+
+async fn join_lobby(addr: SocketAddr, token: &str, lobby_id: &str) {
+    let response = Client::new()
+        .post(format!("http://{}/lobbies/{}/join", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
+
 #[tokio::test]
 #[serial]
 async fn test_websocket_connection_with_token_in_path() {
@@ -87,15 +194,21 @@ async fn test_websocket_connection_with_token_in_path() {
 
 #[tokio::test]
 #[serial]
-async fn test_websocket_connection_without_token_fails() {
-    let addr = spawn_app().await;
+async fn test_websocket_connection_without_token_times_out() {
+    let addr = spawn_app_with_args(Args {
+        auth_timeout_secs: 1,
+        ..test_args()
+    })
+    .await;
 
-    // Try to connect without token in path
+    // A token-less upgrade is accepted but must authenticate with its first message
     let ws_url = format!("ws://{}/", addr);
-    let result = connect_async(&ws_url).await;
+    let (ws_stream, _) = connect_async(&ws_url).await.expect("Failed to connect");
+    let (mut _write, mut read) = ws_stream.split();
+    wait_for_event(&mut read, "IdAssigned").await;
 
-    // Should fail with 401
-    assert!(result.is_err(), "Connection without token should fail");
+    // Nothing was sent before the auth timeout
+    assert_eq!(wait_for_close(&mut read).await, Some(CloseCode::from(4002)));
 }
 
 #[tokio::test]
@@ -194,20 +307,1280 @@ async fn test_websocket_connection_without_joining_lobby_fails() {
     // Authenticate but don't create or join any lobby
     let token = authenticate_and_get_token(addr, "player_a", "pass_a").await;
 
-    // Try to connect via WebSocket
+    // The upgrade is refused because the player is not in any lobby
     let ws_url = format!("ws://{}/{}", addr, token);
     let result = connect_async(&ws_url).await;
+    assert_eq!(http_error_status(result), 403);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_player_removed_from_whitelist_is_kicked() {
+    let addr = spawn_app().await;
+    let client = Client::new();
+
+    let token_owner = authenticate_and_get_token(addr, "owner", "pass").await;
+    let token_guest = authenticate_and_get_token(addr, "guest", "pass").await;
+    let pubkey_guest = helpers::get_public_key("guest", "pass").unwrap();
+
+    let response = client
+        .post(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({ "is_private": true, "whitelist": [pubkey_guest] }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = response.json().await.unwrap();
+    let lobby_id = body["id"].as_str().unwrap().to_string();
+
+    let response = client
+        .post(format!("http://{}/lobbies/{}/join", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_guest))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    let (ws_stream, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_guest))
+        .await
+        .expect("Guest failed to connect");
+    let (mut _write, mut read) = ws_stream.split();
+    // Give the topology time to register the peer
+    sleep(Duration::from_millis(100)).await;
+
+    let response = client
+        .delete(format!("http://{}/lobbies/{}/whitelist", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_owner))
+        .json(&json!({ "pubkeys": [pubkey_guest] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    let kicked = wait_for_control(&mut read, "Kicked").await;
+    assert_eq!(kicked["lobby_id"], lobby_id);
+    assert_eq!(
+        wait_for_close(&mut read).await,
+        Some(CloseCode::from(matchbox_server::state::KICKED_CLOSE_CODE))
+    );
+}
+
+#[tokio::test]
+#[serial]
+async fn test_joining_another_lobby_notifies_old_lobby() {
+    let addr = spawn_app().await;
+
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let token_c = authenticate_and_get_token(addr, "player_c", "pass_c").await;
+
+    let first_lobby = create_lobby(addr, &token_a, json!({ "is_private": false })).await;
+    join_lobby(addr, &token_b, &first_lobby).await;
+
+    let (ws_a, _) = connect_async(format!("ws://{}/{}", addr, token_a))
+        .await
+        .unwrap();
+    let (_write_a, mut read_a) = ws_a.split();
+    let peer_a = wait_for_event(&mut read_a, "IdAssigned").await;
+
+    let (ws_b, _) = connect_async(format!("ws://{}/{}", addr, token_b))
+        .await
+        .unwrap();
+    let (_write_b, mut read_b) = ws_b.split();
+    wait_for_event(&mut read_b, "IdAssigned").await;
+    assert!(wait_for_event(&mut read_a, "NewPeer").await.is_string());
+
+    // A moves to C's lobby
+    let second_lobby = create_lobby(addr, &token_c, json!({ "is_private": false })).await;
+    join_lobby(addr, &token_a, &second_lobby).await;
+
+    assert_eq!(
+        wait_for_close(&mut read_a).await,
+        Some(CloseCode::from(matchbox_server::state::MOVED_CLOSE_CODE))
+    );
+    assert_eq!(wait_for_event(&mut read_b, "PeerLeft").await, peer_a);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_reconnect_within_grace_period_resumes_seat() {
+    let addr = spawn_app_with_args(Args {
+        reconnect_grace_secs: 5,
+        ..test_args()
+    })
+    .await;
+
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let lobby_id = create_lobby(addr, &token_a, json!({ "is_private": false })).await;
+    join_lobby(addr, &token_b, &lobby_id).await;
+
+    let (ws_a, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_a))
+        .await
+        .unwrap();
+    let (mut write_a, mut read_a) = ws_a.split();
+    let peer_a = wait_for_event(&mut read_a, "IdAssigned").await;
+
+    let (ws_b, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_b))
+        .await
+        .unwrap();
+    let (write_b, mut read_b) = ws_b.split();
+    let peer_b = wait_for_event(&mut read_b, "IdAssigned").await;
+    assert_eq!(wait_for_event(&mut read_a, "NewPeer").await, peer_b);
+
+    // B's connection drops without a close handshake
+    drop(write_b);
+    drop(read_b);
+    assert_eq!(
+        wait_for_control(&mut read_a, "PeerDisconnected").await,
+        peer_b
+    );
+
+    // B reconnects with the same token and takes back its peer id
+    let (ws_b, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_b))
+        .await
+        .unwrap();
+    let (_write_b, mut read_b) = ws_b.split();
+    let resumed = wait_for_control(&mut read_b, "Resumed").await;
+    assert_eq!(resumed["peer_id"], peer_b);
+    assert_eq!(resumed["lobby_id"], lobby_id.as_str());
+    assert_eq!(
+        wait_for_control(&mut read_a, "PeerReconnected").await,
+        peer_b
+    );
+
+    // Signals addressed to the old peer id reach the new socket
+    let signal = json!({ "Signal": { "receiver": peer_b, "data": "offer" } });
+    write_a
+        .send(Message::Text(signal.to_string()))
+        .await
+        .unwrap();
+    let received = wait_for_event(&mut read_b, "Signal").await;
+    assert_eq!(received["sender"], peer_a);
+    assert_eq!(received["data"], "offer");
+}
+
+#[tokio::test]
+#[serial]
+async fn test_plain_client_reconnecting_within_grace_period_gets_a_new_peer() {
+    let addr = spawn_app_with_args(Args {
+        reconnect_grace_secs: 5,
+        ..test_args()
+    })
+    .await;
+
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let lobby_id = create_lobby(addr, &token_a, json!({ "is_private": false })).await;
+    join_lobby(addr, &token_b, &lobby_id).await;
+
+    let (ws_a, _) = connect_async(format!("ws://{}/{}", addr, token_a))
+        .await
+        .unwrap();
+    let (_write_a, mut read_a) = ws_a.split();
+    wait_for_event(&mut read_a, "IdAssigned").await;
+
+    let (ws_b, _) = connect_async(format!("ws://{}/{}", addr, token_b))
+        .await
+        .unwrap();
+    let old_peer_b = {
+        let (_write_b, mut read_b) = ws_b.split();
+        wait_for_event(&mut read_b, "IdAssigned").await
+    };
+    assert_eq!(wait_for_event(&mut read_a, "NewPeer").await, old_peer_b);
+
+    // B's connection drops and B comes back as a standard matchbox client, which keeps the peer id
+    // it is assigned: the old one is released and the new one announced
+    sleep(Duration::from_millis(200)).await;
+    let (ws_b, _) = connect_async(format!("ws://{}/{}", addr, token_b))
+        .await
+        .unwrap();
+    let (_write_b, mut read_b) = ws_b.split();
+    let peer_b = wait_for_event(&mut read_b, "IdAssigned").await;
+    assert_ne!(peer_b, old_peer_b);
+    assert_eq!(wait_for_event(&mut read_a, "PeerLeft").await, old_peer_b);
+    assert_eq!(wait_for_event(&mut read_a, "NewPeer").await, peer_b);
+
+    // B kept its seat
+    let response = Client::new()
+        .get(format!("http://{}/lobbies", addr))
+        .send()
+        .await
+        .unwrap();
+    let lobbies: Vec<Value> = response.json().await.unwrap();
+    assert_eq!(lobbies[0]["players"].as_array().unwrap().len(), 2);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_seat_released_after_grace_period() {
+    let addr = spawn_app_with_args(Args {
+        reconnect_grace_secs: 1,
+        ..test_args()
+    })
+    .await;
+
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let lobby_id = create_lobby(addr, &token_a, json!({ "is_private": false })).await;
+    join_lobby(addr, &token_b, &lobby_id).await;
+
+    let (ws_a, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_a))
+        .await
+        .unwrap();
+    let (_write_a, mut read_a) = ws_a.split();
+    wait_for_event(&mut read_a, "IdAssigned").await;
+
+    let (ws_b, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_b))
+        .await
+        .unwrap();
+    let peer_b = {
+        let (_write_b, mut read_b) = ws_b.split();
+        wait_for_event(&mut read_b, "IdAssigned").await
+    };
+    assert_eq!(wait_for_event(&mut read_a, "NewPeer").await, peer_b);
+    assert_eq!(
+        wait_for_control(&mut read_a, "PeerDisconnected").await,
+        peer_b
+    );
+
+    // Nobody comes back, the seat is released once the grace period is over
+    assert_eq!(wait_for_event(&mut read_a, "PeerLeft").await, peer_b);
+
+    let response = Client::new()
+        .get(format!("http://{}/lobbies", addr))
+        .send()
+        .await
+        .unwrap();
+    let lobbies: Vec<Value> = response.json().await.unwrap();
+    assert_eq!(lobbies[0]["players"].as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_websocket_auth_with_subprotocol_and_first_message() {
+    let addr = spawn_app().await;
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let lobby_id = create_lobby(addr, &token_a, json!({"is_private": false})).await;
+    join_lobby(addr, &token_b, &lobby_id).await;
+
+    // Player A passes the token in Sec-WebSocket-Protocol, the server selects `matchbox`
+    let mut request = format!("ws://{}/", addr).into_client_request().unwrap();
+    request.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_str(&format!("matchbox, bearer.{}", token_a)).unwrap(),
+    );
+    let (ws_a, response) = connect_async(request)
+        .await
+        .expect("Player A failed to connect");
+    assert_eq!(
+        response.headers().get("Sec-WebSocket-Protocol").unwrap(),
+        "matchbox"
+    );
+    let (_write_a, mut read_a) = ws_a.split();
+    wait_for_event(&mut read_a, "IdAssigned").await;
+
+    // Player B connects without a token and authenticates with its first message
+    let (ws_b, _) = connect_async(format!("ws://{}/", addr))
+        .await
+        .expect("Player B failed to connect");
+    let (mut write_b, mut read_b) = ws_b.split();
+    let peer_b = wait_for_event(&mut read_b, "IdAssigned").await;
+    let auth = json!({"Authenticate": {"token": token_b}}).to_string();
+    write_b.send(Message::Text(auth)).await.unwrap();
+
+    assert_eq!(wait_for_event(&mut read_a, "NewPeer").await, peer_b);
+
+    // `matchbox.control` carries the token the same way and opts in to control events
+    let token_c = authenticate_and_get_token(addr, "player_c", "pass_c").await;
+    join_lobby(addr, &token_c, &lobby_id).await;
+    let mut request = format!("ws://{}/", addr).into_client_request().unwrap();
+    request.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_str(&format!("matchbox.control, bearer.{}", token_c)).unwrap(),
+    );
+    let (ws_c, response) = connect_async(request)
+        .await
+        .expect("Player C failed to connect");
+    assert_eq!(
+        response.headers().get("Sec-WebSocket-Protocol").unwrap(),
+        "matchbox.control"
+    );
+    let (_write_c, mut read_c) = ws_c.split();
+    let roster = wait_for_control(&mut read_c, "Roster").await;
+    assert_eq!(roster["members"].as_array().unwrap().len(), 3);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_token_in_path_is_refused_by_default() {
+    let addr = spawn_app_with_args(Args::default()).await;
+    let token = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    create_lobby(addr, &token, json!({"is_private": false})).await;
+
+    let result = connect_async(format!("ws://{}/{}", addr, token)).await;
+    assert!(result.is_err(), "Token in path should be rejected");
+
+    // A bad first message is rejected as well
+    let (ws_stream, _) = connect_async(format!("ws://{}/", addr))
+        .await
+        .expect("Failed to connect");
+    let (mut write, mut read) = ws_stream.split();
+    wait_for_event(&mut read, "IdAssigned").await;
+    let auth = json!({"Authenticate": {"token": "invalid_token_here"}}).to_string();
+    write.send(Message::Text(auth)).await.unwrap();
+    assert_eq!(wait_for_close(&mut read).await, Some(CloseCode::from(4002)));
+}
+
+#[tokio::test]
+#[serial]
+async fn test_connect_to_explicit_lobby() {
+    let addr = spawn_app().await;
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let lobby_a = create_lobby(addr, &token_a, json!({"is_private": false})).await;
+    let lobby_b = create_lobby(addr, &token_b, json!({"is_private": false})).await;
+
+    // Player A is not a member of player B's lobby
+    let result = connect_async(format!("ws://{}/{}?lobby_id={}", addr, token_a, lobby_b)).await;
+    assert_eq!(http_error_status(result), 403);
+
+    let result = connect_async(format!("ws://{}/{}?lobby_id=not-a-uuid", addr, token_a)).await;
+    assert_eq!(http_error_status(result), 400);
+
+    let (ws_stream, _) = connect_async(format!("ws://{}/{}?lobby_id={}", addr, token_a, lobby_a))
+        .await
+        .expect("Failed to connect to own lobby");
+    let (_write_a, mut read_a) = ws_stream.split();
+    wait_for_event(&mut read_a, "IdAssigned").await;
+
+    // Without a token on the upgrade, membership is checked once the player authenticated
+    let (ws_stream, _) = connect_async(format!("ws://{}/?lobby_id={}", addr, lobby_b))
+        .await
+        .expect("Failed to connect");
+    let (mut write, mut read) = ws_stream.split();
+    wait_for_event(&mut read, "IdAssigned").await;
+    let auth = json!({"Authenticate": {"token": token_a}}).to_string();
+    write.send(Message::Text(auth)).await.unwrap();
+    assert_eq!(wait_for_close(&mut read).await, Some(CloseCode::from(4003)));
+
+    // The refused attempt did not take over player A's live connection
+    join_lobby(addr, &token_b, &lobby_a).await;
+    let (ws_stream, _) = connect_async(format!("ws://{}/{}?lobby_id={}", addr, token_b, lobby_a))
+        .await
+        .expect("Player B failed to connect");
+    let (_write_b, mut read_b) = ws_stream.split();
+    let peer_b = wait_for_event(&mut read_b, "IdAssigned").await;
+    assert_eq!(wait_for_event(&mut read_a, "NewPeer").await, peer_b);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_lobby_control_events() {
+    let addr = spawn_app().await;
+    let client = Client::new();
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let pubkey_b = helpers::get_public_key("player_b", "pass_b").unwrap();
+    let lobby_id = create_lobby(addr, &token_a, json!({"is_private": false})).await;
+    join_lobby(addr, &token_b, &lobby_id).await;
+
+    let (ws_a, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_a))
+        .await
+        .unwrap();
+    let (mut write_a, mut read_a) = ws_a.split();
+    wait_for_event(&mut read_a, "IdAssigned").await;
+    let (ws_b, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_b))
+        .await
+        .unwrap();
+    let (_write_b, mut read_b) = ws_b.split();
+    wait_for_event(&mut read_b, "IdAssigned").await;
+    wait_for_event(&mut read_a, "NewPeer").await;
+
+    // Unparsable messages get an error notice instead of being silently dropped
+    write_a
+        .send(Message::Text("not json".to_string()))
+        .await
+        .unwrap();
+    let error = wait_for_control(&mut read_a, "Error").await;
+    assert_eq!(error["code"], "invalid_request");
+
+    // Only the owner can start, everyone is told
+    let response = client
+        .post(format!("http://{}/lobbies/{}/start", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_b))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+    let response = client
+        .post(format!("http://{}/lobbies/{}/start", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_a))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "InProgress");
+    for read in [&mut read_a, &mut read_b] {
+        let started = wait_for_control(read, "LobbyStarted").await;
+        assert_eq!(started["lobby_id"], lobby_id);
+    }
+
+    // The owner leaving hands the lobby over
+    write_a.send(Message::Close(None)).await.unwrap();
+    let changed = wait_for_control(&mut read_b, "OwnerChanged").await;
+    assert_eq!(changed["owner"], pubkey_b);
+
+    // The new owner closes the lobby
+    let response = client
+        .delete(format!("http://{}/lobbies/{}", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_b))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 204);
+    let closed = wait_for_control(&mut read_b, "LobbyClosed").await;
+    assert_eq!(closed["lobby_id"], lobby_id);
+    assert_eq!(
+        wait_for_close(&mut read_b).await,
+        Some(CloseCode::from(
+            matchbox_server::state::LOBBY_CLOSED_CLOSE_CODE
+        ))
+    );
+
+    let response = client
+        .get(format!("http://{}/lobbies", addr))
+        .send()
+        .await
+        .unwrap();
+    let lobbies: Value = response.json().await.unwrap();
+    assert!(lobbies.as_array().unwrap().is_empty());
+}
+
+#[tokio::test]
+#[serial]
+async fn test_roster_snapshot_and_peer_identity() {
+    let addr = spawn_app().await;
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let pubkey_a = helpers::get_public_key("player_a", "pass_a").unwrap();
+    let pubkey_b = helpers::get_public_key("player_b", "pass_b").unwrap();
+    let lobby_id = create_lobby(addr, &token_a, json!({"is_private": false})).await;
+    join_lobby(addr, &token_b, &lobby_id).await;
+
+    let (ws_a, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_a))
+        .await
+        .unwrap();
+    let (_write_a, mut read_a) = ws_a.split();
+    let peer_a = wait_for_event(&mut read_a, "IdAssigned").await;
+    let roster = wait_for_control(&mut read_a, "Roster").await;
+    assert_eq!(roster["lobby_id"], lobby_id);
+    assert_eq!(
+        roster["members"],
+        json!([{ "peer_id": peer_a, "pubkey": pubkey_a, "username": "player_a" }])
+    );
+
+    let (ws_b, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_b))
+        .await
+        .unwrap();
+    let (_write_b, mut read_b) = ws_b.split();
+    let peer_b = wait_for_event(&mut read_b, "IdAssigned").await;
+    let roster = wait_for_control(&mut read_b, "Roster").await;
+    let members = roster["members"].as_array().unwrap();
+    assert_eq!(members.len(), 2);
+    assert!(
+        members.contains(&json!({ "peer_id": peer_a, "pubkey": pubkey_a, "username": "player_a" }))
+    );
+    assert!(
+        members.contains(&json!({ "peer_id": peer_b, "pubkey": pubkey_b, "username": "player_b" }))
+    );
 
-    // Connection might succeed initially but should close immediately
-    // because the player is not in any lobby
-    if let Ok((ws_stream, _)) = result {
-        let (mut _write, mut read) = ws_stream.split();
+    // The existing member learns who the newcomer is
+    assert_eq!(wait_for_event(&mut read_a, "NewPeer").await, peer_b);
+    assert_eq!(
+        wait_for_control(&mut read_a, "PeerJoined").await,
+        json!({ "peer_id": peer_b, "pubkey": pubkey_b, "username": "player_b" })
+    );
+}
+
+#[tokio::test]
+#[serial]
+async fn test_plain_matchbox_client_only_gets_peer_events() {
+    let addr = spawn_app().await;
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let lobby_id = create_lobby(addr, &token_a, json!({"is_private": false})).await;
+    join_lobby(addr, &token_b, &lobby_id).await;
+
+    // A connects like a standard matchbox client, B opts in to control events
+    let (ws_a, _) = connect_async(format!("ws://{}/{}", addr, token_a))
+        .await
+        .unwrap();
+    let (_write_a, mut read_a) = ws_a.split();
+    let peer_a = wait_for_event(&mut read_a, "IdAssigned").await;
+    let (ws_b, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_b))
+        .await
+        .unwrap();
+    let (mut write_b, mut read_b) = ws_b.split();
+    wait_for_control(&mut read_b, "Roster").await;
+
+    let chat = json!({ "Chat": { "text": "hello" } }).to_string();
+    write_b.send(Message::Text(chat)).await.unwrap();
+    wait_for_control(&mut read_b, "Chat").await;
+    let response = Client::new()
+        .post(format!("http://{}/lobbies/{}/start", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_a))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    wait_for_control(&mut read_b, "LobbyStarted").await;
+    let signal = json!({ "Signal": { "receiver": peer_a, "data": "offer" } });
+    write_b
+        .send(Message::Text(signal.to_string()))
+        .await
+        .unwrap();
+    write_b.send(Message::Close(None)).await.unwrap();
+
+    let mut kinds = Vec::new();
+    tokio::time::timeout(Duration::from_secs(2), async {
+        while let Some(Ok(msg)) = read_a.next().await {
+            if let Message::Text(text) = msg {
+                let parsed: Value = serde_json::from_str(&text).unwrap();
+                let kind = parsed.as_object().unwrap().keys().next().unwrap().clone();
+                kinds.push(kind.clone());
+                if kind == "PeerLeft" {
+                    break;
+                }
+            }
+        }
+    })
+    .await
+    .expect("timed out waiting for PeerLeft");
+    assert_eq!(kinds, ["NewPeer", "Signal", "PeerLeft"]);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_lobby_chat_and_data_relay() {
+    let addr = spawn_app_with_args(Args {
+        chat_max_bytes: 20,
+        chat_history_len: 2,
+        chat_rate_burst: 4,
+        chat_rate_per_sec: 0.1,
+        chat_blocklist: vec!["darn".to_string()],
+        ..test_args()
+    })
+    .await;
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let token_c = authenticate_and_get_token(addr, "player_c", "pass_c").await;
+    let pubkey_a = helpers::get_public_key("player_a", "pass_a").unwrap();
+    let lobby_id = create_lobby(addr, &token_a, json!({"is_private": false})).await;
+    join_lobby(addr, &token_b, &lobby_id).await;
+    join_lobby(addr, &token_c, &lobby_id).await;
+
+    let (ws_a, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_a))
+        .await
+        .unwrap();
+    let (mut write_a, mut read_a) = ws_a.split();
+    let peer_a = wait_for_event(&mut read_a, "IdAssigned").await;
+    let (ws_b, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_b))
+        .await
+        .unwrap();
+    let (_write_b, mut read_b) = ws_b.split();
+    wait_for_event(&mut read_b, "IdAssigned").await;
+    wait_for_event(&mut read_a, "NewPeer").await;
+
+    let chat = |text: &str| Message::Text(json!({ "Chat": { "text": text } }).to_string());
+    write_a.send(chat("hello")).await.unwrap();
+    let received = wait_for_control(&mut read_b, "Chat").await;
+    assert_eq!(received["text"], "hello");
+    assert_eq!(received["peer_id"], peer_a);
+    assert_eq!(received["pubkey"], pubkey_a);
+    assert_eq!(received["username"], "player_a");
+    // The sender gets its own line back
+    assert_eq!(wait_for_control(&mut read_a, "Chat").await["text"], "hello");
+
+    // Blocked words are masked
+    write_a.send(chat("oh Darn it")).await.unwrap();
+    assert_eq!(
+        wait_for_control(&mut read_b, "Chat").await["text"],
+        "oh **** it"
+    );
+
+    write_a
+        .send(chat("this line is way over the limit"))
+        .await
+        .unwrap();
+    let error = wait_for_control(&mut read_a, "Error").await;
+    assert_eq!(error["code"], "message_too_large");
+
+    let data = json!({ "Data": { "data": { "loadout": [1, 2, 3] } } }).to_string();
+    write_a.send(Message::Text(data)).await.unwrap();
+    let received = wait_for_control(&mut read_b, "Data").await;
+    assert_eq!(received["sender"], peer_a);
+    assert_eq!(received["data"], json!({ "loadout": [1, 2, 3] }));
+
+    // The oversized line was refused before the rate limit, so this uses up the burst of four
+    write_a.send(chat("gg")).await.unwrap();
+    write_a.send(chat("spam")).await.unwrap();
+    let error = wait_for_control(&mut read_a, "Error").await;
+    assert_eq!(error["code"], "rate_limited");
+
+    // A latecomer sees the most recent lines
+    let (ws_c, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_c))
+        .await
+        .unwrap();
+    let (_write_c, mut read_c) = ws_c.split();
+    let history = wait_for_control(&mut read_c, "ChatHistory").await;
+    let texts: Vec<_> = history["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["text"].as_str().unwrap())
+        .collect();
+    assert_eq!(texts, ["oh **** it", "gg"]);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_ready_check_auto_starts_lobby() {
+    let addr = spawn_app().await;
+    let client = Client::new();
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let token_c = authenticate_and_get_token(addr, "player_c", "pass_c").await;
+    let pubkey_b = helpers::get_public_key("player_b", "pass_b").unwrap();
+    let lobby_id = create_lobby(
+        addr,
+        &token_a,
+        json!({
+            "is_private": false,
+            "min_players": 2,
+            "auto_start": true,
+            "team_count": 2,
+            "team_size": 1
+        }),
+    )
+    .await;
+
+    let (ws_a, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_a))
+        .await
+        .unwrap();
+    let (_write_a, mut read_a) = ws_a.split();
+    wait_for_event(&mut read_a, "IdAssigned").await;
+
+    let update_member = |token: &str, body: Value| {
+        client
+            .patch(format!("http://{}/lobbies/{}/members/me", addr, lobby_id))
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&body)
+            .send()
+    };
+
+    // Alone in the lobby, min_players is not met yet
+    let response = update_member(&token_a, json!({"ready": true}))
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "Waiting");
+
+    let response = update_member(&token_c, json!({"ready": true}))
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 403);
+
+    join_lobby(addr, &token_b, &lobby_id).await;
+    let oversized = json!({"custom": {"blob": "x".repeat(2000)}});
+    let response = update_member(&token_b, oversized).await.unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+
+    let response = update_member(
+        &token_b,
+        json!({"ready": true, "team": 1, "custom": {"character": "mage"}}),
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "InProgress");
+    assert_eq!(
+        body["members"][&pubkey_b],
+        json!({"ready": true, "team": 1, "custom": {"character": "mage"}})
+    );
+
+    // Player A is told about its own update first, then about player B's
+    let updated = wait_for_control(&mut read_a, "MemberUpdated").await;
+    assert_eq!(updated["state"], json!({"ready": true}));
+    let updated = wait_for_control(&mut read_a, "MemberUpdated").await;
+    assert_eq!(updated["pubkey"], pubkey_b);
+    assert_eq!(updated["state"]["custom"]["character"], "mage");
+    // Player A never picked a team and was placed in the free one at start
+    let started = wait_for_control(&mut read_a, "LobbyStarted").await;
+    assert_eq!(started["lobby_id"], lobby_id);
+    assert_eq!(started["teams"][1], json!([pubkey_b]));
+    assert_eq!(started["teams"][0].as_array().unwrap().len(), 1);
+
+    let response = update_member(&token_b, json!({"ready": false}))
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 409);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_lobby_auto_starts_when_last_unready_player_leaves() {
+    let addr = spawn_app().await;
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let lobby_id = create_lobby(
+        addr,
+        &token_a,
+        json!({"is_private": false, "auto_start": true}),
+    )
+    .await;
+    join_lobby(addr, &token_b, &lobby_id).await;
+
+    let (ws_a, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_a))
+        .await
+        .unwrap();
+    let (_write_a, mut read_a) = ws_a.split();
+    wait_for_event(&mut read_a, "IdAssigned").await;
+
+    let response = Client::new()
+        .patch(format!("http://{}/lobbies/{}/members/me", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_a))
+        .json(&json!({"ready": true}))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "Waiting");
+
+    // Player B never got ready and moves to a lobby of their own
+    create_lobby(addr, &token_b, json!({"is_private": false})).await;
+    let started = wait_for_control(&mut read_a, "LobbyStarted").await;
+    assert_eq!(started["lobby_id"], lobby_id);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_spectators_watch_without_taking_a_seat() {
+    let addr = spawn_app().await;
+    let client = Client::new();
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let token_c = authenticate_and_get_token(addr, "player_c", "pass_c").await;
+    let token_d = authenticate_and_get_token(addr, "player_d", "pass_d").await;
+    let pubkey_c = helpers::get_public_key("player_c", "pass_c").unwrap();
+    let lobby_id = create_lobby(
+        addr,
+        &token_a,
+        json!({"is_private": false, "max_players": 2}),
+    )
+    .await;
+
+    let join = |token: &str, body: Value| {
+        client
+            .post(format!("http://{}/lobbies/{}/join", addr, lobby_id))
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&body)
+            .send()
+    };
+
+    // The spectator does not use up the last seat
+    let response = join(&token_c, json!({"spectate": true})).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    join_lobby(addr, &token_b, &lobby_id).await;
+
+    let (ws_a, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_a))
+        .await
+        .unwrap();
+    let (_write_a, mut read_a) = ws_a.split();
+    let peer_a = wait_for_event(&mut read_a, "IdAssigned").await;
+
+    // The spectator is told about the players, the players only get a control event
+    let (ws_c, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_c))
+        .await
+        .unwrap();
+    let (_write_c, mut read_c) = ws_c.split();
+    let peer_c = wait_for_event(&mut read_c, "IdAssigned").await;
+    let roster = wait_for_control(&mut read_c, "Roster").await;
+    assert!(roster["members"].as_array().unwrap().contains(
+        &json!({"peer_id": peer_c, "pubkey": pubkey_c, "username": "player_c", "spectator": true})
+    ));
+    assert_eq!(wait_for_event(&mut read_c, "NewPeer").await, peer_a);
+    let joined = wait_for_control(&mut read_a, "SpectatorJoined").await;
+    assert_eq!(joined["peer_id"], peer_c);
+    assert_eq!(joined["spectator"], true);
+
+    // Players joining later are announced to spectators as well
+    let (ws_b, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_b))
+        .await
+        .unwrap();
+    let (_write_b, mut read_b) = ws_b.split();
+    let peer_b = wait_for_event(&mut read_b, "IdAssigned").await;
+    assert_eq!(wait_for_event(&mut read_a, "NewPeer").await, peer_b);
+    assert_eq!(wait_for_event(&mut read_c, "NewPeer").await, peer_b);
+
+    let response = client
+        .post(format!("http://{}/lobbies/{}/start", addr, lobby_id))
+        .header("Authorization", format!("Bearer {}", token_a))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["players"].as_array().unwrap().len(), 2);
+    assert_eq!(body["spectators"], json!([pubkey_c]));
+
+    // Only spectators can join once the match is running
+    let response = join(&token_d, json!({})).await.unwrap();
+    assert_eq!(response.status().as_u16(), 409);
+    let response = join(&token_d, json!({"spectate": true})).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_star_topology_and_host_migration() {
+    let addr = spawn_app().await;
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let token_c = authenticate_and_get_token(addr, "player_c", "pass_c").await;
+    let pubkey_a = helpers::get_public_key("player_a", "pass_a").unwrap();
+    let pubkey_b = helpers::get_public_key("player_b", "pass_b").unwrap();
+    let pubkey_c = helpers::get_public_key("player_c", "pass_c").unwrap();
+
+    let response = Client::new()
+        .post(format!("http://{}/lobbies", addr))
+        .header("Authorization", format!("Bearer {}", token_a))
+        .json(&json!({"is_private": false, "topology": "star"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let lobby: Value = response.json().await.unwrap();
+    assert_eq!(lobby["topology"], "star");
+    assert_eq!(lobby["host"], pubkey_a);
+    let lobby_id = lobby["id"].as_str().unwrap();
+    join_lobby(addr, &token_b, lobby_id).await;
+    join_lobby(addr, &token_c, lobby_id).await;
 
-        // The connection should close quickly
-        let timeout = tokio::time::timeout(Duration::from_secs(2), async { read.next().await });
+    let (ws_a, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_a))
+        .await
+        .unwrap();
+    let (mut write_a, mut read_a) = ws_a.split();
+    wait_for_event(&mut read_a, "IdAssigned").await;
+    let (ws_b, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_b))
+        .await
+        .unwrap();
+    let (_write_b, mut read_b) = ws_b.split();
+    let peer_b = wait_for_event(&mut read_b, "IdAssigned").await;
+    assert_eq!(wait_for_event(&mut read_a, "NewPeer").await, peer_b);
+    let (ws_c, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_c))
+        .await
+        .unwrap();
+    let (_write_c, mut read_c) = ws_c.split();
+    let peer_c = wait_for_event(&mut read_c, "IdAssigned").await;
+    assert_eq!(wait_for_event(&mut read_a, "NewPeer").await, peer_c);
+
+    // Clients learn who joined, but are not asked to connect to each other
+    let joined = tokio::time::timeout(Duration::from_secs(2), async {
+        while let Some(Ok(Message::Text(text))) = read_b.next().await {
+            let parsed: Value = serde_json::from_str(&text).unwrap();
+            assert!(parsed.get("NewPeer").is_none(), "client was sent {text}");
+            if parsed["Control"]["type"] == "PeerJoined" {
+                return parsed["Control"]["data"].clone();
+            }
+        }
+        panic!("socket closed before PeerJoined");
+    })
+    .await
+    .unwrap();
+    assert_eq!(joined["peer_id"], peer_c);
 
-        // Either we get no message, or the connection closes
-        // The exact behavior depends on your topology implementation
-        let _ = timeout.await;
+    // The owner leaves: the new owner becomes host and connects to the remaining client
+    write_a.close().await.unwrap();
+    let new_host = pubkey_b.clone().min(pubkey_c.clone());
+    for read in [&mut read_b, &mut read_c] {
+        let changed = wait_for_control(read, "HostChanged").await;
+        assert_eq!(changed["host"], new_host);
+    }
+    if new_host == pubkey_b {
+        assert_eq!(wait_for_event(&mut read_b, "NewPeer").await, peer_c);
+    } else {
+        assert_eq!(wait_for_event(&mut read_c, "NewPeer").await, peer_b);
     }
 }
+
+#[tokio::test]
+#[serial]
+async fn test_signal_is_limited_to_the_lobby() {
+    let addr = spawn_app().await;
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    create_lobby(addr, &token_a, json!({"is_private": false})).await;
+    create_lobby(addr, &token_b, json!({"is_private": false})).await;
+
+    let (ws_a, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_a))
+        .await
+        .unwrap();
+    let (mut write_a, mut read_a) = ws_a.split();
+    wait_for_event(&mut read_a, "IdAssigned").await;
+    let (ws_b, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_b))
+        .await
+        .unwrap();
+    let (mut write_b, mut read_b) = ws_b.split();
+    let peer_b = wait_for_event(&mut read_b, "IdAssigned").await;
+
+    let signal = json!({ "Signal": { "receiver": peer_b, "data": "offer" } });
+    write_a
+        .send(Message::Text(signal.to_string()))
+        .await
+        .unwrap();
+    let error = wait_for_control(&mut read_a, "Error").await;
+    assert_eq!(error["code"], "peer_not_in_lobby");
+
+    // B only sees the answer to its own bad request, not A's signal
+    write_b
+        .send(Message::Text("not json".to_string()))
+        .await
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(2), async {
+        while let Some(Ok(Message::Text(text))) = read_b.next().await {
+            let parsed: Value = serde_json::from_str(&text).unwrap();
+            assert!(parsed.get("Signal").is_none(), "signal crossed lobbies");
+            if parsed["Control"]["type"] == "Error" {
+                return;
+            }
+        }
+        panic!("socket closed before Error");
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+#[serial]
+async fn test_signal_limits_warn_then_disconnect() {
+    let addr = spawn_app_with_args(Args {
+        signal_max_bytes: 128,
+        signal_rate_burst: 2,
+        signal_rate_per_sec: 0.01,
+        signal_max_warnings: 1,
+        ..test_args()
+    })
+    .await;
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let lobby_id = create_lobby(addr, &token_a, json!({"is_private": false})).await;
+    join_lobby(addr, &token_b, &lobby_id).await;
+
+    let (ws_a, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_a))
+        .await
+        .unwrap();
+    let (mut write_a, mut read_a) = ws_a.split();
+    let peer_a = wait_for_event(&mut read_a, "IdAssigned").await;
+    let (ws_b, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_b))
+        .await
+        .unwrap();
+    let (_write_b, mut read_b) = ws_b.split();
+    let peer_b = wait_for_event(&mut read_b, "IdAssigned").await;
+
+    let signal = |data: &str| {
+        Message::Text(json!({ "Signal": { "receiver": peer_b, "data": data } }).to_string())
+    };
+    write_a.send(signal("offer")).await.unwrap();
+    assert_eq!(wait_for_event(&mut read_b, "Signal").await["data"], "offer");
+
+    // The first oversized signal is only a warning
+    write_a.send(signal(&"x".repeat(200))).await.unwrap();
+    let error = wait_for_control(&mut read_a, "Error").await;
+    assert_eq!(error["code"], "message_too_large");
+    write_a.send(signal("candidate")).await.unwrap();
+    assert_eq!(
+        wait_for_event(&mut read_b, "Signal").await["data"],
+        "candidate"
+    );
+
+    // The bucket is empty now, the next refusal ends the connection
+    write_a.send(signal("candidate")).await.unwrap();
+    assert_eq!(
+        wait_for_close(&mut read_a).await,
+        Some(CloseCode::from(4005))
+    );
+    assert_eq!(wait_for_event(&mut read_b, "PeerLeft").await, peer_a);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_malformed_messages_count_against_signal_limits() {
+    let addr = spawn_app_with_args(Args {
+        signal_max_bytes: 128,
+        signal_max_warnings: 2,
+        ..test_args()
+    })
+    .await;
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let lobby_id = create_lobby(addr, &token_a, json!({"is_private": false})).await;
+    join_lobby(addr, &token_b, &lobby_id).await;
+
+    let (ws_a, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_a))
+        .await
+        .unwrap();
+    let (mut write_a, mut read_a) = ws_a.split();
+    let peer_a = wait_for_event(&mut read_a, "IdAssigned").await;
+    let (ws_b, _) = connect_async(format!("ws://{}/{}", addr, token_b))
+        .await
+        .unwrap();
+    let (_write_b, mut read_b) = ws_b.split();
+    wait_for_event(&mut read_b, "IdAssigned").await;
+
+    // Oversized frames are refused whether or not they are valid JSON
+    write_a.send(Message::Text("{".repeat(200))).await.unwrap();
+    let error = wait_for_control(&mut read_a, "Error").await;
+    assert_eq!(error["code"], "message_too_large");
+    write_a
+        .send(Message::Text("not json".to_string()))
+        .await
+        .unwrap();
+    let error = wait_for_control(&mut read_a, "Error").await;
+    assert_eq!(error["code"], "invalid_request");
+
+    // Malformed messages use up the same warnings
+    write_a
+        .send(Message::Text("not json".to_string()))
+        .await
+        .unwrap();
+    assert_eq!(
+        wait_for_close(&mut read_a).await,
+        Some(CloseCode::from(4005))
+    );
+    assert_eq!(wait_for_event(&mut read_b, "PeerLeft").await, peer_a);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_violations_survive_a_reconnect() {
+    let addr = spawn_app_with_args(Args {
+        reconnect_grace_secs: 0,
+        signal_max_warnings: 10,
+        max_violations: 3,
+        ..test_args()
+    })
+    .await;
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let lobby_id = create_lobby(addr, &token_a, json!({"is_private": false})).await;
+    join_lobby(addr, &token_b, &lobby_id).await;
+    let url = format!("ws://{}/{}?control=true", addr, token_a);
+
+    let send_garbage = |count: usize| {
+        let url = url.clone();
+        async move {
+            let (ws, _) = connect_async(&url).await.unwrap();
+            let (mut write, mut read) = ws.split();
+            wait_for_event(&mut read, "IdAssigned").await;
+            for _ in 0..count {
+                write
+                    .send(Message::Text("not json".to_string()))
+                    .await
+                    .unwrap();
+                let error = wait_for_control(&mut read, "Error").await;
+                assert_eq!(error["code"], "invalid_request");
+            }
+            write.send(Message::Close(None)).await.unwrap();
+        }
+    };
+
+    // Two violations, then a fresh connection does not start over from zero
+    // Closing the socket leaves the lobby, so A joins again before each connection
+    send_garbage(2).await;
+    sleep(Duration::from_millis(200)).await;
+    join_lobby(addr, &token_a, &lobby_id).await;
+    send_garbage(1).await;
+    sleep(Duration::from_millis(200)).await;
+    join_lobby(addr, &token_a, &lobby_id).await;
+
+    let result = connect_async(&url).await;
+    assert_eq!(http_error_status(result), 403);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_idle_peer_is_evicted() {
+    let addr = spawn_app_with_args(Args {
+        reconnect_grace_secs: 0,
+        ping_interval_secs: 1,
+        idle_timeout_secs: 2,
+        ..test_args()
+    })
+    .await;
+    let token_a = authenticate_and_get_token(addr, "player_a", "pass_a").await;
+    let token_b = authenticate_and_get_token(addr, "player_b", "pass_b").await;
+    let lobby_id = create_lobby(addr, &token_a, json!({"is_private": false})).await;
+    join_lobby(addr, &token_b, &lobby_id).await;
+
+    // A stops reading, so it never answers the server's pings
+    let (ws_a, _) = connect_async(format!("ws://{}/{}", addr, token_a))
+        .await
+        .unwrap();
+    let (_write_a, mut read_a) = ws_a.split();
+    let peer_a = wait_for_event(&mut read_a, "IdAssigned").await;
+    let (ws_b, _) = connect_async(format!("ws://{}/{}", addr, token_b))
+        .await
+        .unwrap();
+    let (_write_b, mut read_b) = ws_b.split();
+    wait_for_event(&mut read_b, "IdAssigned").await;
+
+    // B keeps reading and answering pings: it sees A leave and stays connected itself
+    let left = tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(msg) = read_b.next().await {
+            if let Ok(Message::Text(text)) = msg {
+                let parsed: Value = serde_json::from_str(&text).unwrap();
+                if let Some(peer) = parsed.get("PeerLeft") {
+                    return peer.clone();
+                }
+            }
+        }
+        panic!("socket closed before PeerLeft");
+    })
+    .await
+    .unwrap();
+    assert_eq!(left, peer_a);
+    let still_open = tokio::time::timeout(Duration::from_secs(3), async {
+        while let Some(msg) = read_b.next().await {
+            assert!(!matches!(msg, Ok(Message::Close(_)) | Err(_)));
+        }
+        panic!("B was evicted");
+    })
+    .await;
+    assert!(still_open.is_err());
+}
+
+#[tokio::test]
+#[serial]
+async fn test_concurrent_moves_and_disconnects() {
+    const PLAYERS: usize = 6;
+    let addr = spawn_app_with_args(Args {
+        reconnect_grace_secs: 0,
+        ..test_args()
+    })
+    .await;
+    let client = Client::new();
+    let token_a = authenticate_and_get_token(addr, "owner", "pass").await;
+    let pubkey_a = helpers::get_public_key("owner", "pass").unwrap();
+    let lobby_id = create_lobby(addr, &token_a, json!({"is_private": false})).await;
+    let (ws_a, _) = connect_async(format!("ws://{}/{}?control=true", addr, token_a))
+        .await
+        .unwrap();
+    let (_write_a, mut read_a) = ws_a.split();
+    wait_for_event(&mut read_a, "IdAssigned").await;
+
+    let mut tokens = Vec::new();
+    for i in 0..PLAYERS {
+        let token = authenticate_and_get_token(addr, &format!("player_{i}"), "pass").await;
+        join_lobby(addr, &token, &lobby_id).await;
+        tokens.push(token);
+    }
+
+    // Every player connects, then half of them hang up while the others move to a lobby of
+    // their own, all at the same time
+    let players = tokens.into_iter().enumerate().map(|(i, token)| async move {
+        let (ws, _) = connect_async(format!("ws://{}/{}?control=true", addr, token))
+            .await
+            .unwrap();
+        let (mut write, mut read) = ws.split();
+        let peer_id = wait_for_event(&mut read, "IdAssigned").await;
+        wait_for_control(&mut read, "Roster").await;
+        if i % 2 == 0 {
+            write.close().await.unwrap();
+        } else {
+            create_lobby(addr, &token, json!({"is_private": false})).await;
+            assert_eq!(wait_for_close(&mut read).await, Some(CloseCode::from(4001)));
+        }
+        peer_id
+    });
+    let peer_ids = futures_util::future::join_all(players).await;
+
+    let mut left = Vec::new();
+    while left.len() < PLAYERS {
+        left.push(wait_for_event(&mut read_a, "PeerLeft").await);
+    }
+    for peer_id in &peer_ids {
+        assert!(left.contains(peer_id), "no PeerLeft for {peer_id}");
+    }
+
+    let response = client
+        .get(format!("http://{}/lobbies", addr))
+        .send()
+        .await
+        .unwrap();
+    let lobbies: Vec<Value> = response.json().await.unwrap();
+    let lobby = lobbies.iter().find(|l| l["id"] == lobby_id).unwrap();
+    assert_eq!(lobby["players"], json!([pubkey_a]));
+    assert_eq!(lobbies.len(), 1 + PLAYERS / 2);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_concurrent_connections_from_one_address() {
+    const PLAYERS: usize = 8;
+    // Behind a trusted proxy every connection comes from the same address and names the same
+    // client
+    let addr = spawn_app_with_args(Args {
+        trusted_proxies: vec!["127.0.0.1".parse().unwrap()],
+        ..test_args()
+    })
+    .await;
+    let token_a = authenticate_and_get_token(addr, "owner", "pass").await;
+    let lobby_id = create_lobby(addr, &token_a, json!({"is_private": false})).await;
+    let mut players = Vec::new();
+    for i in 0..PLAYERS {
+        let username = format!("player_{i}");
+        let token = authenticate_and_get_token(addr, &username, "pass").await;
+        join_lobby(addr, &token, &lobby_id).await;
+        players.push((token, helpers::get_public_key(&username, "pass").unwrap()));
+    }
+
+    // Half of them upgrade with their token, the others authenticate with their first message
+    let connections = players
+        .into_iter()
+        .enumerate()
+        .map(|(i, (token, pubkey))| async move {
+            let url = if i % 2 == 0 {
+                format!("ws://{}/{}?control=true", addr, token)
+            } else {
+                format!("ws://{}/?control=true", addr)
+            };
+            let mut request = url.into_client_request().unwrap();
+            request
+                .headers_mut()
+                .insert("X-Forwarded-For", HeaderValue::from_static("203.0.113.7"));
+            let (ws, _) = connect_async(request).await.unwrap();
+            let (mut write, mut read) = ws.split();
+            let peer_id = wait_for_event(&mut read, "IdAssigned").await;
+            if i % 2 == 1 {
+                let auth = json!({"Authenticate": {"token": token}}).to_string();
+                write.send(Message::Text(auth)).await.unwrap();
+            }
+            let roster = wait_for_control(&mut read, "Roster").await;
+            let me = roster["members"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|member| member["peer_id"] == peer_id)
+                .cloned()
+                .expect("own peer missing from roster");
+            assert_eq!(me["pubkey"], pubkey, "peer {peer_id} got another identity");
+            (write, read)
+        });
+    let connections = futures_util::future::join_all(connections).await;
+    assert_eq!(connections.len(), PLAYERS);
+}